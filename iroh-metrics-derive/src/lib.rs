@@ -43,6 +43,8 @@ pub fn derive_encode_label_set(input: TokenStream) -> TokenStream {
 /// Maps each variant to its name (snake_case by default). Use
 /// `#[label(rename_all = "...")]` on the enum or `#[label(name = "...")]`
 /// per-variant to customize.
+///
+/// Also derives `EncodeStateSet`, so the enum can be used in a `StateSet`.
 #[proc_macro_derive(EncodeLabelValue, attributes(label))]
 pub fn derive_encode_label_value(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
@@ -296,7 +298,9 @@ fn expand_encode_label_value(input: &DeriveInput) -> Result<proc_macro2::TokenSt
     let rule = enum_attr.rename_all.unwrap_or(RenameRule::SnakeCase);
 
     let mut arms = Vec::new();
-    for variant in &data.variants {
+    let mut states = Vec::new();
+    let mut index_arms = Vec::new();
+    for (index, variant) in data.variants.iter().enumerate() {
         if !matches!(variant.fields, Fields::Unit) {
             return Err(Error::new(
                 variant.span(),
@@ -309,6 +313,8 @@ fn expand_encode_label_value(input: &DeriveInput) -> Result<proc_macro2::TokenSt
         arms.push(quote! {
             Self::#ident => ::iroh_metrics::LabelValue::Str(::std::borrow::Cow::Borrowed(#label)),
         });
+        states.push(quote!(Self::#ident));
+        index_arms.push(quote!(Self::#ident => #index,));
     }
 
     Ok(quote! {
@@ -319,6 +325,16 @@ fn expand_encode_label_value(input: &DeriveInput) -> Result<proc_macro2::TokenSt
                 }
             }
         }
+
+        impl ::iroh_metrics::EncodeStateSet for #name {
+            const STATES: &'static [Self] = &[#(#states),*];
+
+            fn state_index(&self) -> usize {
+                match self {
+                    #(#index_arms)*
+                }
+            }
+        }
     })
 }
 
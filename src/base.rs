@@ -1,7 +1,7 @@
 use std::{any::Any, sync::Arc};
 
 use crate::{
-    Metric, MetricType, MetricValue,
+    Exemplar, Metric, MetricType, MetricValue,
     encoding::EncodableMetric,
     iterable::{FieldIter, IntoIterable, Iterable},
 };
@@ -43,6 +43,10 @@ impl EncodableMetric for MetricItem<'_> {
     fn value(&self) -> MetricValue {
         self.metric.value()
     }
+
+    fn exemplars(&self) -> Vec<Option<Exemplar>> {
+        self.metric.exemplars()
+    }
 }
 
 impl<'a> MetricItem<'a> {
@@ -329,6 +333,152 @@ combined_bar_count_total{x="y"} 10
         assert_eq!(registry.encode_openmetrics_to_string().unwrap(), exp);
     }
 
+    #[test]
+    fn test_sub_registries_share_family() {
+        let mut registry = Registry::default();
+        let a = Arc::new(FooMetrics::default());
+        let b = Arc::new(FooMetrics::default());
+        a.metric_a.inc();
+        b.metric_a.inc_by(2);
+        b.metric_b.set(3);
+        registry.sub_registry_with_label("node", "a").register(a);
+        registry.sub_registry_with_label("node", "b").register(b);
+
+        let exp = r#"# HELP foo_metric_a metric_a.
+# TYPE foo_metric_a counter
+foo_metric_a_total{node="a"} 1
+foo_metric_a_total{node="b"} 2
+# HELP foo_metric_b metric_b.
+# TYPE foo_metric_b gauge
+foo_metric_b{node="a"} 0
+foo_metric_b{node="b"} 3
+# EOF
+"#;
+        assert_eq!(registry.encode_openmetrics_to_string().unwrap(), exp);
+
+        #[cfg(feature = "postcard")]
+        {
+            let mut encoder = Encoder::new(Arc::new(RwLock::new(registry)));
+            let mut decoder = Decoder::default();
+            decoder
+                .import_bytes(&encoder.export_bytes().unwrap())
+                .unwrap();
+            assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), exp);
+        }
+    }
+
+    #[test]
+    fn test_unregister() {
+        let mut registry = Registry::default();
+        let foo = Arc::new(FooMetrics::default());
+        let bar = Arc::new(BarMetrics::default());
+        registry.register(foo.clone());
+        registry
+            .sub_registry_with_label("endpoint", "a")
+            .register(bar.clone());
+        registry
+            .sub_registry_with_label("endpoint", "b")
+            .register(Arc::new(BarMetrics::default()));
+
+        let version = registry.schema_version();
+        assert!(registry.unregister(&foo));
+        assert!(!registry.unregister(&foo));
+        assert_eq!(registry.schema_version(), version + 1);
+
+        // Groups in subregistries are found as well.
+        let as_dyn: Arc<dyn MetricsGroup> = bar.clone();
+        assert!(registry.unregister(&as_dyn));
+        let exp = r#"# HELP bar_count Bar Count.
+# TYPE bar_count counter
+bar_count_total{endpoint="b"} 0
+# EOF
+"#;
+        assert_eq!(registry.encode_openmetrics_to_string().unwrap(), exp);
+
+        let version = registry.schema_version();
+        let removed = registry.remove_sub_registries(|sub| sub.labels().any(|(_, v)| v == "b"));
+        assert_eq!(removed, 1);
+        assert_eq!(registry.schema_version(), version + 1);
+        assert_eq!(registry.remove_sub_registries(|_| true), 1);
+        assert_eq!(registry.encode_openmetrics_to_string().unwrap(), "# EOF\n");
+    }
+
+    #[test]
+    fn test_collector() {
+        use std::sync::Mutex;
+
+        use crate::{CollectedMetric, Collector};
+
+        let queue = Arc::new(Mutex::new(vec![1, 2]));
+        let collector: Arc<dyn Collector> = Arc::new({
+            let queue = queue.clone();
+            move || {
+                let refs = Arc::strong_count(&queue) as u64;
+                let queue = queue.lock().unwrap();
+                let mut metrics = vec![
+                    CollectedMetric::gauge("queue_len", "Items in the queue", queue.len() as i64),
+                    CollectedMetric::counter("refs", "References", refs)
+                        .with_label("kind", "strong"),
+                ];
+                if queue.is_empty() {
+                    metrics.push(CollectedMetric::gauge("queue_idle", "Queue is idle", 1));
+                }
+                metrics
+            }
+        });
+        let mut registry = Registry::default();
+        registry
+            .sub_registry_with_prefix("boo")
+            .sub_registry_with_label("x", "y")
+            .register_collector(collector.clone());
+
+        let exp = r#"# HELP boo_queue_len Items in the queue.
+# TYPE boo_queue_len gauge
+boo_queue_len{x="y"} 2
+# HELP boo_refs References.
+# TYPE boo_refs counter
+boo_refs_total{x="y",kind="strong"} 2
+# EOF
+"#;
+        assert_eq!(registry.encode_openmetrics_to_string().unwrap(), exp);
+
+        #[cfg(feature = "postcard")]
+        {
+            let registry = Arc::new(RwLock::new(registry));
+            let mut encoder = Encoder::new(registry.clone());
+            let mut decoder = Decoder::default();
+            decoder.import(encoder.export()).unwrap();
+            assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), exp);
+
+            // Same shape, no schema needed.
+            queue.lock().unwrap().push(3);
+            let update = encoder.export();
+            assert!(update.schema.is_none());
+            decoder.import(update).unwrap();
+            assert!(
+                decoder
+                    .encode_openmetrics_to_string()
+                    .unwrap()
+                    .contains("boo_queue_len{x=\"y\"} 3\n")
+            );
+
+            // A new metric changes the shape and forces a schema.
+            queue.lock().unwrap().clear();
+            let update = encoder.export();
+            assert!(update.schema.is_some());
+            decoder.import(update).unwrap();
+            assert!(
+                decoder
+                    .encode_openmetrics_to_string()
+                    .unwrap()
+                    .contains("boo_queue_idle{x=\"y\"} 1\n")
+            );
+
+            assert!(registry.write().unwrap().unregister(&collector));
+            assert_eq!(registry.encode_openmetrics_to_string().unwrap(), "# EOF\n");
+        }
+    }
+
     #[test]
     fn test_derive() {
         use crate::{MetricValue, MetricsGroup};
@@ -436,16 +586,487 @@ combined_bar_count_total{x="y"} 10
             registry.clone(),
             crate::encoding::EncoderOpts {
                 include_help: false,
+                ..Default::default()
             },
         );
         let mut decoder = Decoder::default();
-        decoder.import_bytes(&update).unwrap();
-        decoder.import(encoder.export());
+        // The values-only update can't be read without its schema.
+        assert!(decoder.import_bytes(&update).is_err());
+        decoder.import(encoder.export()).unwrap();
         for item in decoder.iter() {
             assert_eq!(item.help, None);
         }
     }
 
+    #[test]
+    #[cfg(feature = "postcard")]
+    fn test_encode_decode_delta() {
+        use iroh_metrics_derive::MetricsGroup;
+
+        use crate::encoding::{EncoderOpts, Update, ValueDelta};
+
+        #[derive(Debug, MetricsGroup)]
+        #[metrics(default, name = "link")]
+        pub struct LinkMetrics {
+            /// Packets sent
+            pub packets: Counter,
+            /// Queue length
+            pub queue: Gauge,
+            /// Packet size
+            #[default(Histogram::new(vec![64.0, 512.0, 1500.0]))]
+            pub size: Histogram,
+        }
+
+        let metrics = Arc::new(LinkMetrics::default());
+        let mut registry = Registry::default();
+        registry.register(metrics.clone());
+        let registry = Arc::new(RwLock::new(registry));
+        let opts = EncoderOpts {
+            delta: true,
+            ..Default::default()
+        };
+        let mut encoder = Encoder::new_with_opts(registry.clone(), opts);
+        let mut full_encoder = Encoder::new(registry.clone());
+        let mut decoder = Decoder::default();
+
+        metrics.packets.inc_by(1000);
+        metrics.size.observe(1400.0);
+        let update = encoder.export();
+        assert!(update.schema.is_some());
+        assert!(
+            update.delta.is_none(),
+            "the first update carries all values"
+        );
+        decoder.import(update).unwrap();
+        full_encoder.export();
+        // Without an acknowledgement, the full values are sent.
+        assert!(encoder.export().delta.is_none());
+        decoder.import(encoder.export()).unwrap();
+        encoder.ack(decoder.last_seq().unwrap());
+
+        metrics.packets.inc_by(3);
+        metrics.size.observe(100.0);
+        let update = encoder.export();
+        assert!(update.schema.is_none());
+        assert!(update.values.items.is_empty());
+        let delta = update.delta.clone().expect("delta update");
+        assert_eq!(delta.base, 2);
+        assert_eq!(delta.len, 3);
+        assert_eq!(delta.items[0], (0, ValueDelta::Counter(3)));
+        assert_eq!(delta.items[1].0, 2, "the unchanged gauge is skipped");
+        assert!(
+            postcard::to_stdvec(&update).unwrap().len()
+                < postcard::to_stdvec(&full_encoder.export()).unwrap().len()
+        );
+        decoder.import(update).unwrap();
+        encoder.ack(decoder.last_seq().unwrap());
+        assert_eq!(
+            decoder.encode_openmetrics_to_string().unwrap(),
+            registry.encode_openmetrics_to_string().unwrap()
+        );
+
+        // Unchanged values result in an empty delta.
+        let update = encoder.export_bytes().unwrap();
+        decoder.import_bytes(&update).unwrap();
+        encoder.ack(decoder.last_seq().unwrap());
+        let update: Update = postcard::from_bytes(&update).unwrap();
+        assert!(update.delta.unwrap().items.is_empty());
+
+        // Decreases are sent as new values.
+        metrics.queue.set(-5);
+        metrics.packets.set(1);
+        decoder.import(encoder.export()).unwrap();
+        assert_eq!(
+            decoder.encode_openmetrics_to_string().unwrap(),
+            registry.encode_openmetrics_to_string().unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "postcard")]
+    fn test_decode_errors() {
+        use crate::encoding::{DecodeError, EncoderOpts};
+
+        let metrics = Arc::new(FooMetrics::default());
+        let mut registry = Registry::default();
+        registry.register(metrics.clone());
+        let registry = Arc::new(RwLock::new(registry));
+        let opts = EncoderOpts {
+            delta: true,
+            ..Default::default()
+        };
+        let mut encoder = Encoder::new_with_opts(registry.clone(), opts);
+        let mut decoder = Decoder::default();
+
+        let first = encoder.export();
+        let version = first.schema_version;
+        assert_eq!(first.seq, 0);
+        // Without the schema, the values can't be read.
+        let second = encoder.export();
+        assert!(matches!(
+            Decoder::default().import(second.clone()),
+            Err(DecodeError::SchemaMismatch {
+                expected: None,
+                actual,
+                ..
+            }) if actual == version
+        ));
+        decoder.import(first.clone()).unwrap();
+        decoder.import(second).unwrap();
+        encoder.ack(decoder.last_seq().unwrap());
+
+        // Stale updates are rejected instead of rolling the values back.
+        assert!(matches!(
+            decoder.import(first),
+            Err(DecodeError::OutOfOrder {
+                last: 1,
+                actual: 0,
+                ..
+            })
+        ));
+
+        // Lost updates don't matter, as deltas are based on the acknowledged update.
+        metrics.metric_a.inc();
+        encoder.export();
+        metrics.metric_a.inc();
+        assert_eq!(decoder.missed_updates(), 0);
+        decoder.import(encoder.export()).unwrap();
+        assert_eq!(decoder.missed_updates(), 1);
+        assert_eq!(
+            decoder.encode_openmetrics_to_string().unwrap(),
+            registry.encode_openmetrics_to_string().unwrap()
+        );
+
+        // Acknowledgements may arrive after later updates were exported.
+        let late = decoder.last_seq().unwrap();
+        metrics.metric_a.inc();
+        decoder.import(encoder.export()).unwrap();
+        encoder.ack(late);
+        metrics.metric_a.inc();
+        let update = encoder.export();
+        assert_eq!(update.delta.as_ref().unwrap().base, late);
+        decoder.import(update).unwrap();
+        assert_eq!(
+            decoder.encode_openmetrics_to_string().unwrap(),
+            registry.encode_openmetrics_to_string().unwrap()
+        );
+
+        // A delta based on an update the decoder doesn't have leaves it unchanged.
+        let lost = encoder.export();
+        encoder.ack(lost.seq);
+        metrics.metric_a.inc();
+        let before = decoder.encode_openmetrics_to_string().unwrap();
+        assert!(matches!(
+            decoder.import(encoder.export()),
+            Err(DecodeError::MissingBase { base: 6, .. })
+        ));
+        assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), before);
+
+        // After a reset, the next update brings the decoder back in sync.
+        encoder.reset();
+        let update = encoder.export();
+        let version = version.wrapping_add(1);
+        assert_eq!((update.seq, update.schema_version), (8, version));
+        assert!(update.schema.is_some() && update.delta.is_none());
+        decoder.import(update).unwrap();
+        assert_eq!(
+            decoder.encode_openmetrics_to_string().unwrap(),
+            registry.encode_openmetrics_to_string().unwrap()
+        );
+
+        // An update for an older schema is rejected.
+        let mut stale = encoder.export();
+        stale.schema_version = version.wrapping_sub(1);
+        assert!(matches!(
+            decoder.import(stale),
+            Err(DecodeError::SchemaMismatch {
+                expected: Some(expected),
+                ..
+            }) if expected == version
+        ));
+
+        // Values of another encoder of the same registry are rejected.
+        let mut other = Encoder::new(registry.clone());
+        other.export();
+        assert!(matches!(
+            decoder.import(other.export()),
+            Err(DecodeError::SchemaMismatch { .. })
+        ));
+        assert!(matches!(
+            decoder.import_bytes(&[0xff]),
+            Err(DecodeError::Postcard { .. })
+        ));
+    }
+
+    #[test]
+    fn test_f64_metrics() {
+        use iroh_metrics_derive::MetricsGroup;
+
+        use crate::{CounterF64, GaugeF64};
+
+        #[derive(Debug, Default, MetricsGroup)]
+        #[metrics(name = "link")]
+        pub struct LinkMetrics {
+            /// Time spent sending
+            pub send_seconds: CounterF64,
+            /// Delivery ratio
+            pub delivery_ratio: GaugeF64,
+            /// Estimated bandwidth
+            pub bandwidth: GaugeF64,
+        }
+
+        let metrics = Arc::new(LinkMetrics::default());
+        assert_eq!(metrics.send_seconds.inc_by(0.25), 0.0);
+        metrics.send_seconds.inc_by(1.5);
+        metrics.delivery_ratio.set(0.875);
+        metrics.delivery_ratio.dec_by(0.125);
+        metrics.bandwidth.set(f64::INFINITY);
+        assert_eq!(metrics.send_seconds.get(), 1.75);
+        assert_eq!(metrics.delivery_ratio.get(), 0.75);
+        assert_eq!(metrics.send_seconds.value(), MetricValue::CounterF64(1.75));
+
+        let mut registry = Registry::default();
+        registry.register(metrics.clone());
+        let output = registry.encode_openmetrics_to_string().unwrap();
+        let expected = "# HELP link_send_seconds Time spent sending.
+# TYPE link_send_seconds counter
+link_send_seconds_total 1.75
+# HELP link_delivery_ratio Delivery ratio.
+# TYPE link_delivery_ratio gauge
+link_delivery_ratio 0.75
+# HELP link_bandwidth Estimated bandwidth.
+# TYPE link_bandwidth gauge
+link_bandwidth +Inf
+# EOF
+";
+        assert_eq!(output, expected);
+
+        #[cfg(feature = "postcard")]
+        {
+            let registry = Arc::new(RwLock::new(registry));
+            let mut encoder = Encoder::new(registry.clone());
+            let mut decoder = Decoder::default();
+            decoder
+                .import_bytes(&encoder.export_bytes().unwrap())
+                .unwrap();
+            assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), expected);
+            let item = decoder.iter().nth(1).unwrap();
+            assert_eq!(item.schema.r#type, MetricType::Gauge);
+            assert_eq!(*item.value, MetricValue::GaugeF64(0.75));
+        }
+    }
+
+    #[test]
+    fn test_info_stateset() {
+        use iroh_metrics_derive::MetricsGroup;
+
+        use crate::{EncodeLabelValue, Info, StateSet};
+
+        #[derive(Debug, PartialEq, EncodeLabelValue)]
+        enum RelayMode {
+            Disabled,
+            #[label(name = "default")]
+            Production,
+            Custom,
+        }
+
+        #[derive(Debug, Default, MetricsGroup)]
+        #[metrics(name = "node")]
+        pub struct NodeMetrics {
+            /// Build information
+            pub build: Info,
+            /// Relay selection mode
+            pub relay_mode: StateSet<RelayMode>,
+        }
+
+        let metrics = Arc::new(NodeMetrics::default());
+        assert_eq!(metrics.relay_mode.get(), &RelayMode::Disabled);
+        metrics.relay_mode.set(RelayMode::Production);
+        assert_eq!(metrics.relay_mode.get(), &RelayMode::Production);
+        metrics
+            .build
+            .set([("version", "0.1.0"), ("node_id", "ab\"cd")]);
+
+        let mut registry = Registry::default();
+        registry.register(metrics.clone());
+        let output = registry.encode_openmetrics_to_string().unwrap();
+        let expected = "# HELP node_build Build information.
+# TYPE node_build info
+node_build_info{version=\"0.1.0\",node_id=\"ab\\\"cd\"} 1
+# HELP node_relay_mode Relay selection mode.
+# TYPE node_relay_mode stateset
+node_relay_mode{node_relay_mode=\"disabled\"} 0
+node_relay_mode{node_relay_mode=\"default\"} 1
+node_relay_mode{node_relay_mode=\"custom\"} 0
+# EOF
+";
+        assert_eq!(output, expected);
+
+        #[cfg(feature = "postcard")]
+        {
+            let registry = Arc::new(RwLock::new(registry));
+            let mut encoder = Encoder::new(registry.clone());
+            let mut decoder = Decoder::default();
+            decoder
+                .import_bytes(&encoder.export_bytes().unwrap())
+                .unwrap();
+            assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), expected);
+
+            // The values can be applied to another group.
+            let other = NodeMetrics::default();
+            for (item, decoded) in other.iter().zip(decoder.iter()) {
+                item.metric.set_value(decoded.value.clone());
+            }
+            assert_eq!(other.relay_mode.get(), &RelayMode::Production);
+            assert_eq!(other.build.get(), metrics.build.get());
+
+            // Deserializing rejects states that don't exist.
+            let bytes = postcard::to_stdvec(&metrics.relay_mode).unwrap();
+            let state: StateSet<RelayMode> = postcard::from_bytes(&bytes).unwrap();
+            assert_eq!(state.get(), &RelayMode::Production);
+            let bytes = postcard::to_stdvec(&3u64).unwrap();
+            assert!(postcard::from_bytes::<StateSet<RelayMode>>(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn test_timer_and_guards() {
+        use crate::{EncodeLabelSet, Family, Gauge, Histogram};
+
+        let histogram = Histogram::new(vec![60.0]);
+        {
+            let _timer = histogram.start_timer();
+            assert_eq!(histogram.count(), 0);
+        }
+        assert_eq!(histogram.count(), 1);
+        assert_eq!(histogram.buckets()[0], (60.0, 1));
+        histogram.start_timer().stop_and_discard();
+        assert_eq!(histogram.count(), 1);
+
+        let gauge = Gauge::new();
+        let work = |fail: bool| -> Result<(), ()> {
+            let _guard = gauge.guard();
+            assert_eq!(gauge.get(), 1);
+            if fail {
+                return Err(());
+            }
+            Ok(())
+        };
+        work(false).unwrap();
+        work(true).unwrap_err();
+        assert_eq!(gauge.get(), 0);
+
+        #[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Debug, EncodeLabelSet)]
+        struct Method {
+            method: &'static str,
+        }
+        let get = Method { method: "get" };
+        let latency: Family<Method, Histogram> =
+            Family::with_constructor(|| Histogram::new(vec![60.0]));
+        let in_flight: Family<Method, Gauge> = Family::default();
+        {
+            let _timer = latency.get_or_create(&get).start_timer_owned();
+            let _guard = in_flight.get_or_create(&get).guard_owned();
+            assert_eq!(in_flight.get_or_create(&get).get(), 1);
+        }
+        assert_eq!(in_flight.get_or_create(&get).get(), 0);
+        assert_eq!(latency.get_or_create(&get).count(), 1);
+
+        let last_seen = Gauge::new();
+        last_seen.set_to_current_time();
+        assert!(last_seen.get() > 1_600_000_000);
+    }
+
+    #[test]
+    fn test_gauge_histogram() {
+        use iroh_metrics_derive::MetricsGroup;
+
+        use crate::{EncodeLabelSet, Family, GaugeHistogram};
+
+        let histogram = GaugeHistogram::new(vec![1.0, 10.0]);
+        for v in [0.5, 2.0, 5.0, 20.0] {
+            histogram.observe(v);
+        }
+        histogram.unobserve(5.0);
+        histogram.unobserve(0.5);
+        // Removing from an empty bucket does nothing.
+        histogram.unobserve(0.5);
+        assert_eq!(histogram.count(), 2);
+        assert_eq!(histogram.sum(), 22.0);
+        assert_eq!(
+            histogram.buckets(),
+            vec![(1.0, 0), (10.0, 1), (f64::INFINITY, 2)]
+        );
+
+        #[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Debug, EncodeLabelSet)]
+        struct Queue {
+            queue: &'static str,
+        }
+
+        #[derive(Debug, MetricsGroup)]
+        #[metrics(default, name = "jobs")]
+        pub struct JobMetrics {
+            /// Age of queued jobs
+            #[default(Family::with_constructor(|| GaugeHistogram::new(vec![1.0, 10.0])))]
+            pub age: Family<Queue, GaugeHistogram>,
+        }
+
+        let metrics = Arc::new(JobMetrics::default());
+        let high = metrics.age.get_or_create(&Queue { queue: "high" });
+        high.observe(0.5);
+        high.observe(4.0);
+        high.observe(8.0);
+        high.unobserve(4.0);
+
+        let mut registry = Registry::default();
+        registry.register(metrics.clone());
+        let output = registry.encode_openmetrics_to_string().unwrap();
+        let expected = "# HELP jobs_age Age of queued jobs.
+# TYPE jobs_age gaugehistogram
+jobs_age_bucket{queue=\"high\",le=\"1.0\"} 1
+jobs_age_bucket{queue=\"high\",le=\"10.0\"} 2
+jobs_age_bucket{queue=\"high\",le=\"+Inf\"} 2
+jobs_age_gsum{queue=\"high\"} 8.5
+jobs_age_gcount{queue=\"high\"} 2
+# EOF
+";
+        assert_eq!(output, expected);
+
+        let families = crate::encoding::parse_openmetrics(&output).unwrap();
+        assert_eq!(families[0].r#type, Some(MetricType::GaugeHistogram));
+        assert_eq!(
+            families[0].values(),
+            vec![(
+                vec![("queue".to_string(), "high".to_string())],
+                high.value()
+            )]
+        );
+
+        #[cfg(feature = "postcard")]
+        {
+            let registry = Arc::new(RwLock::new(registry));
+            let mut encoder = Encoder::new(registry.clone());
+            let mut decoder = Decoder::default();
+            decoder
+                .import_bytes(&encoder.export_bytes().unwrap())
+                .unwrap();
+            assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), expected);
+
+            high.unobserve(8.0);
+            decoder
+                .import_bytes(&encoder.export_bytes().unwrap())
+                .unwrap();
+            assert_eq!(
+                decoder.encode_openmetrics_to_string().unwrap(),
+                registry
+                    .read()
+                    .unwrap()
+                    .encode_openmetrics_to_string()
+                    .unwrap()
+            );
+        }
+    }
+
     #[test]
     fn test_histogram() {
         use crate::Histogram;
@@ -698,6 +1319,422 @@ combined_bar_count_total{x="y"} 10
         );
     }
 
+    #[test]
+    fn test_summary() {
+        use crate::Summary;
+
+        let summary = Summary::new(vec![0.5, 0.9, 0.99]);
+        assert!(summary.quantiles().iter().all(|(_, v)| v.is_nan()));
+
+        for i in 1..=10_000 {
+            summary.observe(i as f64);
+        }
+        assert_eq!(summary.count(), 10_000);
+        assert_eq!(summary.sum(), 50_005_000.0);
+
+        // Allowed rank error is `min(q, 1 - q) / 10` of the observation count.
+        let quantiles = summary.quantiles();
+        assert_eq!(quantiles.len(), 3);
+        let (q, p50) = quantiles[0];
+        assert_eq!(q, 0.5);
+        assert!((p50 - 5_000.0).abs() <= 500.0, "p50 {p50}");
+        let (_, p90) = quantiles[1];
+        assert!((p90 - 9_000.0).abs() <= 100.0, "p90 {p90}");
+        let (_, p99) = quantiles[2];
+        assert!((p99 - 9_900.0).abs() <= 10.0, "p99 {p99}");
+    }
+
+    #[test]
+    fn test_summary_window_expires() {
+        use std::time::Duration;
+
+        use crate::Summary;
+
+        let summary = Summary::with_window(vec![0.5], Duration::from_secs(40), 2);
+        summary.observe(1.0);
+        assert_eq!(summary.quantiles(), vec![(0.5, 1.0)]);
+
+        // Within the first age bucket, the observation is still reported.
+        summary.advance(Duration::from_secs(19));
+        assert_eq!(summary.quantiles(), vec![(0.5, 1.0)]);
+
+        summary.advance(Duration::from_secs(41));
+        assert!(summary.quantiles()[0].1.is_nan());
+        // Sum and count are cumulative and not affected by the window.
+        assert_eq!(summary.count(), 1);
+        assert_eq!(summary.sum(), 1.0);
+
+        // Restored estimates expire with the next rotation.
+        summary.set_value(MetricValue::Summary {
+            quantiles: vec![(0.5, 2.0)],
+            sum: 3.0,
+            count: 2,
+        });
+        assert_eq!(summary.quantiles(), vec![(0.5, 2.0)]);
+        summary.advance(Duration::from_secs(20));
+        assert!(summary.quantiles()[0].1.is_nan());
+        assert_eq!(summary.count(), 2);
+
+        // Quantiles outside of `[0, 1]` are clamped.
+        let summary = Summary::with_window(vec![-0.5, 1.5, f64::NAN], Duration::ZERO, 1);
+        summary.observe(1.0);
+        assert_eq!(summary.quantiles(), vec![(0.0, 1.0), (1.0, 1.0)]);
+    }
+
+    #[test]
+    fn test_summary_openmetrics_format() {
+        use iroh_metrics_derive::MetricsGroup;
+
+        use crate::Summary;
+
+        #[derive(Debug, MetricsGroup)]
+        #[metrics(default, name = "rpc")]
+        pub struct SummaryMetrics {
+            /// Request latency
+            #[default(Summary::new(vec![0.5, 0.99]))]
+            pub latency: Summary,
+        }
+
+        let metrics = Arc::new(SummaryMetrics::default());
+        metrics.latency.observe(0.25);
+        let mut registry = Registry::default();
+        registry.register(metrics);
+
+        let exp = r#"# HELP rpc_latency Request latency.
+# TYPE rpc_latency summary
+rpc_latency{quantile="0.5"} 0.25
+rpc_latency{quantile="0.99"} 0.25
+rpc_latency_sum 0.25
+rpc_latency_count 1
+# EOF
+"#;
+        let output = registry.encode_openmetrics_to_string().unwrap();
+        assert_eq!(output, exp);
+
+        let parsed = prometheus_parse::Scrape::parse(output.lines().map(|s| Ok(s.to_owned())))
+            .expect("Failed to parse Prometheus output");
+        let sample = parsed
+            .samples
+            .iter()
+            .find(|s| s.metric == "rpc_latency")
+            .expect("Expected to find rpc_latency summary");
+        if let prometheus_parse::Value::Summary(quantiles) = &sample.value {
+            assert_eq!(quantiles.len(), 2);
+            assert_eq!(quantiles[1].quantile, 0.99);
+            assert_eq!(quantiles[1].count, 0.25);
+        } else {
+            panic!("Expected summary value, got {:?}", sample.value);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "postcard")]
+    fn test_summary_encode_decode() {
+        use iroh_metrics_derive::MetricsGroup;
+
+        use crate::{EncodeLabelSet, Family, Summary};
+
+        #[derive(
+            Clone,
+            Hash,
+            PartialEq,
+            Eq,
+            PartialOrd,
+            Ord,
+            Debug,
+            Serialize,
+            Deserialize,
+            EncodeLabelSet,
+        )]
+        struct Peer {
+            peer: String,
+        }
+
+        #[derive(Debug, MetricsGroup)]
+        #[metrics(default, name = "rpc")]
+        pub struct SummaryMetrics {
+            /// Request latency
+            pub latency: Summary,
+            /// Latency per peer
+            pub peer_latency: Family<Peer, Summary>,
+        }
+
+        let peer = Peer { peer: "a".into() };
+        let metrics = Arc::new(SummaryMetrics::default());
+        for v in [1.0, 2.0, 3.0] {
+            metrics.latency.observe(v);
+            metrics.peer_latency.get_or_create(&peer).observe(v);
+        }
+        let mut registry = Registry::default();
+        registry.register(metrics.clone());
+        let registry = Arc::new(RwLock::new(registry));
+
+        let mut encoder = Encoder::new(registry.clone());
+        let mut decoder = Decoder::default();
+        decoder
+            .import_bytes(&encoder.export_bytes().unwrap())
+            .unwrap();
+        assert_eq!(
+            decoder.encode_openmetrics_to_string().unwrap(),
+            registry.encode_openmetrics_to_string().unwrap(),
+        );
+
+        // Family serde restores the estimates through `Metric::set_value`.
+        let bytes = postcard::to_stdvec(&metrics.peer_latency).unwrap();
+        let decoded: Family<Peer, Summary> = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            decoded.get_or_create(&peer).value(),
+            metrics.peer_latency.get_or_create(&peer).value(),
+        );
+    }
+
+    #[test]
+    fn test_exponential_histogram() {
+        use crate::ExponentialHistogram;
+
+        // Scale 0: buckets are `(2^i, 2^(i + 1)]`.
+        let histogram = ExponentialHistogram::new(0);
+        for v in [0.0, 1.0, 1.5, 2.0, 3.0, 1024.0, -0.75] {
+            histogram.observe(v);
+        }
+        assert_eq!(histogram.count(), 7);
+        assert_eq!(histogram.sum(), 1030.75);
+        let MetricValue::ExponentialHistogram {
+            scale,
+            zero_count,
+            positive,
+            negative,
+            ..
+        } = histogram.value()
+        else {
+            panic!("Expected exponential histogram value");
+        };
+        assert_eq!(scale, 0);
+        assert_eq!(zero_count, 1);
+        assert_eq!(positive, vec![(-1, 1), (0, 2), (1, 1), (9, 1)]);
+        assert_eq!(negative, vec![(-1, 1)]);
+
+        // Downgraded to classic cumulative buckets.
+        assert_eq!(
+            histogram.buckets(),
+            vec![
+                (-0.5, 1),
+                (0.0, 2),
+                (1.0, 3),
+                (2.0, 5),
+                (4.0, 6),
+                (1024.0, 7),
+                (f64::INFINITY, 7),
+            ]
+        );
+
+        // Higher scales split each power of two into `2^scale` buckets.
+        let histogram = ExponentialHistogram::new(2);
+        histogram.observe(2.0);
+        histogram.observe(2.1);
+        let MetricValue::ExponentialHistogram { positive, .. } = histogram.value() else {
+            panic!("Expected exponential histogram value");
+        };
+        assert_eq!(positive, vec![(3, 1), (4, 1)]);
+
+        // Non-finite values only count towards count and sum, and the bucket
+        // of the largest values is merged into the `+Inf` bucket.
+        let histogram = ExponentialHistogram::new(0);
+        for v in [1.5, f64::INFINITY, f64::NEG_INFINITY, f64::NAN, f64::MAX] {
+            histogram.observe(v);
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(
+            histogram.buckets(),
+            vec![(0.0, 0), (2.0, 1), (f64::INFINITY, 5)]
+        );
+
+        // Restored values with another zero threshold are ignored, and the
+        // scale is clamped to the supported range.
+        let value = |zero_threshold, scale| MetricValue::ExponentialHistogram {
+            scale,
+            zero_threshold,
+            zero_count: 1,
+            positive: Vec::new(),
+            negative: Vec::new(),
+            sum: 0.0,
+            count: 1,
+        };
+        let histogram = ExponentialHistogram::new(0);
+        histogram.set_value(value(0.5, 0));
+        assert_eq!(histogram.count(), 0);
+        histogram.set_value(value(0.0, i8::MIN));
+        assert_eq!(histogram.count(), 1);
+        assert_eq!(histogram.scale(), ExponentialHistogram::MIN_SCALE);
+        histogram.observe(3.0);
+        assert_eq!(histogram.count(), 2);
+    }
+
+    #[test]
+    fn test_exponential_histogram_downscales() {
+        use crate::ExponentialHistogram;
+
+        let histogram = ExponentialHistogram::new(4).with_max_buckets(4);
+        // Six orders of magnitude can't fit into four buckets at scale 4.
+        for v in [1e-3, 1e-2, 1e-1, 1.0, 1e1, 1e2, 1e3] {
+            histogram.observe(v);
+        }
+        let MetricValue::ExponentialHistogram {
+            scale, positive, ..
+        } = histogram.value()
+        else {
+            panic!("Expected exponential histogram value");
+        };
+        assert!(scale < 4, "scale {scale}");
+        assert!(positive.len() <= 4, "buckets {positive:?}");
+        assert_eq!(positive.iter().map(|(_, c)| c).sum::<u64>(), 7);
+    }
+
+    #[test]
+    #[cfg(feature = "postcard")]
+    fn test_exponential_histogram_encode_decode() {
+        use iroh_metrics_derive::MetricsGroup;
+
+        use crate::ExponentialHistogram;
+
+        #[derive(Debug, Default, MetricsGroup)]
+        #[metrics(name = "rpc")]
+        pub struct ExpMetrics {
+            /// Request latency
+            pub latency: ExponentialHistogram,
+        }
+
+        let metrics = Arc::new(ExpMetrics::default());
+        for v in [0.002, 0.05, 0.3, 7.0, 1800.0] {
+            metrics.latency.observe(v);
+        }
+        let mut registry = Registry::default();
+        registry.register(metrics.clone());
+
+        let output = registry.encode_openmetrics_to_string().unwrap();
+        assert!(
+            output.contains("# TYPE rpc_latency histogram\n"),
+            "{output}"
+        );
+        assert!(
+            output.contains("rpc_latency_bucket{le=\"+Inf\"} 5\n"),
+            "{output}"
+        );
+        assert!(output.contains("rpc_latency_count 5\n"), "{output}");
+        let parsed = prometheus_parse::Scrape::parse(output.lines().map(|s| Ok(s.to_owned())))
+            .expect("Failed to parse Prometheus output");
+        assert!(parsed.samples.iter().any(|s| s.metric == "rpc_latency"));
+
+        let registry = Arc::new(RwLock::new(registry));
+        let mut encoder = Encoder::new(registry.clone());
+        let mut decoder = Decoder::default();
+        decoder
+            .import_bytes(&encoder.export_bytes().unwrap())
+            .unwrap();
+        let item = decoder.iter().next().unwrap();
+        assert_eq!(item.schema.r#type, MetricType::ExponentialHistogram);
+        assert_eq!(*item.value, metrics.latency.value());
+        assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), output);
+    }
+
+    #[test]
+    fn test_exemplars() {
+        use iroh_metrics_derive::MetricsGroup;
+
+        use crate::Exemplar;
+
+        #[derive(Debug, MetricsGroup)]
+        #[metrics(default, name = "rpc")]
+        pub struct ExemplarMetrics {
+            /// Requests
+            pub requests: Counter,
+            /// Request latency
+            #[default(Histogram::new(vec![0.1, 1.0]))]
+            pub latency: Histogram,
+        }
+
+        let metrics = Arc::new(ExemplarMetrics::default());
+        metrics.requests.inc();
+        assert!(metrics.requests.exemplars().is_empty());
+        metrics.requests.inc_with_exemplar([("trace_id", "abc")]);
+        metrics.latency.observe(0.05);
+        metrics
+            .latency
+            .observe_with_exemplar(0.5, [("trace_id", "def")]);
+        metrics
+            .latency
+            .observe_with_exemplar(0.7, [("trace_id", "ghi")]);
+        metrics.latency.observe(7.0);
+
+        let exemplars = metrics.latency.exemplars();
+        assert_eq!(exemplars.len(), 3);
+        assert!(exemplars[0].is_none());
+        let Some(Exemplar {
+            labels,
+            value,
+            timestamp,
+        }) = &exemplars[1]
+        else {
+            panic!("missing exemplar");
+        };
+        assert_eq!(labels, &[("trace_id".to_string(), "ghi".to_string())]);
+        assert_eq!(*value, 0.7);
+        assert!(timestamp.is_some());
+
+        // Labels are limited to 128 characters in total.
+        let long = "x".repeat(100);
+        let limited = Exemplar::new(
+            [("trace_id", long.as_str()), ("span_id", "abcdefghijklmnop")],
+            1.0,
+        );
+        assert_eq!(limited.labels, [("trace_id".to_string(), long.clone())]);
+        let limited = Exemplar::new([("trace_id", "x".repeat(121))], 1.0);
+        assert!(limited.labels.is_empty());
+
+        // Pin the timestamps so the output is deterministic.
+        let exemplar = |trace_id: &str, value| {
+            Some(Exemplar {
+                labels: vec![("trace_id".into(), trace_id.into())],
+                value,
+                timestamp: Some(1700000000.5),
+            })
+        };
+        *metrics.requests.exemplar.get().unwrap().lock().unwrap() = exemplar("abc", 1.0).unwrap();
+        metrics.latency.exemplars.lock().unwrap()[1] = exemplar("ghi", 0.7);
+
+        let mut registry = Registry::default();
+        registry.register(metrics.clone());
+        let output = registry.encode_openmetrics_to_string().unwrap();
+        let expected = "# HELP rpc_requests Requests.
+# TYPE rpc_requests counter
+rpc_requests_total 2 # {trace_id=\"abc\"} 1.0 1700000000.5
+# HELP rpc_latency Request latency.
+# TYPE rpc_latency histogram
+rpc_latency_bucket{le=\"0.1\"} 1
+rpc_latency_bucket{le=\"1.0\"} 3 # {trace_id=\"ghi\"} 0.7 1700000000.5
+rpc_latency_bucket{le=\"+Inf\"} 4
+rpc_latency_sum 8.25
+rpc_latency_count 4
+# EOF
+";
+        assert_eq!(output, expected);
+
+        let families = crate::encoding::parse_openmetrics(&output).unwrap();
+        assert_eq!(families[0].samples[0].exemplar, exemplar("abc", 1.0));
+        assert_eq!(families[1].samples[1].exemplar, exemplar("ghi", 0.7));
+
+        // Exemplars survive the binary encoding.
+        #[cfg(feature = "postcard")]
+        {
+            let mut encoder = Encoder::new(Arc::new(RwLock::new(registry)));
+            let mut decoder = Decoder::default();
+            decoder
+                .import_bytes(&encoder.export_bytes().unwrap())
+                .unwrap();
+            assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), expected);
+        }
+    }
+
     #[test]
     fn test_family_in_metrics_group() {
         use std::borrow::Cow;
@@ -869,10 +1906,12 @@ combined_bar_count_total{x="y"} 10
             assert_eq!(schema.items.len(), 3);
 
             let mut decoder = Decoder::default();
-            decoder.import(Update {
-                schema: Some(schema),
-                values: third.values,
-            });
+            decoder
+                .import(Update {
+                    schema: Some(schema),
+                    ..third
+                })
+                .unwrap();
             assert_eq!(
                 decoder.encode_openmetrics_to_string().unwrap(),
                 registry.encode_openmetrics_to_string().unwrap(),
@@ -0,0 +1,251 @@
+//! Metrics that are read at encode time.
+
+use std::{
+    borrow::Cow,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+};
+
+use portable_atomic::{AtomicU64, Ordering};
+
+use crate::{
+    MetricType, MetricValue,
+    encoding::{GroupedSamples, ItemSchema, Schema, Values, encode_metric_value},
+};
+
+/// Produces metrics when a [`Registry`](crate::Registry) is encoded.
+///
+/// Collectors suit values that are cheap to read but awkward to keep up to
+/// date in a [`Counter`](crate::Counter) or [`Gauge`](crate::Gauge), like
+/// queue lengths or [`Arc::strong_count`]. Register them with
+/// [`Registry::register_collector`](crate::Registry::register_collector).
+///
+/// Closures returning a `Vec<CollectedMetric>` implement this trait:
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use iroh_metrics::{CollectedMetric, Registry};
+/// let queue = Arc::new(std::sync::Mutex::new(Vec::<u8>::new()));
+/// let mut registry = Registry::default();
+/// registry.register_collector(Arc::new({
+///     let queue = queue.clone();
+///     move || {
+///         let len = queue.lock().unwrap().len();
+///         vec![CollectedMetric::gauge(
+///             "queue_len",
+///             "Items in the queue",
+///             len as i64,
+///         )]
+///     }
+/// }));
+/// ```
+pub trait Collector: Send + Sync + 'static {
+    /// Returns the current metrics.
+    ///
+    /// Called on every encode, so this should be fast. Metrics with the same
+    /// name must have the same type and help text.
+    fn collect(&self) -> Vec<CollectedMetric>;
+}
+
+impl<F> Collector for F
+where
+    F: Fn() -> Vec<CollectedMetric> + Send + Sync + 'static,
+{
+    fn collect(&self) -> Vec<CollectedMetric> {
+        self()
+    }
+}
+
+impl fmt::Debug for dyn Collector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Collector")
+    }
+}
+
+/// A metric value produced by a [`Collector`].
+///
+/// Unlike a [`MetricItem`](crate::MetricItem), it owns its value and can
+/// carry labels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectedMetric {
+    /// The metric name, without the registry prefix.
+    pub name: Cow<'static, str>,
+    /// The help text.
+    pub help: Cow<'static, str>,
+    /// Labels added after the registry labels.
+    pub labels: Vec<(Cow<'static, str>, Cow<'static, str>)>,
+    /// The current value.
+    pub value: MetricValue,
+}
+
+impl CollectedMetric {
+    /// Creates a metric without labels.
+    pub fn new(
+        name: impl Into<Cow<'static, str>>,
+        help: impl Into<Cow<'static, str>>,
+        value: MetricValue,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            help: help.into(),
+            labels: Vec::new(),
+            value,
+        }
+    }
+
+    /// Creates a counter metric.
+    pub fn counter(
+        name: impl Into<Cow<'static, str>>,
+        help: impl Into<Cow<'static, str>>,
+        value: u64,
+    ) -> Self {
+        Self::new(name, help, MetricValue::Counter(value))
+    }
+
+    /// Creates a gauge metric.
+    pub fn gauge(
+        name: impl Into<Cow<'static, str>>,
+        help: impl Into<Cow<'static, str>>,
+        value: i64,
+    ) -> Self {
+        Self::new(name, help, MetricValue::Gauge(value))
+    }
+
+    /// Adds a label.
+    pub fn with_label(
+        mut self,
+        key: impl Into<Cow<'static, str>>,
+        value: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Returns the [`MetricType`] of the value.
+    pub fn r#type(&self) -> MetricType {
+        self.value.r#type()
+    }
+}
+
+/// A [`Collector`] in a registry, with the shape of its last output.
+#[derive(Debug)]
+pub(crate) struct RegisteredCollector {
+    pub(crate) collector: Arc<dyn Collector>,
+    /// Hash of the names, labels and types last exported by `encode_schema`.
+    shape: AtomicU64,
+}
+
+impl RegisteredCollector {
+    /// Wraps `collector`, calling it once to record its initial shape.
+    pub(crate) fn new(collector: Arc<dyn Collector>) -> Self {
+        let shape = AtomicU64::new(shape(&collector.collect()));
+        Self { collector, shape }
+    }
+
+    /// Adds the samples of all collected metrics to `samples`.
+    pub(crate) fn collect_openmetrics(
+        &self,
+        samples: &mut GroupedSamples,
+        prefix: Option<&str>,
+        labels: &[(Cow<'_, str>, Cow<'_, str>)],
+    ) -> fmt::Result {
+        let prefixes = prefix.as_slice();
+        for metric in self.collector.collect() {
+            let buf = samples.get_or_insert(prefixes, &metric.name, &metric.help, metric.r#type());
+            encode_metric_value(
+                buf,
+                &metric.name,
+                prefixes,
+                labels,
+                &metric.labels,
+                &metric.value,
+                &[],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Adds the schema and values of all collected metrics.
+    ///
+    /// A collector may return different metrics on every call. If they differ
+    /// from the previous call, `schema_version` is bumped so that encoders
+    /// send the schema along with the values.
+    pub(crate) fn encode_schema(
+        &self,
+        schema: Option<&mut Schema>,
+        values: &mut Values,
+        prefix: Option<&str>,
+        labels: &[(Cow<'_, str>, Cow<'_, str>)],
+        schema_version: &AtomicU64,
+    ) {
+        let metrics = self.collector.collect();
+        let shape = shape(&metrics);
+        if self.shape.swap(shape, Ordering::Relaxed) != shape {
+            schema_version.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let prefixes = prefix.as_slice();
+        let mut schema = schema;
+        for metric in metrics {
+            if let Some(schema) = schema.as_deref_mut() {
+                let labels = labels
+                    .iter()
+                    .chain(&metric.labels)
+                    .map(|(k, v)| (k.as_ref(), v.as_ref()));
+                schema.push(
+                    ItemSchema::from_label_iter(&metric.name, prefixes, labels, metric.r#type()),
+                    &metric.help,
+                );
+            }
+            values.push(metric.value, Vec::new());
+        }
+    }
+}
+
+/// Hashes the names, help texts, labels and types of `metrics`.
+fn shape(metrics: &[CollectedMetric]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for metric in metrics {
+        (&metric.name, &metric.help, &metric.labels, metric.r#type()).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shape() {
+        let histogram = MetricValue::Histogram {
+            buckets: vec![(f64::INFINITY, 0)],
+            sum: 0.0,
+            count: 0,
+        };
+        let exponential = MetricValue::ExponentialHistogram {
+            scale: 0,
+            zero_threshold: 0.0,
+            zero_count: 0,
+            positive: Vec::new(),
+            negative: Vec::new(),
+            sum: 0.0,
+            count: 0,
+        };
+        let base = shape(&[CollectedMetric::new(
+            "latency",
+            "Latency",
+            histogram.clone(),
+        )]);
+
+        // Both encode as `histogram`, but carry different schemas.
+        let other_type = shape(&[CollectedMetric::new("latency", "Latency", exponential)]);
+        assert_ne!(base, other_type);
+
+        let other_help = shape(&[CollectedMetric::new("latency", "Delay", histogram.clone())]);
+        assert_ne!(base, other_help);
+
+        let same = shape(&[CollectedMetric::new("latency", "Latency", histogram)]);
+        assert_eq!(base, same);
+    }
+}
@@ -1,20 +1,31 @@
-//! Functions to encode metrics into the [OpenMetrics text format].
+//! Functions to encode metrics into the [OpenMetrics text format], and to
+//! parse them back from it.
 //!
 //! [OpenMetrics text format]: https://github.com/prometheus/OpenMetrics/blob/main/specification/OpenMetrics.md
 
 use std::{
     borrow::Cow,
+    collections::{HashMap, VecDeque},
     fmt::{self, Write},
+    hash::{BuildHasher, RandomState},
     sync::{Arc, RwLock},
 };
 
+use n0_error::e;
 use serde::{Deserialize, Serialize};
 
+pub use self::{
+    aggregate::AggregatingDecoder,
+    parse::{ParseError, ParsedFamily, ParsedSample, parse_openmetrics},
+};
 use crate::{
-    LabelValue, MetricItem, MetricType, MetricValue, MetricsGroup, MetricsSource, RwLockRegistry,
-    iterable::IntoIterable,
+    Exemplar, LabelValue, MetricItem, MetricType, MetricValue, MetricsGroup, MetricsSource,
+    RwLockRegistry, iterable::IntoIterable, metrics::exponential_to_classic,
 };
 
+mod aggregate;
+mod parse;
+
 /// Encodes a label value directly into the writer.
 ///
 /// Blanket-implemented for any `AsRef<str>` so existing callers using `&str`,
@@ -76,9 +87,17 @@ pub(crate) fn encode_eof(writer: &mut impl Write) -> fmt::Result {
 /// Encodes a metric value (without HELP/TYPE headers) in OpenMetrics format.
 ///
 /// The following suffixes are appended to the metric name per the OpenMetrics spec:
-/// - Counter: `_total` (e.g. `my_counter_total`)
+/// - Counter and f64 counter: `_total` (e.g. `my_counter_total`)
 /// - Gauge: no suffix
 /// - Histogram: `_bucket` (with `le` label), `_sum`, `_count`
+/// - Gauge histogram: `_bucket` (with `le` label), `_gsum`, `_gcount`
+/// - Summary: no suffix (with `quantile` label), `_sum`, `_count`
+/// - Exponential histogram: downgraded to a classic histogram
+/// - Info: `_info`, with the info as labels
+/// - State set: no suffix, one sample per state labeled with the metric name
+///
+/// `exemplars` are indexed like [`Metric::exemplars`](crate::Metric::exemplars)
+/// and appended to the counter sample and the histogram bucket samples.
 pub(crate) fn encode_metric_value<W, K1, V1, K2, V2>(
     writer: &mut W,
     name: &str,
@@ -86,6 +105,7 @@ pub(crate) fn encode_metric_value<W, K1, V1, K2, V2>(
     labels: &[(K1, V1)],
     extra_labels: &[(K2, V2)],
     value: &MetricValue,
+    exemplars: &[Option<Exemplar>],
 ) -> fmt::Result
 where
     W: Write + ?Sized,
@@ -96,58 +116,246 @@ where
 {
     match value {
         MetricValue::Counter(v) => {
-            encode_prefix_name(writer, prefixes, name)?;
-            // Counters require a `_total` suffix per the OpenMetrics spec.
-            // Skip if the encoded name already ends with `_total`. Two cases:
-            //   - `name` itself ends with `_total` (e.g. `requests_total`).
-            //   - `name` is exactly `total` and there is at least one prefix,
-            //     so the prefix's `_` separator turns the join into
-            //     `..._total`.
-            let already_total =
-                name.ends_with("_total") || (name == "total" && !prefixes.is_empty());
-            if !already_total {
-                writer.write_str("_total")?;
-            }
-            encode_labels(writer, labels, extra_labels, None)?;
-            writer.write_char(' ')?;
+            encode_counter_start(writer, prefixes, name, labels, extra_labels)?;
             encode_u64(writer, *v)?;
+            encode_exemplar(writer, exemplars.first())?;
+            writer.write_char('\n')?;
+        }
+        MetricValue::CounterF64(v) => {
+            encode_counter_start(writer, prefixes, name, labels, extra_labels)?;
+            encode_f64(writer, *v)?;
+            encode_exemplar(writer, exemplars.first())?;
             writer.write_char('\n')?;
         }
         MetricValue::Gauge(v) => {
-            encode_prefix_name(writer, prefixes, name)?;
-            encode_labels(writer, labels, extra_labels, None)?;
-            writer.write_char(' ')?;
+            encode_sample_start(writer, prefixes, name, "", labels, extra_labels, &[])?;
             encode_i64(writer, *v)?;
             writer.write_char('\n')?;
         }
+        MetricValue::GaugeF64(v) => {
+            encode_sample_start(writer, prefixes, name, "", labels, extra_labels, &[])?;
+            encode_f64(writer, *v)?;
+            writer.write_char('\n')?;
+        }
         MetricValue::Histogram {
             buckets,
             sum,
             count,
+        } => {
+            for (i, (le, cnt)) in buckets.iter().enumerate() {
+                let bound = [("le", TrailingValue::Bound(*le))];
+                encode_sample_start(
+                    writer,
+                    prefixes,
+                    name,
+                    "_bucket",
+                    labels,
+                    extra_labels,
+                    &bound,
+                )?;
+                encode_u64(writer, *cnt)?;
+                encode_exemplar(writer, exemplars.get(i))?;
+                writer.write_char('\n')?;
+            }
+            encode_sum_count(writer, prefixes, name, labels, extra_labels, *sum, *count)?;
+        }
+        MetricValue::GaugeHistogram {
+            buckets,
+            sum,
+            count,
         } => {
             for (le, cnt) in buckets {
-                encode_prefix_name(writer, prefixes, name)?;
-                writer.write_str("_bucket")?;
-                encode_labels(writer, labels, extra_labels, Some(*le))?;
-                writer.write_char(' ')?;
+                let bound = [("le", TrailingValue::Bound(*le))];
+                encode_sample_start(
+                    writer,
+                    prefixes,
+                    name,
+                    "_bucket",
+                    labels,
+                    extra_labels,
+                    &bound,
+                )?;
                 encode_u64(writer, *cnt)?;
                 writer.write_char('\n')?;
             }
-
-            encode_prefix_name(writer, prefixes, name)?;
-            writer.write_str("_sum")?;
-            encode_labels(writer, labels, extra_labels, None)?;
-            writer.write_char(' ')?;
+            encode_sample_start(writer, prefixes, name, "_gsum", labels, extra_labels, &[])?;
             encode_f64(writer, *sum)?;
             writer.write_char('\n')?;
-
-            encode_prefix_name(writer, prefixes, name)?;
-            writer.write_str("_count")?;
-            encode_labels(writer, labels, extra_labels, None)?;
-            writer.write_char(' ')?;
+            encode_sample_start(writer, prefixes, name, "_gcount", labels, extra_labels, &[])?;
             encode_u64(writer, *count)?;
             writer.write_char('\n')?;
         }
+        MetricValue::Summary {
+            quantiles,
+            sum,
+            count,
+        } => {
+            for (q, v) in quantiles {
+                let bound = [("quantile", TrailingValue::Bound(*q))];
+                encode_sample_start(writer, prefixes, name, "", labels, extra_labels, &bound)?;
+                encode_f64(writer, *v)?;
+                writer.write_char('\n')?;
+            }
+            encode_sum_count(writer, prefixes, name, labels, extra_labels, *sum, *count)?;
+        }
+        MetricValue::ExponentialHistogram {
+            scale,
+            zero_threshold,
+            zero_count,
+            positive,
+            negative,
+            sum,
+            count,
+        } => {
+            let buckets = exponential_to_classic(
+                *scale,
+                *zero_threshold,
+                *zero_count,
+                positive,
+                negative,
+                *count,
+            );
+            let classic = MetricValue::Histogram {
+                buckets,
+                sum: *sum,
+                count: *count,
+            };
+            encode_metric_value(writer, name, prefixes, labels, extra_labels, &classic, &[])?;
+        }
+        MetricValue::Info(info) => {
+            let suffix = if name.ends_with("_info") { "" } else { "_info" };
+            let info: Vec<_> = info
+                .iter()
+                .map(|(k, v)| (k.as_str(), TrailingValue::Str(v)))
+                .collect();
+            encode_sample_start(writer, prefixes, name, suffix, labels, extra_labels, &info)?;
+            writer.write_str("1\n")?;
+        }
+        MetricValue::StateSet(states) => {
+            // The state label is named after the metric family.
+            let mut key = String::new();
+            encode_prefix_name(&mut key, prefixes, name)?;
+            for (state, active) in states {
+                let state = [(key.as_str(), TrailingValue::Str(state))];
+                encode_sample_start(writer, prefixes, name, "", labels, extra_labels, &state)?;
+                writer.write_str(if *active { "1\n" } else { "0\n" })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `<prefixes>_<name><suffix>{labels} ` — everything of a sample line
+/// up to the value.
+///
+/// `trailing` labels are appended after all other labels, used for the
+/// histogram `le` and summary `quantile` labels, the state of a state set
+/// and the labels of an info.
+fn encode_sample_start<W, K1, V1, K2, V2>(
+    writer: &mut W,
+    prefixes: &[impl AsRef<str>],
+    name: &str,
+    suffix: &str,
+    labels: &[(K1, V1)],
+    extra_labels: &[(K2, V2)],
+    trailing: &[(&str, TrailingValue<'_>)],
+) -> fmt::Result
+where
+    W: Write + ?Sized,
+    K1: AsRef<str>,
+    V1: EncodeLabelTo,
+    K2: AsRef<str>,
+    V2: EncodeLabelTo,
+{
+    encode_prefix_name(writer, prefixes, name)?;
+    writer.write_str(suffix)?;
+    encode_labels(writer, labels, extra_labels, trailing)?;
+    writer.write_char(' ')
+}
+
+/// The value of a label written by [`encode_sample_start`] after all others.
+#[derive(Debug, Clone, Copy)]
+enum TrailingValue<'a> {
+    /// A numeric bound, like the `le` of a histogram bucket.
+    Bound(f64),
+    /// A string value.
+    Str(&'a str),
+}
+
+/// Writes the start of a counter sample, up to the value.
+fn encode_counter_start<W, K1, V1, K2, V2>(
+    writer: &mut W,
+    prefixes: &[impl AsRef<str>],
+    name: &str,
+    labels: &[(K1, V1)],
+    extra_labels: &[(K2, V2)],
+) -> fmt::Result
+where
+    W: Write + ?Sized,
+    K1: AsRef<str>,
+    V1: EncodeLabelTo,
+    K2: AsRef<str>,
+    V2: EncodeLabelTo,
+{
+    // Counters require a `_total` suffix per the OpenMetrics spec.
+    // Skip if the encoded name already ends with `_total`. Two cases:
+    //   - `name` itself ends with `_total` (e.g. `requests_total`).
+    //   - `name` is exactly `total` and there is at least one prefix,
+    //     so the prefix's `_` separator turns the join into
+    //     `..._total`.
+    let already_total = name.ends_with("_total") || (name == "total" && !prefixes.is_empty());
+    let suffix = if already_total { "" } else { "_total" };
+    encode_sample_start(writer, prefixes, name, suffix, labels, extra_labels, &[])
+}
+
+/// Writes the `_sum` and `_count` lines shared by histograms and summaries.
+fn encode_sum_count<W, K1, V1, K2, V2>(
+    writer: &mut W,
+    prefixes: &[impl AsRef<str>],
+    name: &str,
+    labels: &[(K1, V1)],
+    extra_labels: &[(K2, V2)],
+    sum: f64,
+    count: u64,
+) -> fmt::Result
+where
+    W: Write + ?Sized,
+    K1: AsRef<str>,
+    V1: EncodeLabelTo,
+    K2: AsRef<str>,
+    V2: EncodeLabelTo,
+{
+    encode_sample_start(writer, prefixes, name, "_sum", labels, extra_labels, &[])?;
+    encode_f64(writer, sum)?;
+    writer.write_char('\n')?;
+    encode_sample_start(writer, prefixes, name, "_count", labels, extra_labels, &[])?;
+    encode_u64(writer, count)?;
+    writer.write_char('\n')
+}
+
+/// Writes ` # {labels} value [timestamp]` if an exemplar is present.
+fn encode_exemplar<W>(writer: &mut W, exemplar: Option<&Option<Exemplar>>) -> fmt::Result
+where
+    W: Write + ?Sized,
+{
+    let Some(Some(exemplar)) = exemplar else {
+        return Ok(());
+    };
+    writer.write_str(" # {")?;
+    for (i, (k, v)) in exemplar.limited_labels().iter().enumerate() {
+        if i > 0 {
+            writer.write_char(',')?;
+        }
+        writer.write_str(k)?;
+        writer.write_str("=\"")?;
+        encode_escaped(writer, v, true)?;
+        writer.write_char('"')?;
+    }
+    writer.write_str("} ")?;
+    encode_f64(writer, exemplar.value)?;
+    if let Some(timestamp) = exemplar.timestamp {
+        writer.write_char(' ')?;
+        encode_f64(writer, timestamp)?;
     }
     Ok(())
 }
@@ -156,7 +364,7 @@ fn encode_labels<W, K1, V1, K2, V2>(
     w: &mut W,
     labels: &[(K1, V1)],
     extra_labels: &[(K2, V2)],
-    le: Option<f64>,
+    trailing: &[(&str, TrailingValue<'_>)],
 ) -> fmt::Result
 where
     W: Write + ?Sized,
@@ -165,7 +373,7 @@ where
     K2: AsRef<str>,
     V2: EncodeLabelTo,
 {
-    if labels.is_empty() && extra_labels.is_empty() && le.is_none() {
+    if labels.is_empty() && extra_labels.is_empty() && trailing.is_empty() {
         return Ok(());
     }
 
@@ -194,15 +402,21 @@ where
         first = false;
     }
 
-    if let Some(le) = le {
+    for (key, value) in trailing {
         if !first {
             w.write_char(',')?;
         }
-        if le.is_infinite() {
-            w.write_str("le=\"+Inf\"")?;
-        } else {
-            write!(w, "le=\"{}\"", ryu::Buffer::new().format(le))?;
+        w.write_str(key)?;
+        match value {
+            TrailingValue::Bound(value) if value.is_infinite() => w.write_str("=\"+Inf\"")?,
+            TrailingValue::Bound(value) => write!(w, "=\"{}\"", ryu::Buffer::new().format(*value))?,
+            TrailingValue::Str(value) => {
+                w.write_str("=\"")?;
+                encode_escaped(w, value, true)?;
+                w.write_char('"')?;
+            }
         }
+        first = false;
     }
 
     w.write_char('}')
@@ -344,6 +558,28 @@ pub struct HistogramData {
 pub struct Values {
     /// The individual metric values
     pub items: Vec<MetricValue>,
+    /// Exemplars of the metric values, as `(index into items, exemplars)`.
+    ///
+    /// Sorted by index. Items without any exemplar are omitted.
+    pub exemplars: Vec<(usize, Vec<Option<Exemplar>>)>,
+}
+
+impl Values {
+    /// Appends a value and its exemplars.
+    pub(crate) fn push(&mut self, value: MetricValue, exemplars: Vec<Option<Exemplar>>) {
+        if exemplars.iter().any(Option::is_some) {
+            self.exemplars.push((self.items.len(), exemplars));
+        }
+        self.items.push(value);
+    }
+
+    /// Returns the exemplars of the value at `index`.
+    pub fn exemplars(&self, index: usize) -> &[Option<Exemplar>] {
+        match self.exemplars.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(pos) => &self.exemplars[pos].1,
+            Err(_) => &[],
+        }
+    }
 }
 
 /// An update containing schema and/or values for metrics.
@@ -351,11 +587,198 @@ pub struct Values {
 /// Used to transfer metric information between encoders and decoders.
 /// The schema is optional and only included when it has changed.
 #[derive(Debug, Serialize, Clone, Deserialize, Default)]
+#[non_exhaustive]
 pub struct Update {
+    /// Sequence number, incremented by one for every update of an [`Encoder`]
+    pub seq: u64,
+    /// Version of the schema the values are aligned to
+    ///
+    /// Starts at a random value for every encoder, so that a decoder
+    /// doesn't apply the values of one encoder to the schema of another,
+    /// and is incremented whenever the encoder sends a schema.
+    pub schema_version: u64,
     /// Optional schema information (included when schema changes)
     pub schema: Option<Schema>,
     /// The metric values
+    ///
+    /// Empty if the update carries a [`delta`](Self::delta) instead.
     pub values: Values,
+    /// Changes to the values of an acknowledged update
+    ///
+    /// Only set by encoders with [`EncoderOpts::delta`] enabled.
+    pub delta: Option<ValuesDelta>,
+}
+
+/// The changes between two [`Values`] of the same schema.
+#[derive(Debug, Serialize, Clone, Deserialize, Default, PartialEq)]
+pub struct ValuesDelta {
+    /// Sequence number of the update whose values the changes apply to
+    pub base: u64,
+    /// Number of values after applying the changes
+    pub len: usize,
+    /// Changed values as `(index, change)`, sorted by index
+    pub items: Vec<(usize, ValueDelta)>,
+    /// Changed exemplars as `(index, exemplars)`, sorted by index
+    ///
+    /// An empty list removes the exemplars of an item.
+    pub exemplars: Vec<(usize, Vec<Option<Exemplar>>)>,
+}
+
+impl ValuesDelta {
+    /// Computes the changes from `old`, the values of update `base`, to `new`.
+    pub fn between(base: u64, old: &Values, new: &Values) -> Self {
+        let items = new
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, value)| match old.items.get(i) {
+                Some(old) => ValueDelta::between(old, value).map(|delta| (i, delta)),
+                None => Some((i, ValueDelta::Value(value.clone()))),
+            })
+            .collect();
+        let indices = old.exemplars.iter().chain(&new.exemplars).map(|(i, _)| *i);
+        let mut exemplars: Vec<_> = indices
+            .filter(|i| *i < new.items.len() && old.exemplars(*i) != new.exemplars(*i))
+            .map(|i| (i, new.exemplars(i).to_vec()))
+            .collect();
+        exemplars.sort_by_key(|(i, _)| *i);
+        exemplars.dedup_by_key(|(i, _)| *i);
+        Self {
+            base,
+            len: new.items.len(),
+            items,
+            exemplars,
+        }
+    }
+
+    /// Applies the changes to `values`.
+    ///
+    /// `values` must be the values the delta was computed from. Changes that
+    /// don't fit the current values are skipped.
+    pub fn apply(self, values: &mut Values) {
+        values.items.truncate(self.len);
+        for (i, delta) in self.items {
+            let len = values.items.len();
+            match (values.items.get_mut(i), delta) {
+                (Some(value), delta) => delta.apply(value),
+                (None, ValueDelta::Value(value)) if i == len => values.items.push(value),
+                (None, _) => {}
+            }
+        }
+        values.exemplars.retain(|(i, _)| *i < self.len);
+        for (i, exemplars) in self.exemplars {
+            match values.exemplars.binary_search_by_key(&i, |(i, _)| *i) {
+                Ok(pos) if exemplars.is_empty() => {
+                    values.exemplars.remove(pos);
+                }
+                Ok(pos) => values.exemplars[pos].1 = exemplars,
+                Err(_) if exemplars.is_empty() => {}
+                Err(pos) => values.exemplars.insert(pos, (i, exemplars)),
+            }
+        }
+    }
+}
+
+/// The change of a single [`MetricValue`].
+#[derive(Debug, Serialize, Clone, Deserialize, PartialEq)]
+#[non_exhaustive]
+pub enum ValueDelta {
+    /// Increase of a counter
+    Counter(u64),
+    /// Changes of a histogram whose bucket bounds stayed the same
+    Histogram {
+        /// Increases of the changed cumulative bucket counts, as `(bucket index, increase)`
+        buckets: Vec<(usize, u64)>,
+        /// The new sum of all observed values
+        sum: f64,
+        /// Increase of the total count of observations
+        count: u64,
+    },
+    /// A new value, replacing the previous one
+    Value(MetricValue),
+}
+
+impl ValueDelta {
+    /// Computes the change from `old` to `new`, or `None` if they are equal.
+    ///
+    /// Counters and histograms that only grew are sent as increases, which
+    /// serialize to small varints. Anything else, like a reset, is sent as a
+    /// replacement value.
+    pub fn between(old: &MetricValue, new: &MetricValue) -> Option<Self> {
+        if old == new {
+            return None;
+        }
+        let delta = match (old, new) {
+            (MetricValue::Counter(old), MetricValue::Counter(new)) if new > old => {
+                Self::Counter(new - old)
+            }
+            (
+                MetricValue::Histogram {
+                    buckets: old_buckets,
+                    count: old_count,
+                    ..
+                },
+                MetricValue::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                },
+            ) if count >= old_count
+                && buckets.len() == old_buckets.len()
+                && buckets
+                    .iter()
+                    .zip(old_buckets)
+                    .all(|((bound, n), (old_bound, old_n))| bound == old_bound && n >= old_n) =>
+            {
+                Self::Histogram {
+                    buckets: buckets
+                        .iter()
+                        .zip(old_buckets)
+                        .enumerate()
+                        .filter(|(_, ((_, n), (_, old_n)))| n != old_n)
+                        .map(|(i, ((_, n), (_, old_n)))| (i, n - old_n))
+                        .collect(),
+                    sum: *sum,
+                    count: count - old_count,
+                }
+            }
+            _ => Self::Value(new.clone()),
+        };
+        Some(delta)
+    }
+
+    /// Applies the change to `value`.
+    ///
+    /// Increases are skipped if `value` is not of the matching type.
+    pub fn apply(self, value: &mut MetricValue) {
+        match (self, value) {
+            (Self::Value(new), value) => *value = new,
+            (Self::Counter(increase), MetricValue::Counter(value)) => {
+                *value = value.wrapping_add(increase)
+            }
+            (
+                Self::Histogram {
+                    buckets: increases,
+                    sum: new_sum,
+                    count: increase,
+                },
+                MetricValue::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                },
+            ) => {
+                for (i, increase) in increases {
+                    if let Some((_, n)) = buckets.get_mut(i) {
+                        *n = n.wrapping_add(increase);
+                    }
+                }
+                *sum = new_sum;
+                *count = count.wrapping_add(increase);
+            }
+            _ => {}
+        }
+    }
 }
 
 /// A metric item combining schema and value information.
@@ -367,6 +790,8 @@ pub struct Item<'a> {
     pub schema: &'a ItemSchema,
     /// Reference to the metric's current value
     pub value: &'a MetricValue,
+    /// The exemplars of the value, indexed like [`Metric::exemplars`](crate::Metric::exemplars)
+    pub exemplars: &'a [Option<Exemplar>],
     /// Help text, if available
     pub help: Option<&'a String>,
 }
@@ -387,6 +812,10 @@ impl EncodableMetric for Item<'_> {
     fn value(&self) -> MetricValue {
         self.value.clone()
     }
+
+    fn exemplars(&self) -> Vec<Option<Exemplar>> {
+        self.exemplars.to_vec()
+    }
 }
 
 impl Item<'_> {
@@ -413,23 +842,6 @@ impl Item<'_> {
         Ok(())
     }
 
-    /// Writes only the `# HELP` and `# TYPE` headers for this item.
-    pub(crate) fn encode_openmetrics_header(
-        &self,
-        writer: &mut impl std::fmt::Write,
-    ) -> fmt::Result {
-        writer.write_str("# HELP ")?;
-        encode_prefix_name(writer, &self.schema.prefixes, &self.schema.name)?;
-        writer.write_str(" ")?;
-        encode_help_text(writer, self.help.map(|x| x.as_str()).unwrap_or_default())?;
-
-        writer.write_str("# TYPE ")?;
-        encode_prefix_name(writer, &self.schema.prefixes, &self.schema.name)?;
-        writer.write_str(" ")?;
-        writer.write_str(self.schema.r#type.as_str())?;
-        writer.write_str("\n")
-    }
-
     /// Writes only the value line(s) for this item, without `# HELP`/`# TYPE`.
     pub(crate) fn encode_openmetrics_value(
         &self,
@@ -449,10 +861,53 @@ impl Item<'_> {
             &labels,
             empty,
             self.value,
+            self.exemplars,
         )
     }
 }
 
+/// Error returned when a [`Decoder`] can't import an [`Update`].
+///
+/// The decoder is left unchanged. To recover, call [`Encoder::reset`] so
+/// that the next update carries the schema and all values.
+#[n0_error::stack_error(derive, add_meta, from_sources, std_sources)]
+#[non_exhaustive]
+pub enum DecodeError {
+    /// The values refer to a schema the decoder doesn't have.
+    #[error("update has schema version {actual}, but the decoder has {expected:?}")]
+    SchemaMismatch {
+        /// The schema version of the decoder, if it has a schema
+        expected: Option<u64>,
+        /// The schema version of the update
+        actual: u64,
+    },
+    /// The update is not newer than the last imported update of its encoder.
+    #[error("update {actual} is not newer than the last imported update {last}")]
+    OutOfOrder {
+        /// Sequence number of the last imported update
+        last: u64,
+        /// Sequence number of the update
+        actual: u64,
+    },
+    /// The update a delta update is based on was not imported.
+    #[error("missing update {base} that the delta is based on")]
+    MissingBase {
+        /// Sequence number of the update the delta is based on
+        base: u64,
+    },
+    /// The update could not be deserialized.
+    #[cfg(feature = "postcard")]
+    #[error(transparent)]
+    Postcard {
+        /// The deserialization error
+        source: postcard::Error,
+    },
+}
+
+/// Number of recent updates an [`Encoder`] accepts acknowledgements for, and
+/// a [`Decoder`] keeps the values of to apply deltas to.
+const ACK_WINDOW: usize = 8;
+
 /// Decoder for metrics received from an [`Encoder`]
 ///
 /// Implements [`MetricsSource`] to export the decoded metrics to OpenMetrics.
@@ -460,27 +915,136 @@ impl Item<'_> {
 pub struct Decoder {
     schema: Option<Schema>,
     values: Values,
+    /// Version of `schema`, if received from an encoder
+    schema_version: Option<u64>,
+    /// Sequence number of the last imported update
+    last_seq: Option<u64>,
+    /// Number of updates skipped between imported updates
+    missed: u64,
+    /// Sequence numbers and values of the last imported updates, oldest first
+    recent: VecDeque<(u64, Values)>,
+    /// Sequence number and values of the update the last delta was based on
+    base: Option<(u64, Values)>,
 }
 
 impl Decoder {
     /// Imports a metric update.
     ///
     /// Updates the decoder's schema (if provided) and values with the given update.
-    pub fn import(&mut self, update: Update) {
+    ///
+    /// Updates with a schema are always imported. Otherwise, the update must
+    /// have the schema version of the last imported schema, and a
+    /// [`delta`](Update::delta) update must be based on one of the last few
+    /// imported updates or on the same update as the previous delta, as it
+    /// is applied on top of its values. Updates in between may be lost, and
+    /// are counted in [`Decoder::missed_updates`].
+    ///
+    /// Updates of the same encoder must be imported in order: an update that
+    /// is not newer than the last imported one is rejected, as it would roll
+    /// the values back.
+    pub fn import(&mut self, update: Update) -> Result<(), DecodeError> {
+        if update.schema.is_none() && self.schema_version != Some(update.schema_version) {
+            return Err(e!(DecodeError::SchemaMismatch {
+                expected: self.schema_version,
+                actual: update.schema_version,
+            }));
+        }
+        // A restarted encoder starts with a new random schema version.
+        let same_encoder = self.schema_version.is_some_and(|version| {
+            update.schema_version == version || update.schema_version == version.wrapping_add(1)
+        });
+        let mut missed = 0;
+        if let Some(last) = self.last_seq.filter(|_| same_encoder) {
+            let ahead = update.seq.wrapping_sub(last) as i64;
+            if ahead <= 0 {
+                return Err(e!(DecodeError::OutOfOrder {
+                    last,
+                    actual: update.seq,
+                }));
+            }
+            missed = ahead as u64 - 1;
+        }
+        if let Some(delta) = &update.delta {
+            let base = delta.base;
+            if let Some(pos) = self.recent.iter().position(|(seq, _)| *seq == base) {
+                // Deltas are never based on older updates again.
+                self.base = self.recent.drain(..=pos).next_back();
+            } else if self.base.as_ref().is_none_or(|(seq, _)| *seq != base) {
+                return Err(e!(DecodeError::MissingBase { base }));
+            }
+        }
+
         if let Some(schema) = update.schema {
             self.schema = Some(schema);
+            self.schema_version = Some(update.schema_version);
+            self.recent.clear();
+            self.base = None;
+        }
+        match update.delta {
+            Some(delta) => {
+                let (_, base) = self.base.as_ref().expect("checked above");
+                self.values = base.clone();
+                delta.apply(&mut self.values);
+            }
+            None => self.values = update.values,
         }
-        self.values = update.values;
+        if self.recent.len() == ACK_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((update.seq, self.values.clone()));
+        self.last_seq = Some(update.seq);
+        self.missed += missed;
+        Ok(())
+    }
+
+    /// Returns the number of updates that were skipped between imported
+    /// updates, e.g. because they were lost in transit.
+    pub fn missed_updates(&self) -> u64 {
+        self.missed
+    }
+
+    /// Returns the sequence number of the last imported update.
+    ///
+    /// Pass it to [`Encoder::ack`] to base later delta updates on it.
+    pub fn last_seq(&self) -> Option<u64> {
+        self.last_seq
+    }
+
+    /// Imports metrics from OpenMetrics or Prometheus text, replacing the
+    /// current schema and values.
+    ///
+    /// Every label set of every parsed family becomes one item, grouped as
+    /// described in [`ParsedFamily::values`]. This lets scraped text be
+    /// re-served through [`MetricsSource`].
+    pub fn import_openmetrics(&mut self, text: &str) -> Result<(), ParseError> {
+        let families = parse_openmetrics(text)?;
+        let mut schema = Schema::default();
+        let mut values = Values::default();
+        let no_prefixes: &[&str] = &[];
+        for family in families {
+            let help = family.help.as_deref().unwrap_or_default();
+            for (labels, value) in family.values() {
+                let item = ItemSchema::new(&family.name, no_prefixes, &labels, value.r#type());
+                schema.push(item, help);
+                values.push(value, Vec::new());
+            }
+        }
+        self.schema = Some(schema);
+        self.values = values;
+        self.schema_version = None;
+        self.last_seq = None;
+        self.recent.clear();
+        self.base = None;
+        Ok(())
     }
 
     /// Imports a metric update from serialized bytes.
     ///
     /// Deserializes the bytes using postcard and imports the resulting update.
     #[cfg(feature = "postcard")]
-    pub fn import_bytes(&mut self, data: &[u8]) -> Result<(), postcard::Error> {
+    pub fn import_bytes(&mut self, data: &[u8]) -> Result<(), DecodeError> {
         let update = postcard::from_bytes(data)?;
-        self.import(update);
-        Ok(())
+        self.import(update)
     }
 
     /// Creates an iterator over the decoded metric items.
@@ -511,6 +1075,7 @@ impl<'a> Iterator for DecoderIter<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         let schema = self.inner.schema.as_ref()?.items.get(self.pos)?;
         let value = self.inner.values.items.get(self.pos)?;
+        let exemplars = self.inner.values.exemplars(self.pos);
         let help = self
             .inner
             .schema
@@ -522,6 +1087,7 @@ impl<'a> Iterator for DecoderIter<'a> {
         Some(Item {
             schema,
             value,
+            exemplars,
             help,
         })
     }
@@ -529,19 +1095,20 @@ impl<'a> Iterator for DecoderIter<'a> {
 
 impl MetricsSource for Decoder {
     fn encode_openmetrics(&self, writer: &mut impl std::fmt::Write) -> Result<(), crate::Error> {
-        // Family entries share name and type but appear as separate items in
-        // the schema. Emit `# HELP` / `# TYPE` only when the prefixed name
-        // changes between consecutive items, matching what the registry path
-        // produces.
-        let mut prev_key: Option<(Vec<String>, String)> = None;
+        // Family entries and sub-registry items share name and type but
+        // appear as separate items in the schema. Group them like the
+        // registry path does, so each name gets a single `# HELP` / `# TYPE`.
+        let mut samples = GroupedSamples::default();
         for item in self.iter() {
-            let key = (item.schema.prefixes.clone(), item.schema.name.clone());
-            if prev_key.as_ref() != Some(&key) {
-                item.encode_openmetrics_header(writer)?;
-                prev_key = Some(key);
-            }
-            item.encode_openmetrics_value(writer)?;
+            let buf = samples.get_or_insert(
+                &item.schema.prefixes,
+                &item.schema.name,
+                EncodableMetric::help(&item),
+                item.schema.r#type,
+            );
+            item.encode_openmetrics_value(buf)?;
         }
+        samples.encode_openmetrics(writer)?;
         encode_eof(writer)?;
         Ok(())
     }
@@ -561,8 +1128,19 @@ impl MetricsSource for Arc<RwLock<Decoder>> {
 pub struct Encoder {
     /// The metrics registry to encode from
     registry: RwLockRegistry,
-    /// Version of the last schema that was exported
-    last_schema_version: u64,
+    /// Registry schema version at the last exported schema
+    ///
+    /// `None` if the next export must include the schema.
+    last_schema_version: Option<u64>,
+    /// Version of the last exported schema, starting at a random value
+    schema_version: u64,
+    /// Sequence number of the next update
+    seq: u64,
+    /// Sequence numbers and values of the last exports, oldest first, if
+    /// sending deltas
+    recent: VecDeque<(u64, Values)>,
+    /// Sequence number and values of the last acknowledged export
+    acked_values: Option<(u64, Values)>,
     opts: EncoderOpts,
 }
 
@@ -572,11 +1150,22 @@ pub struct Encoder {
 pub struct EncoderOpts {
     /// Whether to include the metric help text in the transmitted schema.
     pub include_help: bool,
+    /// Whether to only send the values that changed since the last
+    /// acknowledged export.
+    ///
+    /// Once an update was acknowledged with [`Encoder::ack`], updates carry a
+    /// [`ValuesDelta`] against its values instead of the full values, except
+    /// for updates with a schema. Updates that are lost in between don't
+    /// affect the [`Decoder`].
+    pub delta: bool,
 }
 
 impl Default for EncoderOpts {
     fn default() -> Self {
-        Self { include_help: true }
+        Self {
+            include_help: true,
+            delta: false,
+        }
     }
 }
 
@@ -593,7 +1182,11 @@ impl Encoder {
     pub fn new_with_opts(registry: RwLockRegistry, opts: EncoderOpts) -> Self {
         Self {
             registry,
-            last_schema_version: 0,
+            last_schema_version: None,
+            schema_version: RandomState::new().hash_one(()),
+            seq: 0,
+            recent: VecDeque::new(),
+            acked_values: None,
             opts,
         }
     }
@@ -613,6 +1206,9 @@ impl Encoder {
     /// version would let the next round skip publishing while the values
     /// list has already grown, leaving the decoder one entry behind for
     /// every later item.
+    ///
+    /// Every update gets the next sequence number, and the version of the
+    /// schema its values are aligned to.
     pub fn export(&mut self) -> Update {
         let registry = self.registry.read().expect("poisoned");
         let last_seen = self.last_schema_version;
@@ -626,9 +1222,58 @@ impl Encoder {
         registry.encode_schema(Some(&mut schema), &mut values);
 
         let end_version = registry.schema_version();
-        self.last_schema_version = start_version;
-        let schema = (end_version != last_seen).then_some(schema);
-        Update { schema, values }
+        self.last_schema_version = Some(start_version);
+        let schema = (Some(end_version) != last_seen).then_some(schema);
+        if schema.is_some() {
+            self.schema_version = self.schema_version.wrapping_add(1);
+        }
+        let mut update = Update {
+            seq: self.seq,
+            schema_version: self.schema_version,
+            schema,
+            values,
+            delta: None,
+        };
+        self.seq = self.seq.wrapping_add(1);
+        if self.opts.delta {
+            // Updates with a schema carry full values, as the items may have moved.
+            if update.schema.is_some() {
+                self.recent.clear();
+                self.acked_values = None;
+            }
+            if self.recent.len() == ACK_WINDOW {
+                self.recent.pop_front();
+            }
+            self.recent.push_back((update.seq, update.values.clone()));
+            if let Some((base, acked_values)) = &self.acked_values {
+                let values = std::mem::take(&mut update.values);
+                update.delta = Some(ValuesDelta::between(*base, acked_values, &values));
+            }
+        }
+        update
+    }
+
+    /// Acknowledges that a [`Decoder`] imported the update with sequence number `seq`.
+    ///
+    /// With [`EncoderOpts::delta`] enabled, later updates only carry the
+    /// changes since that update. One of the last 8 exported updates can be
+    /// acknowledged, so acknowledgements may arrive a few exports late.
+    /// Acknowledgements of older updates, or of updates before the last
+    /// acknowledged one, are ignored.
+    pub fn ack(&mut self, seq: u64) {
+        if let Some(pos) = self.recent.iter().position(|(s, _)| *s == seq) {
+            self.acked_values = self.recent.drain(..=pos).next_back();
+        }
+    }
+
+    /// Includes the schema and all values in the next update.
+    ///
+    /// Call this when a [`Decoder`] failed to import an update, e.g. after
+    /// it was restarted, to get it back in sync.
+    pub fn reset(&mut self) {
+        self.last_schema_version = None;
+        self.recent.clear();
+        self.acked_values = None;
     }
 
     /// Exports the current state of the registry as serialized bytes.
@@ -666,9 +1311,10 @@ impl dyn MetricsGroup {
         }
     }
 
-    pub(crate) fn encode_openmetrics<'a>(
+    /// Adds the samples of all metrics in this group to `samples`.
+    pub(crate) fn collect_openmetrics<'a>(
         &self,
-        writer: &'a mut impl Write,
+        samples: &mut GroupedSamples,
         prefix: Option<&'a str>,
         labels: &[(Cow<'a, str>, Cow<'a, str>)],
     ) -> fmt::Result {
@@ -678,12 +1324,27 @@ impl dyn MetricsGroup {
         } else {
             &[name]
         };
+        let empty: &[(&str, &str)] = &[];
         for metric in self.iter() {
-            let labels = labels.iter().map(|(k, v)| (k.as_ref(), v.as_ref()));
-            metric.encode_openmetrics(writer, prefixes, labels)?;
+            let buf =
+                samples.get_or_insert(prefixes, metric.name(), metric.help(), metric.r#type());
+            encode_metric_value(
+                buf,
+                metric.name(),
+                prefixes,
+                labels,
+                empty,
+                &metric.value(),
+                &EncodableMetric::exemplars(&metric),
+            )?;
         }
         for family in IntoIterable::family_iter(self) {
-            family.encode_openmetrics(writer, prefixes, labels)?;
+            let mut buf = String::new();
+            if let Some(r#type) = family.encode_openmetrics_samples(&mut buf, prefixes, labels)? {
+                samples
+                    .get_or_insert(prefixes, family.name(), family.help(), r#type)
+                    .push_str(&buf);
+            }
         }
         Ok(())
     }
@@ -703,6 +1364,9 @@ pub(crate) trait EncodableMetric {
     /// Returns the current value of this item.
     fn value(&self) -> MetricValue;
 
+    /// Returns the exemplars of this item, see [`Metric::exemplars`](crate::Metric::exemplars).
+    fn exemplars(&self) -> Vec<Option<Exemplar>>;
+
     /// Encode the metrics item in the OpenMetrics text format.
     fn encode_openmetrics<'a>(
         &self,
@@ -710,16 +1374,7 @@ pub(crate) trait EncodableMetric {
         prefixes: &[impl AsRef<str>],
         labels: impl Iterator<Item = (&'a str, &'a str)> + 'a,
     ) -> fmt::Result {
-        writer.write_str("# HELP ")?;
-        encode_prefix_name(writer, prefixes, self.name())?;
-        writer.write_str(" ")?;
-        encode_help_text(writer, self.help())?;
-
-        writer.write_str("# TYPE ")?;
-        encode_prefix_name(writer, prefixes, self.name())?;
-        writer.write_str(" ")?;
-        writer.write_str(self.r#type().as_str())?;
-        writer.write_str("\n")?;
+        encode_header(writer, prefixes, self.name(), self.help(), self.r#type())?;
 
         let labels_vec: Vec<_> = labels.collect();
         let empty: &[(&str, &str)] = &[];
@@ -730,6 +1385,7 @@ pub(crate) trait EncodableMetric {
             &labels_vec,
             empty,
             &self.value(),
+            &self.exemplars(),
         )?;
         Ok(())
     }
@@ -749,17 +1405,96 @@ impl MetricItem<'_> {
     }
 
     fn encode_value(&self, values: &mut Values) {
-        values.items.push(self.value());
+        values.push(self.value(), self.exemplars());
     }
+}
 
-    pub(crate) fn encode_openmetrics<'a>(
-        &self,
-        writer: &mut impl Write,
+/// Samples in the OpenMetrics text format, grouped by metric name.
+///
+/// The same metric name can occur several times in a registry, e.g. when a
+/// group type is registered in multiple labeled sub-registries. OpenMetrics
+/// requires all samples of a metric family to follow a single `# HELP` /
+/// `# TYPE` header, so samples are buffered per name and written out in order
+/// of first appearance.
+#[derive(Debug, Default)]
+pub(crate) struct GroupedSamples {
+    families: Vec<SampleFamily>,
+    by_name: HashMap<String, usize>,
+}
+
+#[derive(Debug)]
+struct SampleFamily {
+    name: String,
+    help: String,
+    r#type: MetricType,
+    samples: String,
+}
+
+impl GroupedSamples {
+    /// Returns the sample buffer for the prefixed metric name.
+    ///
+    /// `help` and `type` are taken from the first call for each name.
+    pub(crate) fn get_or_insert(
+        &mut self,
         prefixes: &[impl AsRef<str>],
-        labels: impl Iterator<Item = (&'a str, &'a str)> + 'a,
-    ) -> fmt::Result {
-        EncodableMetric::encode_openmetrics(self, writer, prefixes, labels)
+        name: &str,
+        help: &str,
+        r#type: MetricType,
+    ) -> &mut String {
+        let mut full_name = String::new();
+        // Writing to a `String` cannot fail.
+        encode_prefix_name(&mut full_name, prefixes, name).expect("infallible");
+        let idx = match self.by_name.get(&full_name) {
+            Some(idx) => *idx,
+            None => {
+                self.by_name.insert(full_name.clone(), self.families.len());
+                self.families.push(SampleFamily {
+                    name: full_name,
+                    help: help.to_string(),
+                    r#type,
+                    samples: String::new(),
+                });
+                self.families.len() - 1
+            }
+        };
+        &mut self.families[idx].samples
     }
+
+    /// Writes all metric families, each with its header.
+    pub(crate) fn encode_openmetrics(&self, writer: &mut (impl Write + ?Sized)) -> fmt::Result {
+        let no_prefixes: &[&str] = &[];
+        for family in &self.families {
+            encode_header(
+                writer,
+                no_prefixes,
+                &family.name,
+                &family.help,
+                family.r#type,
+            )?;
+            writer.write_str(&family.samples)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the `# HELP` and `# TYPE` lines of a metric family.
+pub(crate) fn encode_header(
+    writer: &mut (impl Write + ?Sized),
+    prefixes: &[impl AsRef<str>],
+    name: &str,
+    help: &str,
+    r#type: MetricType,
+) -> fmt::Result {
+    writer.write_str("# HELP ")?;
+    encode_prefix_name(writer, prefixes, name)?;
+    writer.write_str(" ")?;
+    encode_help_text(writer, help)?;
+
+    writer.write_str("# TYPE ")?;
+    encode_prefix_name(writer, prefixes, name)?;
+    writer.write_str(" ")?;
+    writer.write_str(r#type.as_str())?;
+    writer.write_str("\n")
 }
 
 pub(crate) fn encode_u64(writer: &mut (impl Write + ?Sized), v: u64) -> fmt::Result {
@@ -773,8 +1508,12 @@ pub(crate) fn encode_i64(writer: &mut (impl Write + ?Sized), v: i64) -> fmt::Res
 }
 
 pub(crate) fn encode_f64(writer: &mut (impl Write + ?Sized), v: f64) -> fmt::Result {
-    writer.write_str(ryu::Buffer::new().format(v))?;
-    Ok(())
+    // ryu writes `inf`, OpenMetrics expects `+Inf`.
+    match v {
+        f64::INFINITY => writer.write_str("+Inf"),
+        f64::NEG_INFINITY => writer.write_str("-Inf"),
+        _ => writer.write_str(ryu::Buffer::new().format(v)),
+    }
 }
 
 pub(crate) fn encode_prefix_name(
@@ -0,0 +1,432 @@
+//! Decoding of updates from many encoders.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, btree_map, hash_map},
+    fmt::Display,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use super::{
+    DecodeError, Decoder, EncodableMetric, GroupedSamples, ItemSchema, Schema, Update, Values,
+    encode_eof, encode_metric_value,
+};
+use crate::{MetricValue, MetricsSource};
+
+/// Decoder for updates from many [`Encoder`](super::Encoder)s, like the
+/// nodes of a deployment.
+///
+/// Keeps one [`Decoder`] per source, keyed by `K`, e.g. a node id or an
+/// instance name. When encoded to OpenMetrics, the samples of every source
+/// carry a label with the source key, so the same metrics of different
+/// sources form a single family. If an item already has a label of that
+/// name, e.g. from [`Registry::sub_registry_with_label`](crate::Registry::sub_registry_with_label), it is renamed to
+/// `exported_<label>`, like Prometheus does for clashing target labels:
+///
+/// ```
+/// # use iroh_metrics::{MetricsSource, encoding::{AggregatingDecoder, Update}};
+/// let mut decoder = AggregatingDecoder::new("instance");
+/// # let update_a = Update::default();
+/// # let update_b = Update::default();
+/// // Errors are handled like for a single `Decoder`.
+/// # let _ =
+/// decoder.import("node-a", update_a);
+/// # let _ =
+/// decoder.import("node-b", update_b);
+/// let text = decoder.encode_openmetrics_to_string().unwrap();
+/// ```
+///
+/// [`AggregatingDecoder::rollup`] sums the metrics over all sources instead.
+/// Sources that stop sending are removed with [`AggregatingDecoder::evict_idle`].
+#[derive(Debug)]
+pub struct AggregatingDecoder<K> {
+    label: String,
+    sources: BTreeMap<K, Source>,
+}
+
+#[derive(Debug)]
+struct Source {
+    decoder: Decoder,
+    last_import: Instant,
+}
+
+impl<K: Ord> AggregatingDecoder<K> {
+    /// Creates a decoder that labels the samples of each source with `label`.
+    pub fn new(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            sources: BTreeMap::new(),
+        }
+    }
+
+    /// Imports an update from `source`.
+    ///
+    /// See [`Decoder::import`] for the errors. A new source is only added
+    /// if its first update could be imported.
+    pub fn import(&mut self, source: K, update: Update) -> Result<(), DecodeError> {
+        let last_import = Instant::now();
+        match self.sources.entry(source) {
+            btree_map::Entry::Occupied(entry) => {
+                let source = entry.into_mut();
+                source.decoder.import(update)?;
+                source.last_import = last_import;
+            }
+            btree_map::Entry::Vacant(entry) => {
+                let mut decoder = Decoder::default();
+                decoder.import(update)?;
+                entry.insert(Source {
+                    decoder,
+                    last_import,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Imports an update from `source` from serialized bytes.
+    #[cfg(feature = "postcard")]
+    pub fn import_bytes(&mut self, source: K, data: &[u8]) -> Result<(), DecodeError> {
+        let update = postcard::from_bytes(data)?;
+        self.import(source, update)
+    }
+
+    /// Returns the decoder of `source`.
+    pub fn get(&self, source: &K) -> Option<&Decoder> {
+        self.sources.get(source).map(|source| &source.decoder)
+    }
+
+    /// Iterates over the sources and their decoders, ordered by source.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Decoder)> {
+        self.sources
+            .iter()
+            .map(|(key, source)| (key, &source.decoder))
+    }
+
+    /// Removes `source`, returning its decoder.
+    pub fn remove(&mut self, source: &K) -> Option<Decoder> {
+        self.sources.remove(source).map(|source| source.decoder)
+    }
+
+    /// Removes the sources that haven't sent an update for at least `idle`.
+    ///
+    /// Returns the number of removed sources.
+    pub fn evict_idle(&mut self, idle: Duration) -> usize {
+        let before = self.sources.len();
+        self.sources
+            .retain(|_, source| source.last_import.elapsed() < idle);
+        before - self.sources.len()
+    }
+
+    /// Returns the number of sources.
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Returns whether there are no sources.
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Sums the metrics of all sources into a single [`Decoder`].
+    ///
+    /// Items with the same name, prefixes and labels are merged: counters
+    /// and gauges are summed, and the buckets of histograms are added up.
+    /// Items that can't be merged, like summaries or histograms with
+    /// different buckets, are left out. Exemplars are dropped.
+    ///
+    /// Summing is right for gauges that count things, like open connections,
+    /// but not for gauges like temperatures or ratios. Read those per source
+    /// with [`get`](Self::get) or [`iter`](Self::iter) instead.
+    pub fn rollup(&self) -> Decoder {
+        let mut items: Vec<(&ItemSchema, &str, Option<MetricValue>)> = Vec::new();
+        let mut index = HashMap::new();
+        for source in self.sources.values() {
+            for item in source.decoder.iter() {
+                let key = (
+                    &item.schema.prefixes,
+                    &item.schema.name,
+                    &item.schema.labels,
+                );
+                match index.entry(key) {
+                    hash_map::Entry::Vacant(entry) => {
+                        entry.insert(items.len());
+                        let help = item.help.map_or("", String::as_str);
+                        items.push((item.schema, help, Some(item.value.clone())));
+                    }
+                    hash_map::Entry::Occupied(entry) => {
+                        let value = &mut items[*entry.get()].2;
+                        if !value.as_mut().is_some_and(|acc| merge(acc, item.value)) {
+                            *value = None;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut schema = Schema::default();
+        let mut values = Values::default();
+        for (item, help, value) in items {
+            if let Some(value) = value {
+                schema.push(item.clone(), help);
+                values.push(value, Vec::new());
+            }
+        }
+        Decoder {
+            schema: Some(schema),
+            values,
+            schema_version: None,
+            last_seq: None,
+            missed: 0,
+            recent: Default::default(),
+            base: None,
+        }
+    }
+}
+
+/// Adds `value` to `acc`, returning `false` if they can't be merged.
+fn merge(acc: &mut MetricValue, value: &MetricValue) -> bool {
+    match (acc, value) {
+        (MetricValue::Counter(acc), MetricValue::Counter(value)) => {
+            *acc = acc.saturating_add(*value);
+        }
+        (MetricValue::Gauge(acc), MetricValue::Gauge(value)) => {
+            *acc = acc.saturating_add(*value);
+        }
+        (MetricValue::CounterF64(acc), MetricValue::CounterF64(value))
+        | (MetricValue::GaugeF64(acc), MetricValue::GaugeF64(value)) => {
+            *acc += value;
+        }
+        (
+            MetricValue::Histogram {
+                buckets: acc_buckets,
+                sum: acc_sum,
+                count: acc_count,
+            },
+            MetricValue::Histogram {
+                buckets,
+                sum,
+                count,
+            },
+        )
+        | (
+            MetricValue::GaugeHistogram {
+                buckets: acc_buckets,
+                sum: acc_sum,
+                count: acc_count,
+            },
+            MetricValue::GaugeHistogram {
+                buckets,
+                sum,
+                count,
+            },
+        ) => {
+            if acc_buckets.len() != buckets.len()
+                || acc_buckets.iter().zip(buckets).any(|(a, b)| a.0 != b.0)
+            {
+                return false;
+            }
+            for ((_, acc), (_, n)) in acc_buckets.iter_mut().zip(buckets) {
+                *acc = acc.saturating_add(*n);
+            }
+            *acc_sum += sum;
+            *acc_count = acc_count.saturating_add(*count);
+        }
+        (
+            MetricValue::ExponentialHistogram {
+                scale: acc_scale,
+                zero_threshold: acc_zero_threshold,
+                zero_count: acc_zero_count,
+                positive: acc_positive,
+                negative: acc_negative,
+                sum: acc_sum,
+                count: acc_count,
+            },
+            MetricValue::ExponentialHistogram {
+                scale,
+                zero_threshold,
+                zero_count,
+                positive,
+                negative,
+                sum,
+                count,
+            },
+        ) => {
+            if acc_scale != scale || acc_zero_threshold != zero_threshold {
+                return false;
+            }
+            *acc_zero_count = acc_zero_count.saturating_add(*zero_count);
+            merge_sparse_buckets(acc_positive, positive);
+            merge_sparse_buckets(acc_negative, negative);
+            *acc_sum += sum;
+            *acc_count = acc_count.saturating_add(*count);
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Renames the labels called `label` to `exported_<label>`.
+fn exported_labels<'a>(labels: &'a [(String, String)], label: &str) -> Cow<'a, [(String, String)]> {
+    if !labels.iter().any(|(key, _)| key == label) {
+        return Cow::Borrowed(labels);
+    }
+    labels
+        .iter()
+        .map(|(key, value)| {
+            let key = if key == label {
+                format!("exported_{key}")
+            } else {
+                key.clone()
+            };
+            (key, value.clone())
+        })
+        .collect()
+}
+
+/// Adds the ascending `(index, count)` pairs of `buckets` to `acc`.
+fn merge_sparse_buckets(acc: &mut Vec<(i32, u64)>, buckets: &[(i32, u64)]) {
+    for &(index, count) in buckets {
+        match acc.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(pos) => acc[pos].1 = acc[pos].1.saturating_add(count),
+            Err(pos) => acc.insert(pos, (index, count)),
+        }
+    }
+}
+
+impl<K: Ord + Display + Send + 'static> MetricsSource for AggregatingDecoder<K> {
+    fn encode_openmetrics(&self, writer: &mut impl std::fmt::Write) -> Result<(), crate::Error> {
+        let mut samples = GroupedSamples::default();
+        for (key, source) in &self.sources {
+            let source_label = [(self.label.as_str(), key.to_string())];
+            for item in source.decoder.iter() {
+                let labels = exported_labels(&item.schema.labels, &self.label);
+                let buf = samples.get_or_insert(
+                    &item.schema.prefixes,
+                    &item.schema.name,
+                    EncodableMetric::help(&item),
+                    item.schema.r#type,
+                );
+                encode_metric_value(
+                    buf,
+                    &item.schema.name,
+                    &item.schema.prefixes,
+                    &source_label,
+                    &labels,
+                    item.value,
+                    item.exemplars,
+                )?;
+            }
+        }
+        samples.encode_openmetrics(writer)?;
+        encode_eof(writer)?;
+        Ok(())
+    }
+}
+
+impl<K: Ord + Display + Send + Sync + 'static> MetricsSource
+    for Arc<RwLock<AggregatingDecoder<K>>>
+{
+    fn encode_openmetrics(&self, writer: &mut impl std::fmt::Write) -> Result<(), crate::Error> {
+        self.read().expect("poisoned").encode_openmetrics(writer)
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::{Counter, Gauge, Histogram, MetricsGroup, Registry, encoding::Encoder};
+
+    #[derive(Debug, MetricsGroup)]
+    #[metrics(default, name = "node")]
+    struct NodeMetrics {
+        /// Requests handled
+        requests: Counter,
+        /// Open connections
+        conns: Gauge,
+        /// Request latency
+        #[default(Histogram::new(vec![0.1, 1.0]))]
+        latency: Histogram,
+    }
+
+    fn node() -> (Arc<NodeMetrics>, Encoder) {
+        let metrics = Arc::new(NodeMetrics::default());
+        let mut registry = Registry::default();
+        registry.register(metrics.clone());
+        (metrics, Encoder::new(Arc::new(RwLock::new(registry))))
+    }
+
+    #[test]
+    fn test_aggregating_decoder() {
+        let (a, mut encoder_a) = node();
+        let (b, mut encoder_b) = node();
+        a.requests.inc_by(3);
+        a.conns.set(2);
+        a.latency.observe(0.05);
+        b.requests.inc_by(4);
+        b.conns.set(5);
+        b.latency.observe(0.5);
+
+        let mut decoder = AggregatingDecoder::new("instance");
+        decoder.import("b", encoder_b.export()).unwrap();
+        decoder.import("a", encoder_a.export()).unwrap();
+        assert_eq!(decoder.len(), 2);
+        assert!(Decoder::default().import(encoder_a.export()).is_err());
+        assert!(decoder.import("c", encoder_a.export()).is_err());
+        assert_eq!(decoder.len(), 2, "failed imports don't add sources");
+
+        let text = decoder.encode_openmetrics_to_string().unwrap();
+        assert_eq!(text.matches("# TYPE node_requests counter\n").count(), 1);
+        assert!(text.contains(
+            "node_requests_total{instance=\"a\"} 3\nnode_requests_total{instance=\"b\"} 4\n"
+        ));
+        assert!(text.contains("node_latency_bucket{instance=\"b\",le=\"1.0\"} 1\n"));
+
+        let rollup = decoder.rollup().encode_openmetrics_to_string().unwrap();
+        assert!(rollup.contains("node_requests_total 7\n"), "{rollup}");
+        assert!(rollup.contains("node_conns 7\n"));
+        assert!(rollup.contains("node_latency_bucket{le=\"0.1\"} 1\n"));
+        assert!(rollup.contains("node_latency_bucket{le=\"1.0\"} 2\n"));
+        assert!(rollup.contains("node_latency_count 2\n"));
+
+        // Histograms with different buckets can't be merged.
+        let metrics = Arc::new(NodeMetrics {
+            requests: Counter::new(),
+            conns: Gauge::new(),
+            latency: Histogram::new(vec![1.0]),
+        });
+        let mut registry = Registry::default();
+        registry.register(metrics);
+        let mut encoder = Encoder::new(Arc::new(RwLock::new(registry)));
+        decoder.import("c", encoder.export()).unwrap();
+        let rollup = decoder.rollup().encode_openmetrics_to_string().unwrap();
+        assert!(rollup.contains("node_requests_total 7\n"));
+        assert!(!rollup.contains("node_latency"));
+
+        assert!(decoder.remove(&"c").is_some());
+        assert_eq!(decoder.evict_idle(Duration::from_secs(60)), 0);
+        assert_eq!(decoder.evict_idle(Duration::ZERO), 2);
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn test_aggregating_decoder_label_clash() {
+        let metrics = Arc::new(NodeMetrics::default());
+        metrics.requests.inc();
+        let mut registry = Registry::default();
+        registry
+            .sub_registry_with_label("instance", "local")
+            .register(metrics);
+        let mut encoder = Encoder::new(Arc::new(RwLock::new(registry)));
+
+        let mut decoder = AggregatingDecoder::new("instance");
+        decoder.import("a", encoder.export()).unwrap();
+        let text = decoder.encode_openmetrics_to_string().unwrap();
+        assert!(
+            text.contains("node_requests_total{instance=\"a\",exported_instance=\"local\"} 1\n"),
+            "{text}"
+        );
+    }
+}
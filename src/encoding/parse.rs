@@ -0,0 +1,834 @@
+//! Parser for the [OpenMetrics text format] and the Prometheus text format.
+//!
+//! [OpenMetrics text format]: https://github.com/prometheus/OpenMetrics/blob/main/specification/OpenMetrics.md
+
+use std::collections::HashMap;
+
+use n0_error::e;
+
+use crate::{Exemplar, MetricType, MetricValue};
+
+/// Error returned when parsing OpenMetrics text fails.
+///
+/// Line and column are 1-based, columns count characters.
+#[n0_error::stack_error(derive, add_meta)]
+#[error("line {line}, column {column}: {message}")]
+pub struct ParseError {
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+impl ParseError {
+    /// Returns the line at which the error occurred.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the column at which the error occurred.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Returns a description of the error.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// A metric family parsed from OpenMetrics text.
+///
+/// Groups all samples that belong to one metric name, together with the
+/// metadata from the `# TYPE`, `# HELP` and `# UNIT` lines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedFamily {
+    /// The family name, without type-specific suffixes like `_total` or `_bucket`.
+    pub name: String,
+    /// The declared type, or `None` if the family is `unknown` or untyped.
+    pub r#type: Option<MetricType>,
+    /// The unescaped help text, if any.
+    pub help: Option<String>,
+    /// The unit, if any.
+    pub unit: Option<String>,
+    /// The samples of this family, in the order they appeared.
+    pub samples: Vec<ParsedSample>,
+}
+
+/// A single sample line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedSample {
+    /// The full sample name, including suffixes like `_total` or `_bucket`.
+    pub name: String,
+    /// The unescaped labels, in the order they appeared.
+    pub labels: Vec<(String, String)>,
+    /// The sample value.
+    pub value: f64,
+    /// The timestamp in seconds since the epoch, if any.
+    ///
+    /// Prometheus text timestamps are in milliseconds and converted to seconds.
+    pub timestamp: Option<f64>,
+    /// The exemplar attached to this sample, if any.
+    pub exemplar: Option<Exemplar>,
+}
+
+impl ParsedSample {
+    /// Returns the value of the label `key`, if present.
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Suffixes a sample name may carry in addition to the family name.
+const SUFFIXES: &[&str] = &[
+    "_total", "_created", "_bucket", "_count", "_sum", "_gcount", "_gsum", "_info",
+];
+
+impl ParsedFamily {
+    /// Groups the samples into one [`MetricValue`] per label set.
+    ///
+    /// Histogram and gauge histogram buckets, summary quantiles and the
+    /// states of a state set are collected into a single value, with the
+    /// `le`, `quantile` and state labels removed from the label set. The
+    /// labels of an info can't be told apart from those of its series, so
+    /// they stay in the label set, with an empty [`MetricValue::Info`].
+    ///
+    /// Counter values become [`MetricValue::Counter`] if they are integral
+    /// and [`MetricValue::CounterF64`] otherwise, and gauge and untyped values
+    /// likewise [`MetricValue::Gauge`] or [`MetricValue::GaugeF64`].
+    /// Timestamps, exemplars and `_created` samples are dropped.
+    pub fn values(&self) -> Vec<(Vec<(String, String)>, MetricValue)> {
+        let mut out: Vec<(Vec<(String, String)>, MetricValue)> = Vec::new();
+        for sample in &self.samples {
+            let suffix = sample.name.strip_prefix(self.name.as_str()).unwrap_or("");
+            if suffix == "_created" {
+                continue;
+            }
+            let bound_key = match self.r#type {
+                Some(MetricType::Histogram | MetricType::GaugeHistogram) => Some("le"),
+                Some(MetricType::Summary) => Some("quantile"),
+                Some(MetricType::StateSet) => Some(self.name.as_str()),
+                _ => None,
+            };
+            let labels: Vec<_> = sample
+                .labels
+                .iter()
+                .filter(|(k, _)| Some(k.as_str()) != bound_key)
+                .cloned()
+                .collect();
+            let pos = match out.iter().position(|(l, _)| *l == labels) {
+                Some(pos) => pos,
+                None => {
+                    let value = match self.r#type {
+                        Some(MetricType::Counter) => MetricValue::Counter(0),
+                        Some(MetricType::Histogram) => MetricValue::Histogram {
+                            buckets: Vec::new(),
+                            sum: 0.0,
+                            count: 0,
+                        },
+                        Some(MetricType::GaugeHistogram) => MetricValue::GaugeHistogram {
+                            buckets: Vec::new(),
+                            sum: 0.0,
+                            count: 0,
+                        },
+                        Some(MetricType::Summary) => MetricValue::Summary {
+                            quantiles: Vec::new(),
+                            sum: 0.0,
+                            count: 0,
+                        },
+                        Some(MetricType::Info) => MetricValue::Info(Vec::new()),
+                        Some(MetricType::StateSet) => MetricValue::StateSet(Vec::new()),
+                        _ => MetricValue::Gauge(0),
+                    };
+                    out.push((labels, value));
+                    out.len() - 1
+                }
+            };
+            let bound = bound_key
+                .and_then(|key| sample.label(key))
+                .and_then(|v| parse_f64(v).ok());
+            match (&mut out[pos].1, suffix, bound) {
+                (value @ (MetricValue::Counter(_) | MetricValue::CounterF64(_)), _, _) => {
+                    *value = counter_value(sample.value)
+                }
+                (value @ (MetricValue::Gauge(_) | MetricValue::GaugeF64(_)), _, _) => {
+                    *value = gauge_value(sample.value)
+                }
+                (
+                    MetricValue::Histogram { buckets, .. }
+                    | MetricValue::GaugeHistogram { buckets, .. },
+                    "_bucket",
+                    Some(le),
+                ) => buckets.push((le, sample.value as u64)),
+                (MetricValue::Summary { quantiles, .. }, "", Some(q)) => {
+                    quantiles.push((q, sample.value))
+                }
+                (MetricValue::StateSet(states), "", _) => {
+                    if let Some(state) = sample.label(&self.name) {
+                        states.push((state.to_string(), sample.value != 0.0));
+                    }
+                }
+                (
+                    MetricValue::Histogram { sum, .. } | MetricValue::Summary { sum, .. },
+                    "_sum",
+                    _,
+                ) => *sum = sample.value,
+                (
+                    MetricValue::Histogram { count, .. } | MetricValue::Summary { count, .. },
+                    "_count",
+                    _,
+                ) => *count = sample.value as u64,
+                (MetricValue::GaugeHistogram { sum, .. }, "_gsum", _) => *sum = sample.value,
+                (MetricValue::GaugeHistogram { count, .. }, "_gcount", _) => {
+                    *count = sample.value as u64
+                }
+                _ => {}
+            }
+        }
+        out
+    }
+}
+
+/// Returns a [`MetricValue::Counter`] for integral values that fit, or a
+/// [`MetricValue::CounterF64`].
+fn counter_value(value: f64) -> MetricValue {
+    if value.fract() == 0.0 && (0.0..=u64::MAX as f64).contains(&value) {
+        MetricValue::Counter(value as u64)
+    } else {
+        MetricValue::CounterF64(value)
+    }
+}
+
+/// Returns a [`MetricValue::Gauge`] for integral values that fit, or a
+/// [`MetricValue::GaugeF64`].
+fn gauge_value(value: f64) -> MetricValue {
+    if value.fract() == 0.0 && (i64::MIN as f64..=i64::MAX as f64).contains(&value) {
+        MetricValue::Gauge(value as i64)
+    } else {
+        MetricValue::GaugeF64(value)
+    }
+}
+
+/// Parses OpenMetrics or Prometheus text into metric families.
+///
+/// Accepts the OpenMetrics 1.0 text format as well as the more lenient
+/// Prometheus 0.0.4 text format: blank lines, arbitrary comments and a
+/// missing `# EOF` are tolerated. Samples are assigned to the family
+/// declared by a preceding `# TYPE` or `# HELP` line if their name matches it
+/// with an optional type suffix; otherwise they form a new untyped family.
+///
+/// Text without a `# EOF` line is taken to be Prometheus text, whose sample
+/// timestamps are in milliseconds rather than seconds. They are converted so
+/// that [`ParsedSample::timestamp`] is in seconds for both formats.
+///
+/// Returns an error with line and column for the first malformed line.
+pub fn parse_openmetrics(text: &str) -> Result<Vec<ParsedFamily>, ParseError> {
+    let mut families: Vec<ParsedFamily> = Vec::new();
+    let mut by_name: HashMap<String, usize> = HashMap::new();
+    let mut eof = false;
+
+    for (idx, line) in text.lines().enumerate() {
+        let mut cursor = Cursor {
+            line,
+            pos: 0,
+            line_no: idx + 1,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if eof {
+            return Err(cursor.error("unexpected content after `# EOF`"));
+        }
+        if line.starts_with('#') {
+            match parse_descriptor(&mut cursor)? {
+                Descriptor::Eof => eof = true,
+                Descriptor::Comment => {}
+                Descriptor::Meta { name, kind, value } => {
+                    let pos = *by_name.entry(name.to_string()).or_insert_with(|| {
+                        families.push(ParsedFamily::new(name));
+                        families.len() - 1
+                    });
+                    let family = &mut families[pos];
+                    match kind {
+                        "HELP" => family.help = Some(unescape(&value)),
+                        "UNIT" => family.unit = Some(value),
+                        "TYPE" => {
+                            let r#type = parse_type(&value)
+                                .ok_or_else(|| cursor.error(format!("unknown type `{value}`")))?;
+                            if family.r#type.is_some() && family.r#type != r#type {
+                                return Err(cursor.error(format!("conflicting type for `{name}`")));
+                            }
+                            family.r#type = r#type;
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+            }
+            continue;
+        }
+
+        let sample = parse_sample(&mut cursor)?;
+        let pos = match find_family(&families, &by_name, &sample.name) {
+            Some(pos) => pos,
+            None => {
+                families.push(ParsedFamily::new(&sample.name));
+                by_name.insert(sample.name.clone(), families.len() - 1);
+                families.len() - 1
+            }
+        };
+        families[pos].samples.push(sample);
+    }
+    if !eof {
+        for sample in families.iter_mut().flat_map(|family| &mut family.samples) {
+            if let Some(timestamp) = &mut sample.timestamp {
+                *timestamp /= 1000.0;
+            }
+        }
+    }
+    Ok(families)
+}
+
+impl ParsedFamily {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            r#type: None,
+            help: None,
+            unit: None,
+            samples: Vec::new(),
+        }
+    }
+}
+
+fn find_family(
+    families: &[ParsedFamily],
+    by_name: &HashMap<String, usize>,
+    sample_name: &str,
+) -> Option<usize> {
+    // Prefer the most recent family, which is where the sample most likely belongs.
+    let matches = |family: &ParsedFamily| {
+        sample_name == family.name
+            || sample_name
+                .strip_prefix(family.name.as_str())
+                .is_some_and(|suffix| SUFFIXES.contains(&suffix))
+    };
+    if families.last().is_some_and(matches) {
+        return Some(families.len() - 1);
+    }
+    if let Some(pos) = by_name.get(sample_name) {
+        return Some(*pos);
+    }
+    SUFFIXES
+        .iter()
+        .filter_map(|suffix| sample_name.strip_suffix(suffix))
+        .find_map(|name| by_name.get(name).copied())
+}
+
+fn parse_type(s: &str) -> Option<Option<MetricType>> {
+    match s {
+        "counter" => Some(Some(MetricType::Counter)),
+        "gauge" => Some(Some(MetricType::Gauge)),
+        "histogram" => Some(Some(MetricType::Histogram)),
+        "summary" => Some(Some(MetricType::Summary)),
+        "info" => Some(Some(MetricType::Info)),
+        "stateset" => Some(Some(MetricType::StateSet)),
+        "gaugehistogram" => Some(Some(MetricType::GaugeHistogram)),
+        "unknown" | "untyped" => Some(None),
+        _ => None,
+    }
+}
+
+enum Descriptor<'a> {
+    Eof,
+    Comment,
+    Meta {
+        name: &'a str,
+        kind: &'a str,
+        value: String,
+    },
+}
+
+fn parse_descriptor<'a>(cursor: &mut Cursor<'a>) -> Result<Descriptor<'a>, ParseError> {
+    let line = cursor.line;
+    if line.trim_end() == "# EOF" {
+        return Ok(Descriptor::Eof);
+    }
+    let Some(rest) = line.strip_prefix("# ") else {
+        return Ok(Descriptor::Comment);
+    };
+    let kind = match rest.split(' ').next() {
+        Some(kind @ ("HELP" | "TYPE" | "UNIT")) => kind,
+        _ => return Ok(Descriptor::Comment),
+    };
+    cursor.pos = 2 + kind.len();
+    cursor.expect_char(' ')?;
+    let name = cursor.metric_name()?;
+    let value = if cursor.at_end() {
+        String::new()
+    } else {
+        cursor.expect_char(' ')?;
+        cursor.rest().to_string()
+    };
+    if kind != "HELP" && value.trim().contains(' ') {
+        return Err(cursor.error(format!("unexpected whitespace in {kind} value")));
+    }
+    let value = match kind {
+        "HELP" => value,
+        _ => value.trim().to_string(),
+    };
+    Ok(Descriptor::Meta { name, kind, value })
+}
+
+fn parse_sample(cursor: &mut Cursor<'_>) -> Result<ParsedSample, ParseError> {
+    let name = cursor.metric_name()?.to_string();
+    let labels = if cursor.peek() == Some('{') {
+        cursor.label_set()?
+    } else {
+        Vec::new()
+    };
+    cursor.expect_spaces()?;
+    let value = cursor.number("value")?;
+
+    let mut timestamp = None;
+    let mut exemplar = None;
+    cursor.skip_spaces();
+    if !cursor.at_end() && cursor.peek() != Some('#') {
+        timestamp = Some(cursor.number("timestamp")?);
+        cursor.skip_spaces();
+    }
+    if cursor.peek() == Some('#') {
+        cursor.pos += 1;
+        cursor.expect_spaces()?;
+        if cursor.peek() != Some('{') {
+            return Err(cursor.error("expected `{` to start exemplar labels"));
+        }
+        let labels = cursor.label_set()?;
+        cursor.expect_spaces()?;
+        let value = cursor.number("exemplar value")?;
+        cursor.skip_spaces();
+        let timestamp = if cursor.at_end() {
+            None
+        } else {
+            Some(cursor.number("exemplar timestamp")?)
+        };
+        exemplar = Some(Exemplar {
+            labels,
+            value,
+            timestamp,
+        });
+        cursor.skip_spaces();
+    }
+    if !cursor.at_end() {
+        return Err(cursor.error("unexpected trailing content"));
+    }
+    Ok(ParsedSample {
+        name,
+        labels,
+        value,
+        timestamp,
+        exemplar,
+    })
+}
+
+/// Parses a float, accepting the OpenMetrics spellings of the special values.
+fn parse_f64(s: &str) -> Result<f64, std::num::ParseFloatError> {
+    match s {
+        "+Inf" | "Inf" => Ok(f64::INFINITY),
+        "-Inf" => Ok(f64::NEG_INFINITY),
+        "NaN" => Ok(f64::NAN),
+        _ => s.parse(),
+    }
+}
+
+/// Reverses the escaping of `\\`, `\n` and `\"`.
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Position within a single line, used to produce errors with line and column.
+struct Cursor<'a> {
+    line: &'a str,
+    /// Byte offset into `line`.
+    pos: usize,
+    line_no: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        e!(ParseError {
+            line: self.line_no,
+            column: self.line[..self.pos].chars().count() + 1,
+            message: message.into(),
+        })
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.line[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.line.len()
+    }
+
+    fn expect_char(&mut self, c: char) -> Result<(), ParseError> {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{c}`")))
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t']).len();
+    }
+
+    fn expect_spaces(&mut self) -> Result<(), ParseError> {
+        if !matches!(self.peek(), Some(' ' | '\t')) {
+            return Err(self.error("expected whitespace"));
+        }
+        self.skip_spaces();
+        Ok(())
+    }
+
+    /// Consumes a name of the form `[a-zA-Z_:][a-zA-Z0-9_:]*`, or without `:`
+    /// for label names.
+    fn name(&mut self, allow_colon: bool, what: &str) -> Result<&'a str, ParseError> {
+        let rest = self.rest();
+        let valid = |(i, c): &(usize, char)| {
+            c.is_ascii_alphabetic()
+                || *c == '_'
+                || (allow_colon && *c == ':')
+                || (*i > 0 && c.is_ascii_digit())
+        };
+        let len = rest
+            .char_indices()
+            .take_while(valid)
+            .map(|(_, c)| c.len_utf8())
+            .sum();
+        if len == 0 {
+            return Err(self.error(format!("expected {what}")));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    fn metric_name(&mut self) -> Result<&'a str, ParseError> {
+        self.name(true, "metric name")
+    }
+
+    /// Consumes a whitespace-delimited number.
+    fn number(&mut self, what: &str) -> Result<f64, ParseError> {
+        let rest = self.rest();
+        let token = rest.split([' ', '\t']).next().unwrap_or_default();
+        if token.is_empty() {
+            return Err(self.error(format!("expected {what}")));
+        }
+        let value =
+            parse_f64(token).map_err(|_| self.error(format!("invalid {what} `{token}`")))?;
+        self.pos += token.len();
+        Ok(value)
+    }
+
+    /// Consumes `{name="value",...}`, allowing a trailing comma.
+    fn label_set(&mut self) -> Result<Vec<(String, String)>, ParseError> {
+        self.expect_char('{')?;
+        let mut labels = Vec::new();
+        loop {
+            self.skip_spaces();
+            if self.peek() == Some('}') {
+                self.pos += 1;
+                return Ok(labels);
+            }
+            let name = self.name(false, "label name")?.to_string();
+            self.skip_spaces();
+            self.expect_char('=')?;
+            self.skip_spaces();
+            let value = self.label_value()?;
+            labels.push((name, value));
+            self.skip_spaces();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {}
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+
+    /// Consumes a quoted, escaped label value.
+    fn label_value(&mut self) -> Result<String, ParseError> {
+        self.expect_char('"')?;
+        let mut value = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(value);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c @ ('\\' | '"'))) => value.push(c),
+                    _ => {
+                        self.pos += i;
+                        return Err(self.error("invalid escape sequence"));
+                    }
+                },
+                c => value.push(c),
+            }
+        }
+        self.pos = self.line.len();
+        Err(self.error("unterminated label value"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_families() {
+        let text = r#"# HELP http_requests Requests "served".\n
+# TYPE http_requests counter
+http_requests_total{method="GET",path="/a\"b\\c"} 10 1700000000.5 # {trace_id="abc"} 1 1700000000
+http_requests_created{method="GET",path="/a\"b\\c"} 1600000000
+# TYPE latency histogram
+# UNIT latency seconds
+latency_bucket{le="0.5"} 3
+latency_bucket{le="+Inf"} 4
+latency_sum 2.5
+latency_count 4
+untyped_thing 7
+# EOF
+"#;
+        let families = parse_openmetrics(text).unwrap();
+        assert_eq!(families.len(), 3);
+
+        let requests = &families[0];
+        assert_eq!(requests.name, "http_requests");
+        assert_eq!(requests.r#type, Some(MetricType::Counter));
+        assert_eq!(requests.help.as_deref(), Some("Requests \"served\".\n"));
+        assert_eq!(requests.samples.len(), 2);
+        let sample = &requests.samples[0];
+        assert_eq!(sample.label("path"), Some("/a\"b\\c"));
+        assert_eq!(sample.timestamp, Some(1700000000.5));
+        let exemplar = sample.exemplar.as_ref().unwrap();
+        assert_eq!(exemplar.labels, vec![("trace_id".into(), "abc".into())]);
+        assert_eq!(exemplar.value, 1.0);
+        assert_eq!(exemplar.timestamp, Some(1700000000.0));
+        let labels = vec![
+            ("method".to_string(), "GET".to_string()),
+            ("path".to_string(), "/a\"b\\c".to_string()),
+        ];
+        assert_eq!(requests.values(), vec![(labels, MetricValue::Counter(10))]);
+
+        let latency = &families[1];
+        assert_eq!(latency.unit.as_deref(), Some("seconds"));
+        assert_eq!(
+            latency.values(),
+            vec![(
+                vec![],
+                MetricValue::Histogram {
+                    buckets: vec![(0.5, 3), (f64::INFINITY, 4)],
+                    sum: 2.5,
+                    count: 4,
+                }
+            )]
+        );
+
+        assert_eq!(families[2].name, "untyped_thing");
+        assert_eq!(families[2].r#type, None);
+    }
+
+    #[test]
+    fn parse_errors_have_positions() {
+        let err = parse_openmetrics("ok 1\nbad{x=\"y} 1\n").unwrap_err();
+        assert_eq!((err.line(), err.column()), (2, 12));
+        assert_eq!(err.message(), "unterminated label value");
+
+        let err = parse_openmetrics("foo 1.2.3\n").unwrap_err();
+        assert_eq!((err.line(), err.column()), (1, 5));
+
+        let err = parse_openmetrics("# TYPE foo bogus\n").unwrap_err();
+        assert_eq!(err.line(), 1);
+
+        let err = parse_openmetrics("foo 1\n# EOF\nfoo 2\n").unwrap_err();
+        assert_eq!(err.line(), 3);
+
+        let err = parse_openmetrics("foo{a=\"1\" b=\"2\"} 1\n").unwrap_err();
+        assert_eq!((err.line(), err.column()), (1, 11));
+    }
+
+    #[test]
+    fn prometheus_timestamps_are_milliseconds() {
+        let families = parse_openmetrics("foo 1 1700000000500\n").unwrap();
+        assert_eq!(families[0].samples[0].timestamp, Some(1700000000.5));
+
+        let families = parse_openmetrics("foo 1 1700000000.5\n# EOF\n").unwrap();
+        assert_eq!(families[0].samples[0].timestamp, Some(1700000000.5));
+    }
+
+    #[test]
+    fn fractional_values_round_trip() {
+        use crate::{MetricsSource, encoding::Decoder};
+
+        let text = "# HELP cpu_seconds CPU time.
+# TYPE cpu_seconds counter
+cpu_seconds_total 0.7
+# HELP requests Requests.
+# TYPE requests counter
+requests_total 3
+# HELP load Load.
+# TYPE load gauge
+load -1.5
+# HELP conns Connections.
+# TYPE conns gauge
+conns 2
+# EOF
+";
+        let families = parse_openmetrics(text).unwrap();
+        let values: Vec<_> = families
+            .iter()
+            .map(|family| family.values().remove(0).1)
+            .collect();
+        assert_eq!(
+            values,
+            [
+                MetricValue::CounterF64(0.7),
+                MetricValue::Counter(3),
+                MetricValue::GaugeF64(-1.5),
+                MetricValue::Gauge(2),
+            ]
+        );
+
+        let mut decoder = Decoder::default();
+        decoder.import_openmetrics(text).unwrap();
+        assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), text);
+    }
+
+    #[test]
+    fn info_values_keep_labels() {
+        use crate::{MetricsSource, encoding::Decoder};
+
+        let text = "# HELP build Build information.
+# TYPE build info
+build_info{instance=\"a\",version=\"1.0\"} 1
+build_info{instance=\"b\",version=\"1.1\"} 1
+# EOF
+";
+        let families = parse_openmetrics(text).unwrap();
+        let label = |instance: &str, version: &str| {
+            vec![
+                ("instance".to_string(), instance.to_string()),
+                ("version".to_string(), version.to_string()),
+            ]
+        };
+        assert_eq!(
+            families[0].values(),
+            [
+                (label("a", "1.0"), MetricValue::Info(Vec::new())),
+                (label("b", "1.1"), MetricValue::Info(Vec::new())),
+            ]
+        );
+
+        let mut decoder = Decoder::default();
+        decoder.import_openmetrics(text).unwrap();
+        assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), text);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn parse_prometheus_metrics_shim() {
+        let text = "# TYPE foo counter\nfoo_total{a=\"1\"} 3\nnot a sample\nbar 1.5\n";
+        let metrics = crate::parse_prometheus_metrics(text);
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics["foo_total{a=\"1\"}"], 3.0);
+        assert_eq!(metrics["bar"], 1.5);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn decoder_reserves_parsed_text() {
+        use std::sync::Arc;
+
+        use iroh_metrics_derive::MetricsGroup;
+
+        use crate::{
+            Counter, EncodeLabelSet, EncodeLabelValue, Family, Gauge, Histogram, Info,
+            MetricsSource, Registry, StateSet, Summary, encoding::Decoder,
+        };
+
+        #[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Debug, EncodeLabelSet)]
+        struct Path {
+            path: String,
+        }
+
+        #[derive(EncodeLabelValue)]
+        enum Mode {
+            Idle,
+            Serving,
+        }
+
+        #[derive(Debug, MetricsGroup)]
+        #[metrics(default, name = "http")]
+        struct Metrics {
+            /// Requests served
+            requests: Family<Path, Counter>,
+            /// Open connections
+            connections: Gauge,
+            /// Request latency
+            #[default(Histogram::new(vec![0.1, 1.0]))]
+            latency: Histogram,
+            /// Response sizes
+            #[default(Summary::new(vec![0.5]))]
+            sizes: Summary,
+            /// Never touched
+            idle: Counter,
+            /// Build information
+            #[default(Info::new([("version", "1.0")]))]
+            build: Info,
+            /// Server mode
+            mode: StateSet<Mode>,
+        }
+
+        let metrics = Arc::new(Metrics::default());
+        let path = |p: &str| Path { path: p.into() };
+        metrics.requests.get_or_create(&path("/a")).inc_by(3);
+        metrics.requests.get_or_create(&path("/b\"")).inc();
+        metrics.connections.set(-2);
+        metrics.latency.observe(0.5);
+        metrics.sizes.observe(512.0);
+        metrics.mode.set(Mode::Serving);
+        let mut registry = Registry::default();
+        registry
+            .sub_registry_with_label("node", "n1")
+            .register(metrics);
+
+        let text = registry.encode_openmetrics_to_string().unwrap();
+        assert!(text.contains("http_build_info{node=\"n1\",version=\"1.0\"} 1\n"));
+        assert!(text.contains("http_mode{node=\"n1\",http_mode=\"serving\"} 1\n"));
+        let mut decoder = Decoder::default();
+        decoder.import_openmetrics(&text).unwrap();
+        assert_eq!(decoder.encode_openmetrics_to_string().unwrap(), text);
+    }
+}
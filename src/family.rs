@@ -2,16 +2,22 @@
 //!
 //! A [`Family`] is a collection of metrics indexed by label sets. Labels
 //! should be low cardinality: each unique combination becomes a separate
-//! timeseries on the backend, and the internal map grows without bound.
+//! timeseries on the backend, and the internal map grows without bound
+//! unless a limit is set with [`Family::with_limit`]. Series for short-lived
+//! label values (connections, relays) can be expired with [`Family::with_ttl`]
+//! or [`Family::evict_idle`].
 
 #[cfg(feature = "metrics")]
 use std::collections::HashMap;
 #[cfg(feature = "metrics")]
 use std::sync::{OnceLock, RwLock};
+#[cfg(feature = "metrics")]
+use std::time::Instant;
 use std::{
     borrow::Cow,
     fmt::{self, Write},
     sync::Arc,
+    time::Duration,
 };
 
 use portable_atomic::AtomicU64;
@@ -22,20 +28,47 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 #[cfg(feature = "metrics")]
 use crate::MetricValue;
 #[cfg(feature = "metrics")]
-use crate::encoding::{ItemSchema, encode_help_text, encode_metric_value, encode_prefix_name};
+use crate::encoding::{ItemSchema, encode_metric_value};
 use crate::{
-    Metric,
-    encoding::{Schema, Values},
+    Metric, MetricType,
+    encoding::{Schema, Values, encode_header},
     labels::EncodeLabelSet,
 };
 
+mod sealed {
+    pub trait Sealed {}
+
+    use crate::{Metric, labels::EncodeLabelSet};
+
+    impl<L: EncodeLabelSet, M: Metric> Sealed for super::Family<L, M> {}
+}
+
 /// Type-erased encoding interface for a [`Family`].
 ///
 /// `Family<L, M>` is generic in both label and metric type. This trait
 /// collapses those generics so a metrics group containing multiple families
 /// can iterate them as `&dyn FamilyEncoder`.
-pub trait FamilyEncoder: Send + Sync + 'static {
+///
+/// This trait is sealed: it is implemented for [`Family`] only, and exists
+/// so that the [`MetricsGroup`](crate::MetricsGroup) derive can refer to
+/// families without naming their generics.
+pub trait FamilyEncoder: sealed::Sealed + Send + Sync + 'static {
+    /// Encodes the samples to OpenMetrics text format, without the `# HELP`
+    /// and `# TYPE` lines.
+    ///
+    /// Returns the metric type of the entries, or `None` if the family is
+    /// empty and nothing was written.
+    fn encode_openmetrics_samples(
+        &self,
+        writer: &mut dyn Write,
+        name: &str,
+        prefixes: &[&str],
+        registry_labels: &[(Cow<'_, str>, Cow<'_, str>)],
+    ) -> Result<Option<MetricType>, fmt::Error>;
+
     /// Encodes to OpenMetrics text format.
+    ///
+    /// Writes nothing if the family is empty.
     fn encode_openmetrics(
         &self,
         writer: &mut dyn Write,
@@ -43,7 +76,16 @@ pub trait FamilyEncoder: Send + Sync + 'static {
         help: &str,
         prefixes: &[&str],
         registry_labels: &[(Cow<'_, str>, Cow<'_, str>)],
-    ) -> fmt::Result;
+    ) -> fmt::Result {
+        let mut samples = String::new();
+        let Some(r#type) =
+            self.encode_openmetrics_samples(&mut samples, name, prefixes, registry_labels)?
+        else {
+            return Ok(());
+        };
+        encode_header(writer, prefixes, name, help, r#type)?;
+        writer.write_str(&samples)
+    }
 
     /// Encodes the binary export of this family.
     ///
@@ -66,8 +108,9 @@ pub trait FamilyEncoder: Send + Sync + 'static {
     /// Wires this family up to a registry's schema-version counter.
     ///
     /// Called by the registry when the parent metrics group is registered, so
-    /// that adding a new label combination (a new entry to the family) bumps
-    /// the version and re-publishes the schema on the next binary export.
+    /// that adding a new label combination (a new entry to the family) or
+    /// removing one bumps the version and re-publishes the schema on the next
+    /// binary export.
     fn attach_schema_version(&self, version: Arc<AtomicU64>);
 }
 
@@ -109,6 +152,20 @@ impl<'a> FamilyItem<'a> {
         self.family.is_empty()
     }
 
+    /// Encodes the samples to OpenMetrics text format, without the `# HELP`
+    /// and `# TYPE` lines.
+    ///
+    /// Returns the metric type, or `None` if the family is empty.
+    pub fn encode_openmetrics_samples(
+        &self,
+        writer: &mut dyn fmt::Write,
+        prefixes: &[&str],
+        registry_labels: &[(Cow<'_, str>, Cow<'_, str>)],
+    ) -> Result<Option<MetricType>, fmt::Error> {
+        self.family
+            .encode_openmetrics_samples(writer, self.name, prefixes, registry_labels)
+    }
+
     /// Encodes to OpenMetrics text format.
     pub fn encode_openmetrics(
         &self,
@@ -142,7 +199,8 @@ impl<'a> FamilyItem<'a> {
     ///
     /// Used by [`Registry::register`](crate::Registry::register) to wire
     /// every family in a metrics group up to the registry's version counter
-    /// so that adding a new label combination invalidates the cached schema.
+    /// so that adding or removing a label combination invalidates the cached
+    /// schema.
     pub fn attach_schema_version(&self, version: Arc<AtomicU64>) {
         self.family.attach_schema_version(version);
     }
@@ -152,7 +210,7 @@ impl<'a> FamilyItem<'a> {
 type Constructor<M> = Arc<dyn Fn() -> M + Send + Sync>;
 
 /// One entry in a [`Family`]: the metric plus the rendered label strings
-/// computed once at insert time.
+/// computed once at insert time, and when it was last looked up.
 #[cfg(feature = "metrics")]
 struct FamilyEntry<M> {
     metric: Arc<M>,
@@ -164,20 +222,95 @@ struct FamilyEntry<M> {
     /// export. Do not emit these directly to a wire format that requires
     /// escaping.
     encoded_labels: Vec<(&'static str, String)>,
+    /// Milliseconds since the family's epoch at which the entry was created,
+    /// or last looked up if the family has a TTL.
+    last_active: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+impl<M> FamilyEntry<M> {
+    fn new(metric: Arc<M>, encoded_labels: Vec<(&'static str, String)>, epoch: Instant) -> Self {
+        Self {
+            metric,
+            encoded_labels,
+            last_active: AtomicU64::new(millis_since(epoch)),
+        }
+    }
+
+    fn touch(&self, epoch: Instant) {
+        self.last_active
+            .fetch_max(millis_since(epoch), Ordering::Relaxed);
+    }
+
+    /// Returns true if the entry was not active for `idle` and nobody holds
+    /// on to its metric.
+    fn is_idle(&self, now: u64, idle: Duration) -> bool {
+        let last_active = self.last_active.load(Ordering::Relaxed);
+        Arc::strong_count(&self.metric) == 1
+            && now.saturating_sub(last_active) >= idle.as_millis() as u64
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn millis_since(epoch: Instant) -> u64 {
+    epoch.elapsed().as_millis() as u64
+}
+
+/// The label map of a [`Family`] plus its overflow series.
+#[cfg(feature = "metrics")]
+struct FamilyState<L, M> {
+    entries: HashMap<L, FamilyEntry<M>>,
+    /// Shared by all label sets rejected because the family is at its
+    /// series limit. Created on the first rejection.
+    overflow: Option<FamilyEntry<M>>,
+}
+
+#[cfg(feature = "metrics")]
+impl<L, M> Default for FamilyState<L, M> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            overflow: None,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<L: Ord, M> FamilyState<L, M> {
+    /// Returns all entries sorted by labels, followed by the overflow series.
+    fn sorted_entries(&self) -> Vec<&FamilyEntry<M>> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(a, _)| *a);
+        entries
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .chain(self.overflow.as_ref())
+            .collect()
+    }
 }
 
 /// A family of metrics indexed by labels.
 ///
 /// Thread-safe: multiple threads can look up or create metrics concurrently.
 /// Each metric is reference-counted so it can be used independently after lookup.
+///
+/// The number of label combinations can be capped with [`Family::with_limit`].
+/// Once the limit is reached, new label sets share a single overflow series
+/// labeled `otherwise="true"`.
 #[cfg(feature = "metrics")]
 pub struct Family<L, M>
 where
     L: EncodeLabelSet,
     M: Metric,
 {
-    inner: Arc<RwLock<HashMap<L, FamilyEntry<M>>>>,
+    inner: Arc<RwLock<FamilyState<L, M>>>,
     constructor: Constructor<M>,
+    limit: Option<usize>,
+    /// Number of lookups routed to the overflow series.
+    overflow_lookups: Arc<AtomicU64>,
+    ttl: Option<Duration>,
+    /// Reference point for [`FamilyEntry::last_active`].
+    epoch: Instant,
     // Set once when the parent group is registered. Bumped on each new label
     // combo so the binary encoder re-publishes the schema.
     //
@@ -200,11 +333,7 @@ where
 {
     /// Creates a new family using `M::default()` for new metrics.
     pub fn new() -> Self {
-        Self {
-            inner: Arc::new(RwLock::new(HashMap::new())),
-            constructor: Arc::new(M::default),
-            schema_version: Arc::new(OnceLock::new()),
-        }
+        Self::with_constructor(M::default)
     }
 }
 
@@ -228,44 +357,165 @@ where
     /// Creates a new family with a custom constructor (useful for Histogram buckets).
     pub fn with_constructor<F: Fn() -> M + Send + Sync + 'static>(constructor: F) -> Self {
         Self {
-            inner: Arc::new(RwLock::new(HashMap::new())),
+            inner: Default::default(),
             constructor: Arc::new(constructor),
+            limit: None,
+            overflow_lookups: Default::default(),
+            ttl: None,
+            epoch: Instant::now(),
             schema_version: Arc::new(OnceLock::new()),
         }
     }
 
+    /// Caps the number of label combinations tracked by this family.
+    ///
+    /// Once `limit` label sets exist, [`Family::get_or_create`] returns a
+    /// shared overflow metric for any new label set. The overflow series is
+    /// encoded with the single label `otherwise="true"`, and the number of
+    /// lookups routed to it is available from [`Family::overflow_lookups`].
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Returns the configured series limit, if any.
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Returns how many lookups were routed to the overflow series because
+    /// the family was at its limit.
+    ///
+    /// Every lookup of an untracked label set counts, so a label set that
+    /// is looked up repeatedly is counted repeatedly.
+    pub fn overflow_lookups(&self) -> u64 {
+        self.overflow_lookups.load(Ordering::Relaxed)
+    }
+
+    /// Expires series that have been idle for `ttl`.
+    ///
+    /// Idle entries are removed with [`Family::evict_idle`] each time the
+    /// family is encoded. See there for what counts as activity.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Returns the configured idle timeout, if any.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// Removes all series that have not been active for at least `idle`,
+    /// returning how many were removed.
+    ///
+    /// Series whose metric is still held through an `Arc<M>` returned by
+    /// [`Family::get_or_create`] or [`Family::get`] are never removed. Other
+    /// series are active when they are created and, if the family has a
+    /// [TTL](Family::with_ttl), when they are looked up. Without a TTL,
+    /// lookups are not tracked, so `idle` counts from the creation of the
+    /// series.
+    pub fn evict_idle(&self, idle: Duration) -> usize {
+        let now = millis_since(self.epoch);
+        let mut guard = self.inner.write().expect("poisoned");
+        let before = guard.entries.len();
+        guard.entries.retain(|_, entry| !entry.is_idle(now, idle));
+        let mut evicted = before - guard.entries.len();
+        if guard
+            .overflow
+            .as_ref()
+            .is_some_and(|entry| entry.is_idle(now, idle))
+        {
+            guard.overflow = None;
+            evicted += 1;
+        }
+        if evicted > 0 {
+            self.bump_schema_version();
+        }
+        evicted
+    }
+
+    fn evict_expired(&self) {
+        if let Some(ttl) = self.ttl {
+            self.evict_idle(ttl);
+        }
+    }
+
+    /// Marks `entry` as active, if the family tracks activity.
+    fn touch(&self, entry: &FamilyEntry<M>) {
+        if self.ttl.is_some() {
+            entry.touch(self.epoch);
+        }
+    }
+
     /// Gets or creates a metric for the given labels.
     ///
     /// Each call performs a `HashMap` lookup under a read lock. For hot paths
     /// where the label set is stable, hold on to the returned `Arc<M>` and
     /// reuse it instead of calling `get_or_create` on every record.
+    ///
+    /// If the family is at its [limit](Family::with_limit), returns the
+    /// overflow metric for label sets that are not tracked yet.
     pub fn get_or_create(&self, labels: &L) -> Arc<M> {
-        if let Some(entry) = self.inner.read().expect("poisoned").get(labels) {
-            return Arc::clone(&entry.metric);
+        {
+            let guard = self.inner.read().expect("poisoned");
+            if let Some(entry) = guard.entries.get(labels) {
+                self.touch(entry);
+                return Arc::clone(&entry.metric);
+            }
+            // Serve the overflow series without contending on the write lock.
+            if let Some(entry) = guard.overflow.as_ref().filter(|_| self.at_limit(&guard)) {
+                self.overflow_lookups.fetch_add(1, Ordering::Relaxed);
+                self.touch(entry);
+                return Arc::clone(&entry.metric);
+            }
         }
 
         let mut guard = self.inner.write().expect("poisoned");
-        if let Some(entry) = guard.get(labels) {
+        if let Some(entry) = guard.entries.get(labels) {
+            self.touch(entry);
             return Arc::clone(&entry.metric);
         }
 
+        if self.at_limit(&guard) {
+            self.overflow_lookups.fetch_add(1, Ordering::Relaxed);
+            if let Some(entry) = &guard.overflow {
+                self.touch(entry);
+                return Arc::clone(&entry.metric);
+            }
+            let metric = Arc::new((self.constructor)());
+            let encoded_labels = vec![("otherwise", "true".to_string())];
+            guard.overflow = Some(FamilyEntry::new(
+                Arc::clone(&metric),
+                encoded_labels,
+                self.epoch,
+            ));
+            self.bump_schema_version();
+            return metric;
+        }
+
         let metric = Arc::new((self.constructor)());
         let encoded_labels = labels
             .encode_label_pairs()
             .into_iter()
             .map(|(k, v)| (k, v.as_str().into_owned()))
             .collect();
-        guard.insert(
+        guard.entries.insert(
             labels.clone(),
-            FamilyEntry {
-                metric: Arc::clone(&metric),
-                encoded_labels,
-            },
+            FamilyEntry::new(Arc::clone(&metric), encoded_labels, self.epoch),
         );
+        self.bump_schema_version();
+        metric
+    }
+
+    fn at_limit(&self, state: &FamilyState<L, M>) -> bool {
+        self.limit.is_some_and(|limit| state.entries.len() >= limit)
+    }
+
+    fn bump_schema_version(&self) {
         if let Some(v) = self.schema_version.get() {
             v.fetch_add(1, Ordering::Relaxed);
         }
-        metric
     }
 
     /// Looks up an existing metric without creating one. Read-only fast path.
@@ -273,32 +523,47 @@ where
         self.inner
             .read()
             .expect("poisoned")
+            .entries
             .get(labels)
-            .map(|entry| Arc::clone(&entry.metric))
+            .map(|entry| {
+                self.touch(entry);
+                Arc::clone(&entry.metric)
+            })
     }
 
     /// Removes the metric for the given labels.
     pub fn remove(&self, labels: &L) -> Option<Arc<M>> {
-        self.inner
+        let entry = self
+            .inner
             .write()
             .expect("poisoned")
-            .remove(labels)
-            .map(|entry| entry.metric)
+            .entries
+            .remove(labels)?;
+        self.bump_schema_version();
+        Some(entry.metric)
     }
 
-    /// Removes all metrics.
+    /// Removes all metrics, including the overflow series.
     pub fn clear(&self) {
-        self.inner.write().expect("poisoned").clear();
+        let mut guard = self.inner.write().expect("poisoned");
+        if !guard.entries.is_empty() || guard.overflow.is_some() {
+            guard.entries.clear();
+            guard.overflow = None;
+            self.bump_schema_version();
+        }
     }
 
     /// Returns the number of label combinations tracked.
+    ///
+    /// The overflow series is not counted.
     pub fn len(&self) -> usize {
-        self.inner.read().expect("poisoned").len()
+        self.inner.read().expect("poisoned").entries.len()
     }
 
     /// Returns true if empty.
     pub fn is_empty(&self) -> bool {
-        self.inner.read().expect("poisoned").is_empty()
+        let guard = self.inner.read().expect("poisoned");
+        guard.entries.is_empty() && guard.overflow.is_none()
     }
 }
 
@@ -354,6 +619,38 @@ where
         }
     }
 
+    /// Caps the number of label combinations tracked by this family (no-op).
+    pub fn with_limit(self, limit: usize) -> Self {
+        let _ = limit;
+        self
+    }
+
+    /// Returns the configured series limit (always `None`).
+    pub fn limit(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns how many lookups were routed to the overflow series (always 0).
+    pub fn overflow_lookups(&self) -> u64 {
+        0
+    }
+
+    /// Expires series that have been idle for `ttl` (no-op).
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        let _ = ttl;
+        self
+    }
+
+    /// Returns the configured idle timeout (always `None`).
+    pub fn ttl(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Removes idle series (no-op, returns 0).
+    pub fn evict_idle(&self, _idle: Duration) -> usize {
+        0
+    }
+
     /// Gets or creates a metric for the given labels (returns default metric).
     pub fn get_or_create(&self, _labels: &L) -> Arc<M> {
         Arc::clone(&self.default_metric)
@@ -397,6 +694,10 @@ where
         Self {
             inner: Arc::clone(&self.inner),
             constructor: Arc::clone(&self.constructor),
+            limit: self.limit,
+            overflow_lookups: Arc::clone(&self.overflow_lookups),
+            ttl: self.ttl,
+            epoch: self.epoch,
             schema_version: Arc::clone(&self.schema_version),
         }
     }
@@ -408,34 +709,22 @@ where
     L: EncodeLabelSet + Ord,
     M: Metric + 'static,
 {
-    fn encode_openmetrics(
+    fn encode_openmetrics_samples(
         &self,
         writer: &mut dyn Write,
         name: &str,
-        help: &str,
         prefixes: &[&str],
         registry_labels: &[(Cow<'_, str>, Cow<'_, str>)],
-    ) -> fmt::Result {
+    ) -> Result<Option<MetricType>, fmt::Error> {
+        self.evict_expired();
         let guard = self.inner.read().expect("poisoned");
-        if guard.is_empty() {
-            return Ok(());
-        }
-
-        let mut entries: Vec<_> = guard.iter().collect();
-        entries.sort_by_key(|(a, _)| *a);
-
-        let metric_type = entries[0].1.metric.r#type();
-
-        writer.write_str("# HELP ")?;
-        encode_prefix_name(writer, prefixes, name)?;
-        writer.write_str(" ")?;
-        encode_help_text(writer, help)?;
-
-        writer.write_str("# TYPE ")?;
-        encode_prefix_name(writer, prefixes, name)?;
-        writeln!(writer, " {}", metric_type.as_str())?;
+        let entries = guard.sorted_entries();
+        let Some(first) = entries.first() else {
+            return Ok(None);
+        };
+        let metric_type = first.metric.r#type();
 
-        for (_labels, entry) in entries {
+        for entry in entries {
             encode_metric_value(
                 writer,
                 name,
@@ -443,9 +732,10 @@ where
                 registry_labels,
                 &entry.encoded_labels,
                 &entry.metric.value(),
+                &entry.metric.exemplars(),
             )?;
         }
-        Ok(())
+        Ok(Some(metric_type))
     }
 
     fn encode_schema(
@@ -457,13 +747,13 @@ where
         prefixes: &[&str],
         registry_labels: &[(Cow<'_, str>, Cow<'_, str>)],
     ) {
+        // Eviction bumps the schema version, so the encoder publishes the
+        // schema built below along with these values.
+        self.evict_expired();
         // Hold the read lock for the whole pass so the schema items and the
         // values stay aligned even when other threads call `get_or_create`.
         let guard = self.inner.read().expect("poisoned");
-        let mut entries: Vec<_> = guard.iter().collect();
-        entries.sort_by_key(|(a, _)| *a);
-
-        for (_labels, entry) in entries {
+        for entry in guard.sorted_entries() {
             if let Some(schema) = schema.as_deref_mut() {
                 let all_labels = registry_labels
                     .iter()
@@ -474,7 +764,7 @@ where
                     help,
                 );
             }
-            values.items.push(entry.metric.value());
+            values.push(entry.metric.value(), entry.metric.exemplars());
         }
     }
 
@@ -496,8 +786,11 @@ where
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let guard = self.inner.read().expect("poisoned");
         f.debug_struct("Family")
-            .field("len", &guard.len())
-            .field("labels", &guard.keys().collect::<Vec<_>>())
+            .field("len", &guard.entries.len())
+            .field("labels", &guard.entries.keys().collect::<Vec<_>>())
+            .field("limit", &self.limit)
+            .field("overflow_lookups", &self.overflow_lookups())
+            .field("ttl", &self.ttl)
             .finish()
     }
 }
@@ -511,8 +804,9 @@ where
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         use serde::ser::SerializeSeq;
 
+        // The overflow series has no label set of type `L` and is skipped.
         let guard = self.inner.read().expect("poisoned");
-        let mut entries: Vec<_> = guard.iter().collect();
+        let mut entries: Vec<_> = guard.entries.iter().collect();
         entries.sort_by_key(|(a, _)| *a);
 
         let mut seq = serializer.serialize_seq(Some(entries.len()))?;
@@ -542,12 +836,9 @@ where
                     .into_iter()
                     .map(|(k, v)| (k, v.as_str().into_owned()))
                     .collect();
-                guard.insert(
+                guard.entries.insert(
                     labels,
-                    FamilyEntry {
-                        metric,
-                        encoded_labels,
-                    },
+                    FamilyEntry::new(metric, encoded_labels, family.epoch),
                 );
             }
         }
@@ -575,15 +866,14 @@ where
     L: EncodeLabelSet + Ord,
     M: Metric + 'static,
 {
-    fn encode_openmetrics(
+    fn encode_openmetrics_samples(
         &self,
         _writer: &mut dyn Write,
         _name: &str,
-        _help: &str,
         _prefixes: &[&str],
         _registry_labels: &[(Cow<'_, str>, Cow<'_, str>)],
-    ) -> fmt::Result {
-        Ok(())
+    ) -> Result<Option<MetricType>, fmt::Error> {
+        Ok(None)
     }
 
     fn encode_schema(
@@ -743,6 +1033,114 @@ mod tests {
         assert_eq!(hist_family.get_or_create(&NoLabels).count(), 2);
     }
 
+    #[test]
+    fn test_limit_overflow() {
+        let family: Family<TestLabels, Counter> = Family::new().with_limit(2);
+        family.get_or_create(&labels("GET", 200)).inc();
+        family.get_or_create(&labels("GET", 404)).inc();
+        assert_eq!(family.overflow_lookups(), 0);
+
+        // New label sets beyond the limit share the overflow series.
+        family.get_or_create(&labels("POST", 200)).inc_by(3);
+        family.get_or_create(&labels("PUT", 200)).inc_by(4);
+        // Repeated lookups are counted repeatedly.
+        family.get_or_create(&labels("PUT", 200));
+        assert_eq!(family.len(), 2);
+        assert_eq!(family.overflow_lookups(), 3);
+        assert!(family.get(&labels("POST", 200)).is_none());
+
+        // Known label sets still resolve to their own series.
+        family.get_or_create(&labels("GET", 200)).inc();
+        assert_eq!(family.get_or_create(&labels("GET", 200)).get(), 2);
+
+        let mut out = String::new();
+        FamilyEncoder::encode_openmetrics(&family, &mut out, "requests", "", &["http"], &[])
+            .unwrap();
+        assert!(out.ends_with(
+            "http_requests_total{method=\"GET\",status=\"200\"} 2
+http_requests_total{method=\"GET\",status=\"404\"} 1
+http_requests_total{otherwise=\"true\"} 7
+"
+        ));
+
+        // Creating the overflow series changes the schema.
+        let version = Arc::new(AtomicU64::new(0));
+        let family: Family<TestLabels, Counter> = Family::new().with_limit(0);
+        family.attach_schema_version(version.clone());
+        family.get_or_create(&labels("GET", 200)).inc();
+        family.get_or_create(&labels("GET", 404)).inc();
+        assert_eq!(version.load(Ordering::Relaxed), 1);
+        assert!(!family.is_empty());
+
+        let mut schema = Schema::default();
+        let mut values = Values::default();
+        FamilyEncoder::encode_schema(
+            &family,
+            Some(&mut schema),
+            &mut values,
+            "requests",
+            "",
+            &[],
+            &[],
+        );
+        assert_eq!(schema.items.len(), 1);
+        assert_eq!(values.items, vec![crate::MetricValue::Counter(2)]);
+    }
+
+    #[test]
+    fn test_evict_idle() {
+        use std::time::Duration;
+
+        // Moves the clock of the family forward, as if `by` had passed.
+        fn advance<M: Metric>(family: &mut Family<TestLabels, M>, by: Duration) {
+            family.epoch = family.epoch.checked_sub(by).expect("uptime too short");
+        }
+
+        let version = Arc::new(AtomicU64::new(0));
+        let mut family: Family<TestLabels, Counter> =
+            Family::new().with_ttl(Duration::from_secs(3600));
+        family.attach_schema_version(version.clone());
+        family.get_or_create(&labels("GET", 200));
+        family.get_or_create(&labels("GET", 404));
+        let held = family.get_or_create(&labels("POST", 200));
+        assert_eq!(version.load(Ordering::Relaxed), 3);
+
+        advance(&mut family, Duration::from_secs(100));
+        // A lookup counts as activity, and a retained handle keeps its series.
+        family.get_or_create(&labels("GET", 200));
+
+        assert_eq!(family.evict_idle(Duration::from_secs(50)), 1);
+        assert!(family.get(&labels("GET", 404)).is_none());
+        assert_eq!(family.len(), 2);
+        assert_eq!(version.load(Ordering::Relaxed), 4);
+        assert_eq!(family.evict_idle(Duration::from_secs(50)), 0);
+        assert_eq!(version.load(Ordering::Relaxed), 4);
+
+        drop(held);
+        assert_eq!(family.evict_idle(Duration::from_secs(50)), 1);
+        assert!(family.get(&labels("POST", 200)).is_none());
+
+        // Without a TTL, lookups are not tracked.
+        let mut family: Family<TestLabels, Counter> = Family::new();
+        family.get_or_create(&labels("GET", 200));
+        advance(&mut family, Duration::from_secs(100));
+        family.get_or_create(&labels("GET", 200));
+        assert_eq!(family.evict_idle(Duration::from_secs(50)), 1);
+
+        // With a TTL, idle series are dropped when encoding.
+        let mut family: Family<TestLabels, Counter> =
+            Family::new().with_ttl(Duration::from_secs(50));
+        family.get_or_create(&labels("GET", 200));
+        let mut values = Values::default();
+        FamilyEncoder::encode_schema(&family, None, &mut values, "requests", "", &[], &[]);
+        assert_eq!(values.items.len(), 1);
+        advance(&mut family, Duration::from_secs(100));
+        let mut values = Values::default();
+        FamilyEncoder::encode_schema(&family, None, &mut values, "requests", "", &[], &[]);
+        assert!(values.items.is_empty());
+        assert!(family.is_empty());
+    }
+
     #[test]
     fn test_serde_roundtrip() {
         let family: Family<TestLabels, Counter> = Family::new();
@@ -881,7 +1279,19 @@ mod tests {
             }
         }
 
+        impl super::sealed::Sealed for TriggerFamily {}
+
         impl FamilyEncoder for TriggerFamily {
+            fn encode_openmetrics_samples(
+                &self,
+                writer: &mut dyn Write,
+                name: &str,
+                prefixes: &[&str],
+                registry_labels: &[(Cow<'_, str>, Cow<'_, str>)],
+            ) -> Result<Option<crate::MetricType>, fmt::Error> {
+                self.inner
+                    .encode_openmetrics_samples(writer, name, prefixes, registry_labels)
+            }
             fn encode_openmetrics(
                 &self,
                 writer: &mut dyn Write,
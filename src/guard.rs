@@ -0,0 +1,90 @@
+//! Guards that update a metric when dropped.
+
+use std::ops::Deref;
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+
+use crate::{Gauge, Histogram};
+
+/// Observes the seconds since its creation into a [`Histogram`] when dropped.
+///
+/// Created by [`Histogram::start_timer`], which borrows the histogram, or
+/// [`Histogram::start_timer_owned`], which keeps an [`Arc`](std::sync::Arc)
+/// to it. The latter suits the metrics of a [`Family`](crate::Family):
+///
+/// ```
+/// # use iroh_metrics::{EncodeLabelSet, Family, Histogram};
+/// #[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Debug, EncodeLabelSet)]
+/// struct Method {
+///     method: &'static str,
+/// }
+///
+/// let latency: Family<Method, Histogram> =
+///     Family::with_constructor(|| Histogram::new(vec![0.01, 0.1, 1.0]));
+/// {
+///     let _timer = latency
+///         .get_or_create(&Method { method: "get" })
+///         .start_timer_owned();
+///     // handle the request
+/// }
+/// ```
+#[derive(Debug)]
+#[must_use = "the elapsed time is observed when the timer is dropped"]
+pub struct HistogramTimer<H: Deref<Target = Histogram>> {
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    histogram: H,
+    /// Set to `None` once the timer is stopped.
+    #[cfg(feature = "metrics")]
+    start: Option<Instant>,
+}
+
+impl<H: Deref<Target = Histogram>> HistogramTimer<H> {
+    pub(crate) fn new(histogram: H) -> Self {
+        Self {
+            histogram,
+            #[cfg(feature = "metrics")]
+            start: Some(Instant::now()),
+        }
+    }
+
+    /// Stops the timer without observing anything.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_mut))]
+    pub fn stop_and_discard(mut self) {
+        #[cfg(feature = "metrics")]
+        {
+            self.start = None;
+        }
+    }
+}
+
+impl<H: Deref<Target = Histogram>> Drop for HistogramTimer<H> {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        if let Some(start) = self.start.take() {
+            self.histogram.observe(start.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// Decrements a [`Gauge`] when dropped, to track things in progress.
+///
+/// Created by [`Gauge::guard`] and [`Gauge::guard_owned`], which increment
+/// the gauge. The decrement also happens on early returns and panics.
+#[derive(Debug)]
+#[must_use = "the gauge is decremented when the guard is dropped"]
+pub struct GaugeGuard<G: Deref<Target = Gauge>> {
+    gauge: G,
+}
+
+impl<G: Deref<Target = Gauge>> GaugeGuard<G> {
+    pub(crate) fn new(gauge: G) -> Self {
+        gauge.inc();
+        Self { gauge }
+    }
+}
+
+impl<G: Deref<Target = Gauge>> Drop for GaugeGuard<G> {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
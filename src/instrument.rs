@@ -0,0 +1,449 @@
+//! Metrics for futures and streams.
+
+#[cfg(not(feature = "metrics"))]
+use std::marker::PhantomData;
+#[cfg(feature = "metrics")]
+use std::{fmt, ops::Deref, sync::Arc};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use pin_project_lite::pin_project;
+
+use crate::{Counter, EncodeLabelSet, Family, Gauge, Histogram};
+#[cfg(feature = "metrics")]
+use crate::{GaugeGuard, HistogramTimer};
+
+/// Extension trait to record metrics while a [`Future`] or [`Stream`] runs.
+///
+/// ```
+/// # use iroh_metrics::{Counter, Gauge, Histogram, InstrumentExt, Instruments};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let polls = Counter::new();
+/// let in_flight = Gauge::new();
+/// let latency = Histogram::new(vec![0.01, 0.1, 1.0]);
+///
+/// let instruments = Instruments::new()
+///     .polls(&polls)
+///     .in_flight(&in_flight)
+///     .latency(&latency);
+/// let answer = async { 42 }.with_metrics(instruments).await;
+/// assert_eq!(answer, 42);
+/// # }
+/// ```
+///
+/// Without the `metrics` feature, the wrapper only forwards to the inner
+/// future or stream.
+pub trait InstrumentExt: Sized {
+    /// Wraps `self` to record into the metrics set in `instruments`.
+    fn with_metrics(self, instruments: Instruments<'_>) -> Instrumented<'_, Self> {
+        Instrumented {
+            inner: self,
+            state: State::new(instruments),
+        }
+    }
+}
+
+impl<T> InstrumentExt for T {}
+
+/// The metrics recorded by an [`Instrumented`] future or stream.
+///
+/// All metrics are optional. The in-flight gauge and the latency timer start
+/// on the first poll. A future completes when it returns its output, a
+/// stream when it returns `None`. Dropping either before completion
+/// decrements the in-flight gauge but observes no latency.
+///
+/// The `*_family` variants use the metric for a label set of a [`Family`].
+#[derive(Debug, Clone, Default)]
+pub struct Instruments<'a> {
+    #[cfg(feature = "metrics")]
+    polls: Option<MetricRef<'a, Counter>>,
+    #[cfg(feature = "metrics")]
+    in_flight: Option<MetricRef<'a, Gauge>>,
+    #[cfg(feature = "metrics")]
+    latency: Option<MetricRef<'a, Histogram>>,
+    #[cfg(feature = "metrics")]
+    items: Option<MetricRef<'a, Counter>>,
+    #[cfg(not(feature = "metrics"))]
+    _metrics: PhantomData<&'a ()>,
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_mut))]
+impl<'a> Instruments<'a> {
+    /// Creates an empty set of metrics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts every poll in `counter`.
+    pub fn polls(mut self, counter: &'a Counter) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            self.polls = Some(MetricRef::Borrowed(counter));
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = counter;
+        self
+    }
+
+    /// Counts every poll in the counter for `labels`.
+    pub fn polls_family<L: EncodeLabelSet>(
+        mut self,
+        family: &Family<L, Counter>,
+        labels: &L,
+    ) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            self.polls = Some(MetricRef::Owned(family.get_or_create(labels)));
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = (family, labels);
+        self
+    }
+
+    /// Increments `gauge` while running, from the first poll until completion.
+    pub fn in_flight(mut self, gauge: &'a Gauge) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            self.in_flight = Some(MetricRef::Borrowed(gauge));
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = gauge;
+        self
+    }
+
+    /// Increments the gauge for `labels` while running.
+    pub fn in_flight_family<L: EncodeLabelSet>(
+        mut self,
+        family: &Family<L, Gauge>,
+        labels: &L,
+    ) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            self.in_flight = Some(MetricRef::Owned(family.get_or_create(labels)));
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = (family, labels);
+        self
+    }
+
+    /// Observes the seconds from the first poll until completion in `histogram`.
+    pub fn latency(mut self, histogram: &'a Histogram) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            self.latency = Some(MetricRef::Borrowed(histogram));
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = histogram;
+        self
+    }
+
+    /// Observes the seconds until completion in the histogram for `labels`.
+    pub fn latency_family<L: EncodeLabelSet>(
+        mut self,
+        family: &Family<L, Histogram>,
+        labels: &L,
+    ) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            self.latency = Some(MetricRef::Owned(family.get_or_create(labels)));
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = (family, labels);
+        self
+    }
+
+    /// Counts the items yielded by a stream in `counter`.
+    ///
+    /// Unused for futures.
+    pub fn items(mut self, counter: &'a Counter) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            self.items = Some(MetricRef::Borrowed(counter));
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = counter;
+        self
+    }
+
+    /// Counts the items yielded by a stream in the counter for `labels`.
+    pub fn items_family<L: EncodeLabelSet>(
+        mut self,
+        family: &Family<L, Counter>,
+        labels: &L,
+    ) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            self.items = Some(MetricRef::Owned(family.get_or_create(labels)));
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = (family, labels);
+        self
+    }
+}
+
+/// A metric that is either borrowed or taken from a [`Family`].
+#[cfg(feature = "metrics")]
+enum MetricRef<'a, M> {
+    Borrowed(&'a M),
+    Owned(Arc<M>),
+}
+
+#[cfg(feature = "metrics")]
+impl<M> Deref for MetricRef<'_, M> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        match self {
+            Self::Borrowed(metric) => metric,
+            Self::Owned(metric) => metric,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<M> Clone for MetricRef<'_, M> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Borrowed(metric) => Self::Borrowed(metric),
+            Self::Owned(metric) => Self::Owned(Arc::clone(metric)),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<M: fmt::Debug> fmt::Debug for MetricRef<'_, M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        M::fmt(self, f)
+    }
+}
+
+pin_project! {
+    /// A [`Future`] or [`Stream`] that records metrics, created by
+    /// [`InstrumentExt::with_metrics`].
+    #[derive(Debug)]
+    #[must_use = "futures and streams do nothing unless polled"]
+    pub struct Instrumented<'a, T> {
+        #[pin]
+        inner: T,
+        state: State<'a>,
+    }
+}
+
+/// The metrics of an [`Instrumented`], and what is running.
+#[derive(Debug)]
+struct State<'a> {
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    instruments: Instruments<'a>,
+    #[cfg(feature = "metrics")]
+    in_flight: Option<GaugeGuard<MetricRef<'a, Gauge>>>,
+    #[cfg(feature = "metrics")]
+    timer: Option<HistogramTimer<MetricRef<'a, Histogram>>>,
+}
+
+impl<'a> State<'a> {
+    fn new(instruments: Instruments<'a>) -> Self {
+        Self {
+            instruments,
+            #[cfg(feature = "metrics")]
+            in_flight: None,
+            #[cfg(feature = "metrics")]
+            timer: None,
+        }
+    }
+
+    /// Records a poll, starting the gauge guard and timer on the first one.
+    fn poll(&mut self) {
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(counter) = &self.instruments.polls {
+                counter.inc();
+            }
+            // Taken on the first poll, so that they never restart.
+            if let Some(gauge) = self.instruments.in_flight.take() {
+                self.in_flight = Some(GaugeGuard::new(gauge));
+            }
+            if let Some(histogram) = self.instruments.latency.take() {
+                self.timer = Some(HistogramTimer::new(histogram));
+            }
+        }
+    }
+
+    fn item(&self) {
+        #[cfg(feature = "metrics")]
+        if let Some(counter) = &self.instruments.items {
+            counter.inc();
+        }
+    }
+
+    /// Decrements the in-flight gauge and observes the latency.
+    fn finish(&mut self) {
+        #[cfg(feature = "metrics")]
+        {
+            self.in_flight = None;
+            self.timer = None;
+        }
+    }
+}
+
+impl Drop for State<'_> {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        if let Some(timer) = self.timer.take() {
+            timer.stop_and_discard();
+        }
+    }
+}
+
+impl<T: Future> Future for Instrumented<'_, T> {
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T::Output> {
+        let this = self.project();
+        this.state.poll();
+        let poll = this.inner.poll(cx);
+        if poll.is_ready() {
+            this.state.finish();
+        }
+        poll
+    }
+}
+
+impl<T: Stream> Stream for Instrumented<'_, T> {
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T::Item>> {
+        let this = self.project();
+        this.state.poll();
+        let poll = this.inner.poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(_)) => this.state.item(),
+            Poll::Ready(None) => this.state.finish(),
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use std::task::Waker;
+
+    use super::*;
+
+    /// Yields `0..n`, returning `Pending` before every item.
+    struct Countdown {
+        n: u32,
+        polled: bool,
+    }
+
+    impl Stream for Countdown {
+        type Item = u32;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u32>> {
+            if self.n == 0 {
+                return Poll::Ready(None);
+            }
+            if !self.polled {
+                self.polled = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.polled = false;
+            self.n -= 1;
+            Poll::Ready(Some(self.n))
+        }
+    }
+
+    #[test]
+    fn test_instrument_future() {
+        let polls = Counter::new();
+        let in_flight = Gauge::new();
+        let latency = Histogram::new(vec![60.0]);
+        let instruments = Instruments::new()
+            .polls(&polls)
+            .in_flight(&in_flight)
+            .latency(&latency);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let mut yielded = false;
+        let fut = std::future::poll_fn(|cx| {
+            if std::mem::replace(&mut yielded, true) {
+                Poll::Ready(7)
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        });
+        let mut fut = std::pin::pin!(fut.with_metrics(instruments.clone()));
+        assert_eq!(in_flight.get(), 0);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(in_flight.get(), 1);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(7));
+        assert_eq!(in_flight.get(), 0);
+        assert_eq!(polls.get(), 2);
+        assert_eq!(latency.count(), 1);
+
+        // A cancelled future leaves the in-flight gauge but observes no latency.
+        let mut fut = Box::pin(std::future::pending::<()>().with_metrics(instruments));
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(in_flight.get(), 1);
+        drop(fut);
+        assert_eq!(in_flight.get(), 0);
+        assert_eq!(polls.get(), 3);
+        assert_eq!(latency.count(), 1);
+    }
+
+    #[test]
+    fn test_instrument_stream() {
+        #[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Debug, EncodeLabelSet)]
+        struct Source {
+            source: &'static str,
+        }
+
+        let polls: Family<Source, Counter> = Family::default();
+        let in_flight: Family<Source, Gauge> = Family::default();
+        let latency: Family<Source, Histogram> =
+            Family::with_constructor(|| Histogram::new(vec![60.0]));
+        let items: Family<Source, Counter> = Family::default();
+        let labels = Source { source: "relay" };
+
+        let stream = Countdown {
+            n: 3,
+            polled: false,
+        }
+        .with_metrics(
+            Instruments::new()
+                .polls_family(&polls, &labels)
+                .in_flight_family(&in_flight, &labels)
+                .latency_family(&latency, &labels)
+                .items_family(&items, &labels),
+        );
+        let mut stream = std::pin::pin!(stream);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut out = Vec::new();
+        loop {
+            match stream.as_mut().poll_next(&mut cx) {
+                Poll::Ready(Some(item)) => {
+                    assert_eq!(in_flight.get_or_create(&labels).get(), 1);
+                    out.push(item);
+                }
+                Poll::Ready(None) => break,
+                Poll::Pending => {}
+            }
+        }
+        assert_eq!(out, [2, 1, 0]);
+        assert_eq!(polls.get_or_create(&labels).get(), 7);
+        assert_eq!(items.get_or_create(&labels).get(), 3);
+        assert_eq!(in_flight.get_or_create(&labels).get(), 0);
+        assert_eq!(latency.get_or_create(&labels).count(), 1);
+    }
+}
@@ -116,6 +116,18 @@ impl_encode_label_value_int!(
     u64 => Uint, u32 => Uint, u16 => Uint, u8 => Uint
 );
 
+/// An enum whose variants are the states of a [`StateSet`](crate::StateSet).
+///
+/// Derived along with `EncodeLabelValue` for enums with unit variants. The
+/// label value of a variant is the name of its state.
+pub trait EncodeStateSet: EncodeLabelValue + Sized + 'static {
+    /// All states, in declaration order.
+    const STATES: &'static [Self];
+
+    /// Returns the position of `self` in [`Self::STATES`].
+    fn state_index(&self) -> usize;
+}
+
 /// Trait for types that can be encoded as a set of labels.
 ///
 /// Implement this for label structs to use with [`Family`](crate::Family).
@@ -3,25 +3,43 @@
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
 #![cfg_attr(iroh_docsrs, feature(doc_auto_cfg))]
 
+#[cfg(feature = "instrument")]
+pub use self::instrument::{InstrumentExt, Instrumented, Instruments};
+#[cfg(feature = "process")]
+pub use self::process::ProcessMetrics;
+#[cfg(feature = "tokio")]
+pub use self::tokio_metrics::TokioMetrics;
 pub use self::{
     base::*,
+    collector::{CollectedMetric, Collector},
     family::{Family, FamilyEncoder, FamilyItem},
+    guard::{GaugeGuard, HistogramTimer},
     labels::*,
     metrics::*,
     registry::*,
 };
 
 mod base;
+mod collector;
 pub mod encoding;
 mod family;
+mod guard;
+#[cfg(feature = "instrument")]
+mod instrument;
 pub mod iterable;
 mod labels;
 mod metrics;
+#[cfg(feature = "process")]
+mod process;
+#[cfg(feature = "metrics")]
+mod quantile;
 mod registry;
 #[cfg(feature = "service")]
 pub mod service;
 #[cfg(feature = "static_core")]
 pub mod static_core;
+#[cfg(feature = "tokio")]
+mod tokio_metrics;
 
 /// Derives [`EncodeLabelSet`] for a struct.
 ///
@@ -113,22 +131,34 @@ pub enum Error {
 }
 
 /// Parses Prometheus metrics from a string.
+///
+/// Maps each sample, with its labels as in `name{key="value"}`, to its value.
+/// Lines that can't be parsed are skipped.
+#[deprecated(
+    since = "1.0.0-rc.0",
+    note = "use `encoding::parse_openmetrics`, which keeps types, help texts and labels"
+)]
 pub fn parse_prometheus_metrics(data: &str) -> HashMap<String, f64> {
     let mut metrics = HashMap::new();
+    // Parse line by line so that a malformed line doesn't hide the others.
     for line in data.lines() {
-        if line.starts_with('#') {
-            continue;
-        }
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() < 2 {
-            continue;
-        }
-        let metric = parts[0];
-        let value = parts[1].parse::<f64>();
-        if value.is_err() {
+        let Ok(families) = encoding::parse_openmetrics(line) else {
             continue;
+        };
+        for sample in families.into_iter().flat_map(|family| family.samples) {
+            let mut key = sample.name;
+            if !sample.labels.is_empty() {
+                let labels: Vec<_> = sample
+                    .labels
+                    .iter()
+                    .map(|(k, v)| format!("{k}=\"{v}\""))
+                    .collect();
+                key.push('{');
+                key.push_str(&labels.join(","));
+                key.push('}');
+            }
+            metrics.insert(key, sample.value);
         }
-        metrics.insert(metric.to_string(), value.unwrap());
     }
     metrics
 }
@@ -6,14 +6,24 @@
 //! If the `metrics` feature is disabled, all operations defined on these types are noops,
 //! and the structs don't collect actual data.
 
-use std::any::Any;
+use std::{any::Any, fmt, marker::PhantomData, sync::Arc, time::Duration};
+#[cfg(feature = "metrics")]
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, OnceLock, RwLock},
+    time::{Instant, SystemTime},
+};
 
 #[cfg(feature = "metrics")]
 use portable_atomic::{AtomicI64, AtomicU64, Ordering};
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "metrics")]
+use crate::quantile::QuantileStream;
+use crate::{EncodeStateSet, GaugeGuard, HistogramTimer};
+
 /// The types of metrics supported by this crate.
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 #[non_exhaustive]
 pub enum MetricType {
     /// A [`Counter`].
@@ -22,6 +32,18 @@ pub enum MetricType {
     Gauge,
     /// A [`Histogram`].
     Histogram,
+    /// A [`Summary`].
+    Summary,
+    /// An [`ExponentialHistogram`].
+    ///
+    /// Encoded as a classic `histogram` in the OpenMetrics text format.
+    ExponentialHistogram,
+    /// An [`Info`].
+    Info,
+    /// A [`StateSet`].
+    StateSet,
+    /// A [`GaugeHistogram`].
+    GaugeHistogram,
 }
 
 impl MetricType {
@@ -31,6 +53,11 @@ impl MetricType {
             MetricType::Counter => "counter",
             MetricType::Gauge => "gauge",
             MetricType::Histogram => "histogram",
+            MetricType::Summary => "summary",
+            MetricType::ExponentialHistogram => "histogram",
+            MetricType::Info => "info",
+            MetricType::StateSet => "stateset",
+            MetricType::GaugeHistogram => "gaugehistogram",
         }
     }
 }
@@ -52,6 +79,49 @@ pub enum MetricValue {
         /// Total count of observations
         count: u64,
     },
+    /// A [`Summary`] value with quantile estimates, sum, and count.
+    Summary {
+        /// Target quantiles and their estimated values
+        quantiles: Vec<(f64, f64)>,
+        /// Sum of all observed values
+        sum: f64,
+        /// Total count of observations
+        count: u64,
+    },
+    /// An [`ExponentialHistogram`] value with sparse buckets.
+    ExponentialHistogram {
+        /// Resolution of the buckets
+        scale: i8,
+        /// Absolute values up to this threshold are counted in the zero bucket
+        zero_threshold: f64,
+        /// Count of observations in the zero bucket
+        zero_count: u64,
+        /// Populated positive buckets as ascending `(index, count)` pairs
+        positive: Vec<(i32, u64)>,
+        /// Populated negative buckets as ascending `(index, count)` pairs
+        negative: Vec<(i32, u64)>,
+        /// Sum of all observed values
+        sum: f64,
+        /// Total count of observations
+        count: u64,
+    },
+    /// A [`CounterF64`] value.
+    CounterF64(f64),
+    /// A [`GaugeF64`] value.
+    GaugeF64(f64),
+    /// An [`Info`] value with its labels.
+    Info(Vec<(String, String)>),
+    /// A [`StateSet`] value with all states and whether they are active.
+    StateSet(Vec<(String, bool)>),
+    /// A [`GaugeHistogram`] value with buckets, sum, and count.
+    GaugeHistogram {
+        /// Bucket upper bounds and cumulative counts
+        buckets: Vec<(f64, u64)>,
+        /// Sum of all current values
+        sum: f64,
+        /// Count of all current values
+        count: u64,
+    },
 }
 
 impl MetricValue {
@@ -61,6 +131,15 @@ impl MetricValue {
             MetricValue::Counter(value) => *value as f32,
             MetricValue::Gauge(value) => *value as f32,
             MetricValue::Histogram { count, .. } => *count as f32,
+            MetricValue::Summary { count, .. } => *count as f32,
+            MetricValue::ExponentialHistogram { count, .. } => *count as f32,
+            MetricValue::CounterF64(value) => *value as f32,
+            MetricValue::GaugeF64(value) => *value as f32,
+            MetricValue::Info(_) => 1.0,
+            MetricValue::StateSet(states) => {
+                states.iter().filter(|(_, active)| *active).count() as f32
+            }
+            MetricValue::GaugeHistogram { count, .. } => *count as f32,
         }
     }
 
@@ -70,6 +149,13 @@ impl MetricValue {
             MetricValue::Counter(_) => MetricType::Counter,
             MetricValue::Gauge(_) => MetricType::Gauge,
             MetricValue::Histogram { .. } => MetricType::Histogram,
+            MetricValue::Summary { .. } => MetricType::Summary,
+            MetricValue::ExponentialHistogram { .. } => MetricType::ExponentialHistogram,
+            MetricValue::CounterF64(_) => MetricType::Counter,
+            MetricValue::GaugeF64(_) => MetricType::Gauge,
+            MetricValue::Info(_) => MetricType::Info,
+            MetricValue::StateSet(_) => MetricType::StateSet,
+            MetricValue::GaugeHistogram { .. } => MetricType::GaugeHistogram,
         }
     }
 }
@@ -92,6 +178,64 @@ impl Metric for MetricValue {
     }
 }
 
+/// An exemplar: a reference from a sample to data outside the metric set,
+/// typically a trace.
+///
+/// The OpenMetrics spec limits the combined length of the label names and
+/// values of an exemplar to 128 characters. Labels past the limit, starting
+/// with the first one that doesn't fit, are left out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exemplar {
+    /// The exemplar labels, e.g. `trace_id`.
+    pub labels: Vec<(String, String)>,
+    /// The observed value, or the increment for counters.
+    pub value: f64,
+    /// The time the exemplar was recorded, in seconds since the Unix epoch.
+    pub timestamp: Option<f64>,
+}
+
+impl Exemplar {
+    /// Creates an exemplar with the given labels and value, timestamped now.
+    pub fn new(
+        labels: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+        value: f64,
+    ) -> Self {
+        #[cfg(feature = "metrics")]
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs_f64());
+        #[cfg(not(feature = "metrics"))]
+        let timestamp = None;
+        let mut exemplar = Self {
+            labels: labels
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+            value,
+            timestamp,
+        };
+        let len = exemplar.limited_labels().len();
+        exemplar.labels.truncate(len);
+        exemplar
+    }
+
+    /// Returns the leading labels that fit into the length limit.
+    pub(crate) fn limited_labels(&self) -> &[(String, String)] {
+        const MAX_CHARS: usize = 128;
+        let mut chars = 0;
+        let len = self
+            .labels
+            .iter()
+            .take_while(|(k, v)| {
+                chars += k.chars().count() + v.chars().count();
+                chars <= MAX_CHARS
+            })
+            .count();
+        &self.labels[..len]
+    }
+}
+
 /// Trait for metric items.
 pub trait Metric: std::fmt::Debug + Send + Sync {
     /// Returns the type of this metric.
@@ -110,6 +254,16 @@ pub trait Metric: std::fmt::Debug + Send + Sync {
     /// ignore it.
     fn set_value(&self, value: MetricValue);
 
+    /// Returns the most recent exemplar of each sample of this metric.
+    ///
+    /// Indexed like the samples in the OpenMetrics encoding of the value: the
+    /// single sample of a counter, or one entry per bucket of a histogram.
+    /// May be shorter than the number of samples, or empty if the metric
+    /// does not support exemplars.
+    fn exemplars(&self) -> Vec<Option<Exemplar>> {
+        Vec::new()
+    }
+
     /// Casts this metric to [`Any`] for downcasting to concrete types.
     fn as_any(&self) -> &dyn Any;
 }
@@ -122,6 +276,10 @@ pub struct Counter {
     /// The counter value.
     #[cfg(feature = "metrics")]
     pub(crate) value: AtomicU64,
+    /// The most recent exemplar, allocated on first use.
+    #[cfg(feature = "metrics")]
+    #[serde(skip)]
+    pub(crate) exemplar: OnceLock<Box<Mutex<Exemplar>>>,
 }
 
 impl Metric for Counter {
@@ -139,6 +297,14 @@ impl Metric for Counter {
         }
     }
 
+    fn exemplars(&self) -> Vec<Option<Exemplar>> {
+        #[cfg(feature = "metrics")]
+        if let Some(exemplar) = self.exemplar.get() {
+            return vec![Some(exemplar.lock().expect("poisoned").clone())];
+        }
+        Vec::new()
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -173,6 +339,42 @@ impl Counter {
         }
     }
 
+    /// Increases the [`Counter`] by 1 and records an exemplar, returning the previous value.
+    ///
+    /// The exemplar replaces any previously recorded one.
+    pub fn inc_with_exemplar(
+        &self,
+        labels: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    ) -> u64 {
+        self.inc_by_with_exemplar(1, labels)
+    }
+
+    /// Increases the [`Counter`] by `u64` and records an exemplar, returning the previous value.
+    ///
+    /// The exemplar replaces any previously recorded one.
+    pub fn inc_by_with_exemplar(
+        &self,
+        v: u64,
+        labels: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    ) -> u64 {
+        #[cfg(feature = "metrics")]
+        {
+            let mut exemplar = Some(Exemplar::new(labels, v as f64));
+            let slot = self
+                .exemplar
+                .get_or_init(|| Box::new(Mutex::new(exemplar.take().expect("not taken yet"))));
+            if let Some(exemplar) = exemplar {
+                *slot.lock().expect("poisoned") = exemplar;
+            }
+            self.inc_by(v)
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = (v, labels.into_iter());
+            0
+        }
+    }
+
     /// Sets the [`Counter`] value, returning the previous value.
     ///
     /// Warning: this is not default behavior for a counter that should always be monotonically increasing.
@@ -222,6 +424,10 @@ pub struct Histogram {
     /// Total count of observations.
     #[cfg(feature = "metrics")]
     pub(crate) count: AtomicU64,
+    /// Most recent exemplar per bucket, allocated on first use.
+    #[cfg(feature = "metrics")]
+    #[serde(skip)]
+    pub(crate) exemplars: Mutex<Vec<Option<Exemplar>>>,
 }
 
 impl Histogram {
@@ -245,6 +451,7 @@ impl Histogram {
                 counts,
                 sum: AtomicU64::new(0.0_f64.to_bits()),
                 count: AtomicU64::new(0),
+                exemplars: Mutex::new(Vec::new()),
             }
         }
         #[cfg(not(feature = "metrics"))]
@@ -257,27 +464,73 @@ impl Histogram {
     /// Records a value in the histogram.
     pub fn observe(&self, value: f64) {
         #[cfg(feature = "metrics")]
+        self.observe_inner(value);
+        #[cfg(not(feature = "metrics"))]
         {
-            self.count.fetch_add(1, Ordering::Relaxed);
-
-            self.sum
-                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
-                    let current_sum = f64::from_bits(current);
-                    Some((current_sum + value).to_bits())
-                })
-                .ok();
+            let _ = value;
+        }
+    }
 
-            for (i, &upper_bound) in self.buckets.iter().enumerate() {
-                if value <= upper_bound {
-                    self.counts[i].fetch_add(1, Ordering::Relaxed);
-                    break;
-                }
+    /// Records a value in the histogram together with an exemplar.
+    ///
+    /// The exemplar replaces any previously recorded one for the bucket the
+    /// value falls into.
+    pub fn observe_with_exemplar(
+        &self,
+        value: f64,
+        labels: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    ) {
+        #[cfg(feature = "metrics")]
+        if let Some(bucket) = self.observe_inner(value) {
+            let mut exemplars = self.exemplars.lock().expect("poisoned");
+            if exemplars.is_empty() {
+                exemplars.resize(self.buckets.len(), None);
             }
+            exemplars[bucket] = Some(Exemplar::new(labels, value));
         }
         #[cfg(not(feature = "metrics"))]
         {
-            let _ = value;
+            let _ = (value, labels.into_iter());
+        }
+    }
+
+    /// Starts a timer that observes the elapsed seconds when dropped.
+    ///
+    /// Use [`HistogramTimer::stop_and_discard`] to drop it without observing,
+    /// e.g. for failed requests.
+    pub fn start_timer(&self) -> HistogramTimer<&Self> {
+        HistogramTimer::new(self)
+    }
+
+    /// Like [`Self::start_timer`], but the timer keeps the histogram alive.
+    ///
+    /// Useful with the `Arc` returned by [`Family::get_or_create`], which
+    /// [`Self::start_timer`] could only borrow as a temporary.
+    ///
+    /// [`Family::get_or_create`]: crate::Family::get_or_create
+    pub fn start_timer_owned(self: Arc<Self>) -> HistogramTimer<Arc<Self>> {
+        HistogramTimer::new(self)
+    }
+
+    /// Records a value, returning the index of the bucket it fell into.
+    #[cfg(feature = "metrics")]
+    fn observe_inner(&self, value: f64) -> Option<usize> {
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        self.sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                let current_sum = f64::from_bits(current);
+                Some((current_sum + value).to_bits())
+            })
+            .ok();
+
+        for (i, &upper_bound) in self.buckets.iter().enumerate() {
+            if value <= upper_bound {
+                self.counts[i].fetch_add(1, Ordering::Relaxed);
+                return Some(i);
+            }
         }
+        None
     }
 
     /// Returns the total count of observations.
@@ -401,97 +654,934 @@ impl Metric for Histogram {
         }
     }
 
+    fn exemplars(&self) -> Vec<Option<Exemplar>> {
+        #[cfg(feature = "metrics")]
+        {
+            self.exemplars.lock().expect("poisoned").clone()
+        }
+        #[cfg(not(feature = "metrics"))]
+        Vec::new()
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
 }
 
-impl Metric for Gauge {
-    fn r#type(&self) -> MetricType {
-        MetricType::Gauge
-    }
+/// OpenMetrics `GaugeHistogram` to track the current distribution of values.
+///
+/// Unlike a [`Histogram`], values can be removed again with
+/// [`GaugeHistogram::unobserve`], e.g. when an item leaves a queue. Encoded
+/// with `_bucket`, `_gsum` and `_gcount` samples.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GaugeHistogram {
+    /// Bucket upper bounds.
+    #[cfg(feature = "metrics")]
+    pub(crate) buckets: Vec<f64>,
+    /// Individual counts for each bucket.
+    #[cfg(feature = "metrics")]
+    pub(crate) counts: Vec<AtomicU64>,
+    /// Sum of all current values (stored as bits for atomic operations).
+    #[cfg(feature = "metrics")]
+    pub(crate) sum: AtomicU64,
+}
 
-    fn value(&self) -> MetricValue {
-        MetricValue::Gauge(self.get())
+impl GaugeHistogram {
+    /// Constructs a new gauge histogram with the given bucket boundaries.
+    ///
+    /// Like for [`Histogram::new`], an infinity bucket is added if not present.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_mut))]
+    pub fn new(mut buckets: Vec<f64>) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            if buckets.last().is_none_or(|b| !b.is_infinite()) {
+                buckets.push(f64::INFINITY);
+            }
+            let counts = buckets.iter().map(|_| AtomicU64::new(0)).collect();
+            Self {
+                buckets,
+                counts,
+                sum: AtomicU64::new(0.0_f64.to_bits()),
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = buckets;
+            Self {}
+        }
     }
 
-    fn set_value(&self, value: MetricValue) {
-        if let MetricValue::Gauge(v) = value {
-            self.set(v);
+    /// Adds a value.
+    pub fn observe(&self, value: f64) {
+        #[cfg(feature = "metrics")]
+        if let Some(i) = self.bucket(value) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+            f64_fetch_add(&self.sum, value);
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = value;
         }
     }
 
-    fn as_any(&self) -> &dyn Any {
-        self
+    /// Removes a previously observed value.
+    ///
+    /// Does nothing if the bucket of `value` is empty.
+    pub fn unobserve(&self, value: f64) {
+        #[cfg(feature = "metrics")]
+        if let Some(i) = self.bucket(value) {
+            let removed = self.counts[i]
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| c.checked_sub(1))
+                .is_ok();
+            if removed {
+                f64_fetch_add(&self.sum, -value);
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = value;
+        }
     }
-}
 
-impl Gauge {
-    /// Constructs a new gauge, based on the given `help`.
-    pub fn new() -> Self {
-        Self::default()
+    #[cfg(feature = "metrics")]
+    fn bucket(&self, value: f64) -> Option<usize> {
+        self.buckets.iter().position(|&bound| value <= bound)
     }
 
-    /// Increases the [`Gauge`] by 1, returning the previous value.
-    pub fn inc(&self) -> i64 {
+    /// Returns the number of current values.
+    pub fn count(&self) -> u64 {
         #[cfg(feature = "metrics")]
         {
-            self.value.fetch_add(1, Ordering::Relaxed)
+            self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
         }
         #[cfg(not(feature = "metrics"))]
         0
     }
 
-    /// Increases the [`Gauge`] by `i64`, returning the previous value.
-    pub fn inc_by(&self, v: i64) -> i64 {
+    /// Returns the sum of all current values.
+    pub fn sum(&self) -> f64 {
         #[cfg(feature = "metrics")]
         {
-            self.value.fetch_add(v, Ordering::Relaxed)
+            f64::from_bits(self.sum.load(Ordering::Relaxed))
         }
         #[cfg(not(feature = "metrics"))]
-        {
-            let _ = v;
-            0
-        }
+        0.0
     }
 
-    /// Decreases the [`Gauge`] by 1, returning the previous value.
-    pub fn dec(&self) -> i64 {
+    /// Returns the bucket counts as a vector of (upper_bound, cumulative_count) pairs.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
         #[cfg(feature = "metrics")]
         {
-            self.value.fetch_sub(1, Ordering::Relaxed)
+            let mut cumulative = 0u64;
+            self.buckets
+                .iter()
+                .zip(self.counts.iter())
+                .map(|(&bound, count)| {
+                    cumulative += count.load(Ordering::Relaxed);
+                    (bound, cumulative)
+                })
+                .collect()
         }
         #[cfg(not(feature = "metrics"))]
-        0
+        Vec::new()
     }
+}
 
-    /// Decreases the [`Gauge`] by `i64`, returning the previous value.
-    pub fn dec_by(&self, v: i64) -> i64 {
+impl Metric for GaugeHistogram {
+    fn r#type(&self) -> MetricType {
+        MetricType::GaugeHistogram
+    }
+
+    fn value(&self) -> MetricValue {
+        let buckets = self.buckets();
+        MetricValue::GaugeHistogram {
+            count: buckets.last().map_or(0, |(_, count)| *count),
+            buckets,
+            sum: self.sum(),
+        }
+    }
+
+    fn set_value(&self, value: MetricValue) {
         #[cfg(feature = "metrics")]
-        {
-            self.value.fetch_sub(v, Ordering::Relaxed)
+        if let MetricValue::GaugeHistogram { buckets, sum, .. } = value {
+            let matches =
+                buckets.len() == self.buckets.len() && buckets.windows(2).all(|w| w[0].1 <= w[1].1);
+            if !matches {
+                return;
+            }
+            self.sum.store(sum.to_bits(), Ordering::Relaxed);
+            let mut prev = 0;
+            for ((_, cumulative), count) in buckets.iter().zip(&self.counts) {
+                count.store(cumulative - prev, Ordering::Relaxed);
+                prev = *cumulative;
+            }
         }
         #[cfg(not(feature = "metrics"))]
         {
-            let _ = v;
-            0
+            let _ = value;
         }
     }
 
-    /// Sets the [`Gauge`] to `v`, returning the previous value.
-    pub fn set(&self, v: i64) -> i64 {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Exponential histogram with sparse, automatically created buckets.
+///
+/// Follows the base-2 exponential bucketing of OpenTelemetry and Prometheus
+/// native histograms: at scale `s`, bucket `i` covers the range
+/// `(base^i, base^(i + 1)]` with `base = 2^(2^-s)`. Higher scales mean finer
+/// buckets. Only buckets that received observations are stored.
+///
+/// If more than `max_buckets` buckets are in use, the scale is decreased
+/// (halving the resolution) until the populated buckets fit again.
+///
+/// The OpenMetrics text format has no native representation for exponential
+/// histograms, so the text encoder downgrades them to a classic histogram:
+/// see [`ExponentialHistogram::buckets`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExponentialHistogram {
+    /// Absolute values up to this threshold are counted in the zero bucket.
+    #[cfg(feature = "metrics")]
+    pub(crate) zero_threshold: f64,
+    /// Upper limit for the number of populated buckets.
+    #[cfg(feature = "metrics")]
+    pub(crate) max_buckets: usize,
+    /// Count of observations in the zero bucket.
+    #[cfg(feature = "metrics")]
+    pub(crate) zero_count: AtomicU64,
+    /// Sum of all observed values (stored as bits for atomic operations).
+    #[cfg(feature = "metrics")]
+    pub(crate) sum: AtomicU64,
+    /// Total count of observations.
+    #[cfg(feature = "metrics")]
+    pub(crate) count: AtomicU64,
+    /// Current scale and the populated buckets.
+    #[cfg(feature = "metrics")]
+    buckets: RwLock<ExponentialBuckets>,
+}
+
+/// Sparse buckets of an [`ExponentialHistogram`], keyed by bucket index.
+///
+/// Kept behind one lock with the scale, because a scale change re-keys all
+/// buckets.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Serialize, Deserialize)]
+struct ExponentialBuckets {
+    scale: i8,
+    positive: BTreeMap<i32, AtomicU64>,
+    negative: BTreeMap<i32, AtomicU64>,
+}
+
+#[cfg(feature = "metrics")]
+impl ExponentialBuckets {
+    fn len(&self) -> usize {
+        self.positive.len() + self.negative.len()
+    }
+
+    /// Decreases the scale by one, merging each pair of adjacent buckets.
+    fn downscale(&mut self) {
+        self.scale -= 1;
+        for buckets in [&mut self.positive, &mut self.negative] {
+            let mut merged = BTreeMap::<i32, AtomicU64>::new();
+            for (index, count) in std::mem::take(buckets) {
+                *merged.entry(index >> 1).or_default().get_mut() += count.into_inner();
+            }
+            *buckets = merged;
+        }
+    }
+}
+
+impl Default for ExponentialHistogram {
+    fn default() -> Self {
+        Self::new(ExponentialHistogram::DEFAULT_SCALE)
+    }
+}
+
+impl ExponentialHistogram {
+    /// Smallest supported scale.
+    pub const MIN_SCALE: i8 = -10;
+    /// Largest supported scale.
+    pub const MAX_SCALE: i8 = 20;
+    /// Default scale, with a growth factor of about 1.09 between buckets.
+    pub const DEFAULT_SCALE: i8 = 3;
+    /// Default upper limit for the number of populated buckets.
+    pub const DEFAULT_MAX_BUCKETS: usize = 160;
+
+    /// Constructs a new exponential histogram starting at the given scale.
+    ///
+    /// The scale is clamped to [`Self::MIN_SCALE`]..=[`Self::MAX_SCALE`].
+    /// Only exact zeros are counted in the zero bucket, and at most
+    /// [`Self::DEFAULT_MAX_BUCKETS`] buckets are kept.
+    pub fn new(scale: i8) -> Self {
         #[cfg(feature = "metrics")]
         {
-            self.value.swap(v, Ordering::Relaxed)
+            Self {
+                zero_threshold: 0.0,
+                max_buckets: Self::DEFAULT_MAX_BUCKETS,
+                zero_count: AtomicU64::new(0),
+                sum: AtomicU64::new(0.0_f64.to_bits()),
+                count: AtomicU64::new(0),
+                buckets: RwLock::new(ExponentialBuckets {
+                    scale: scale.clamp(Self::MIN_SCALE, Self::MAX_SCALE),
+                    positive: BTreeMap::new(),
+                    negative: BTreeMap::new(),
+                }),
+            }
         }
         #[cfg(not(feature = "metrics"))]
         {
-            let _ = v;
-            0
+            let _ = scale;
+            Self {}
         }
     }
 
-    /// Returns the [`Gauge`] value.
+    /// Sets the upper limit for the number of populated buckets.
+    ///
+    /// Clamped to a minimum of 2.
+    pub fn with_max_buckets(self, max_buckets: usize) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            Self {
+                max_buckets: max_buckets.max(2),
+                ..self
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = max_buckets;
+            self
+        }
+    }
+
+    /// Sets the threshold up to which absolute values are counted in the zero bucket.
+    pub fn with_zero_threshold(self, zero_threshold: f64) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            Self {
+                zero_threshold: zero_threshold.abs(),
+                ..self
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = zero_threshold;
+            self
+        }
+    }
+
+    /// Records a value in the histogram.
+    ///
+    /// Infinite and `NaN` values are only counted in the count and the sum,
+    /// not in any bucket.
+    pub fn observe(&self, value: f64) {
+        #[cfg(feature = "metrics")]
+        {
+            self.count.fetch_add(1, Ordering::Relaxed);
+
+            self.sum
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                    let current_sum = f64::from_bits(current);
+                    Some((current_sum + value).to_bits())
+                })
+                .ok();
+
+            if !value.is_finite() {
+                return;
+            }
+            if value.abs() <= self.zero_threshold {
+                self.zero_count.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+
+            // Fast path: the bucket already exists.
+            {
+                let buckets = self.buckets.read().expect("poisoned");
+                let index = exponential_bucket_index(value.abs(), buckets.scale);
+                let map = if value > 0.0 {
+                    &buckets.positive
+                } else {
+                    &buckets.negative
+                };
+                if let Some(count) = map.get(&index) {
+                    count.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+
+            let mut buckets = self.buckets.write().expect("poisoned");
+            let index = exponential_bucket_index(value.abs(), buckets.scale);
+            let map = if value > 0.0 {
+                &mut buckets.positive
+            } else {
+                &mut buckets.negative
+            };
+            map.entry(index)
+                .or_default()
+                .fetch_add(1, Ordering::Relaxed);
+            while buckets.len() > self.max_buckets && buckets.scale > Self::MIN_SCALE {
+                buckets.downscale();
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = value;
+        }
+    }
+
+    /// Returns the current scale.
+    pub fn scale(&self) -> i8 {
+        #[cfg(feature = "metrics")]
+        {
+            self.buckets.read().expect("poisoned").scale
+        }
+        #[cfg(not(feature = "metrics"))]
+        0
+    }
+
+    /// Returns the total count of observations.
+    pub fn count(&self) -> u64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.count.load(Ordering::Relaxed)
+        }
+        #[cfg(not(feature = "metrics"))]
+        0
+    }
+
+    /// Returns the sum of all observed values.
+    pub fn sum(&self) -> f64 {
+        #[cfg(feature = "metrics")]
+        {
+            f64::from_bits(self.sum.load(Ordering::Relaxed))
+        }
+        #[cfg(not(feature = "metrics"))]
+        0.0
+    }
+
+    /// Returns the histogram downgraded to classic buckets, as a vector of
+    /// `(upper_bound, cumulative_count)` pairs.
+    ///
+    /// Every populated bucket becomes one classic bucket bounded by its upper
+    /// boundary, the zero bucket is bounded by the zero threshold, and a final
+    /// `+Inf` bucket holds the total count.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        match self.value() {
+            MetricValue::ExponentialHistogram {
+                scale,
+                zero_threshold,
+                zero_count,
+                positive,
+                negative,
+                count,
+                ..
+            } => exponential_to_classic(
+                scale,
+                zero_threshold,
+                zero_count,
+                &positive,
+                &negative,
+                count,
+            ),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Metric for ExponentialHistogram {
+    fn r#type(&self) -> MetricType {
+        MetricType::ExponentialHistogram
+    }
+
+    fn value(&self) -> MetricValue {
+        #[cfg(feature = "metrics")]
+        {
+            let buckets = self.buckets.read().expect("poisoned");
+            let collect = |map: &BTreeMap<i32, AtomicU64>| {
+                map.iter()
+                    .map(|(index, count)| (*index, count.load(Ordering::Relaxed)))
+                    .collect()
+            };
+            MetricValue::ExponentialHistogram {
+                scale: buckets.scale,
+                zero_threshold: self.zero_threshold,
+                zero_count: self.zero_count.load(Ordering::Relaxed),
+                positive: collect(&buckets.positive),
+                negative: collect(&buckets.negative),
+                sum: self.sum(),
+                count: self.count(),
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        MetricValue::ExponentialHistogram {
+            scale: 0,
+            zero_threshold: 0.0,
+            zero_count: 0,
+            positive: Vec::new(),
+            negative: Vec::new(),
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn set_value(&self, value: MetricValue) {
+        #[cfg(feature = "metrics")]
+        if let MetricValue::ExponentialHistogram {
+            scale,
+            zero_threshold,
+            zero_count,
+            positive,
+            negative,
+            sum,
+            count,
+        } = value
+        {
+            if zero_threshold != self.zero_threshold {
+                return;
+            }
+            let collect = |pairs: Vec<(i32, u64)>| {
+                pairs
+                    .into_iter()
+                    .map(|(index, count)| (index, AtomicU64::new(count)))
+                    .collect()
+            };
+            let mut buckets = self.buckets.write().expect("poisoned");
+            buckets.scale = scale.clamp(Self::MIN_SCALE, Self::MAX_SCALE);
+            buckets.positive = collect(positive);
+            buckets.negative = collect(negative);
+            self.zero_count.store(zero_count, Ordering::Relaxed);
+            self.sum.store(sum.to_bits(), Ordering::Relaxed);
+            self.count.store(count, Ordering::Relaxed);
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = value;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Returns the index of the exponential bucket containing the positive value `v`.
+#[cfg(feature = "metrics")]
+fn exponential_bucket_index(v: f64, scale: i8) -> i32 {
+    const MANTISSA_MASK: u64 = (1 << 52) - 1;
+    let bits = v.to_bits();
+    if v.is_normal() {
+        // Exact powers of two sit on a bucket boundary and belong to the
+        // lower bucket, which the log-based formula can get wrong.
+        let exponent = ((bits >> 52) & 0x7ff) as i32 - 1023;
+        let exact = bits & MANTISSA_MASK == 0;
+        if scale <= 0 {
+            let index = if exact { exponent - 1 } else { exponent };
+            return index >> -scale;
+        }
+        if exact {
+            return (exponent << scale) - 1;
+        }
+    }
+    let scaled = v.log2() * f64::powi(2.0, scale.into());
+    (scaled.ceil() as i32).saturating_sub(1)
+}
+
+/// Returns the lower boundary of exponential bucket `index` at `scale`.
+fn exponential_bucket_lower(index: i32, scale: i8) -> f64 {
+    f64::powf(2.0, index as f64 * f64::powi(2.0, -i32::from(scale)))
+}
+
+/// Downgrades exponential histogram buckets to classic cumulative buckets.
+///
+/// Negative buckets come first (most negative first), then the zero bucket
+/// bounded by `zero_threshold`, then the positive buckets and finally a
+/// `+Inf` bucket with the total `count`. Buckets whose bound overflows to
+/// infinity are merged into the next bucket.
+pub(crate) fn exponential_to_classic(
+    scale: i8,
+    zero_threshold: f64,
+    zero_count: u64,
+    positive: &[(i32, u64)],
+    negative: &[(i32, u64)],
+    count: u64,
+) -> Vec<(f64, u64)> {
+    let mut out = Vec::with_capacity(positive.len() + negative.len() + 2);
+    let mut cumulative = 0;
+    for (index, bucket_count) in negative.iter().rev() {
+        cumulative += bucket_count;
+        let bound = -exponential_bucket_lower(*index, scale);
+        if bound.is_finite() {
+            out.push((bound, cumulative));
+        }
+    }
+    cumulative += zero_count;
+    out.push((zero_threshold, cumulative));
+    for (index, bucket_count) in positive {
+        cumulative += bucket_count;
+        let bound = exponential_bucket_lower(index + 1, scale);
+        if bound.is_finite() {
+            out.push((bound, cumulative));
+        }
+    }
+    out.push((f64::INFINITY, count));
+    out
+}
+
+/// OpenMetrics [`Summary`] to track streaming quantiles of observed values.
+///
+/// Unlike a [`Histogram`], which can only report the bucket a quantile falls
+/// into, a summary estimates the quantiles directly. The estimates cover a
+/// sliding time window: the window is split into age buckets, and the oldest
+/// bucket is dropped each time the window advances by one bucket width.
+///
+/// Sum and count are cumulative over the summary's whole lifetime, as
+/// required by the OpenMetrics spec.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Summary {
+    /// Target quantiles, in the range `[0, 1]`.
+    #[cfg(feature = "metrics")]
+    pub(crate) quantiles: Vec<f64>,
+    /// Sum of all observed values (stored as bits for atomic operations).
+    #[cfg(feature = "metrics")]
+    pub(crate) sum: AtomicU64,
+    /// Total count of observations.
+    #[cfg(feature = "metrics")]
+    pub(crate) count: AtomicU64,
+    /// Quantile estimates over the sliding window.
+    #[cfg(feature = "metrics")]
+    window: Mutex<SummaryWindow>,
+}
+
+/// Rotating set of quantile streams backing a [`Summary`].
+///
+/// Every observation goes into all streams. Queries read the stream at
+/// `head`, which is the oldest one and thus covers the full window. On each
+/// rotation that stream is reset and becomes the youngest.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Serialize, Deserialize)]
+struct SummaryWindow {
+    streams: Vec<QuantileStream>,
+    head: usize,
+    bucket_width: Duration,
+    #[serde(skip, default = "Instant::now")]
+    rotated_at: Instant,
+    /// Quantile values set through [`Metric::set_value`]. Reported instead of
+    /// the stream estimates until the next observation or rotation.
+    restored: Option<Vec<(f64, f64)>>,
+}
+
+#[cfg(feature = "metrics")]
+impl SummaryWindow {
+    fn rotate(&mut self, now: Instant) {
+        if self.bucket_width.is_zero() {
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.rotated_at);
+        if elapsed < self.bucket_width {
+            return;
+        }
+        self.restored = None;
+        if elapsed >= self.bucket_width * self.streams.len() as u32 {
+            for stream in &mut self.streams {
+                stream.reset();
+            }
+            self.rotated_at = now;
+            return;
+        }
+        while now.saturating_duration_since(self.rotated_at) >= self.bucket_width {
+            self.streams[self.head].reset();
+            self.head = (self.head + 1) % self.streams.len();
+            self.rotated_at += self.bucket_width;
+        }
+    }
+}
+
+impl Default for Summary {
+    /// Creates a summary tracking the median, p90 and p99.
+    fn default() -> Self {
+        Self::new(vec![0.5, 0.9, 0.99])
+    }
+}
+
+impl Summary {
+    /// Default length of the sliding window.
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(600);
+    /// Default number of age buckets the window is split into.
+    pub const DEFAULT_AGE_BUCKETS: usize = 5;
+
+    /// Constructs a new summary estimating the given quantiles.
+    ///
+    /// Uses a sliding window of [`Self::DEFAULT_MAX_AGE`] split into
+    /// [`Self::DEFAULT_AGE_BUCKETS`] age buckets.
+    pub fn new(quantiles: Vec<f64>) -> Self {
+        Self::with_window(quantiles, Self::DEFAULT_MAX_AGE, Self::DEFAULT_AGE_BUCKETS)
+    }
+
+    /// Constructs a new summary with a custom sliding window.
+    ///
+    /// Observations older than `max_age` are dropped from the quantile
+    /// estimates. The window advances in `age_buckets` steps, so an
+    /// observation is reported for between `max_age * (age_buckets - 1) /
+    /// age_buckets` and `max_age`.
+    ///
+    /// The allowed rank error of each quantile `q` is `min(q, 1 - q) / 10`,
+    /// so tail quantiles are estimated more precisely than the median.
+    ///
+    /// Quantiles are clamped to `[0, 1]`, and `NaN` quantiles are ignored.
+    pub fn with_window(quantiles: Vec<f64>, max_age: Duration, age_buckets: usize) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            let quantiles: Vec<_> = quantiles
+                .into_iter()
+                .filter(|q| !q.is_nan())
+                .map(|q| q.clamp(0.0, 1.0))
+                .collect();
+            let age_buckets = age_buckets.max(1);
+            let targets: Vec<_> = quantiles
+                .iter()
+                .map(|&q| (q, (q.min(1.0 - q) / 10.0).max(0.0001)))
+                .collect();
+            let streams = (0..age_buckets)
+                .map(|_| QuantileStream::new(targets.clone()))
+                .collect();
+            Self {
+                quantiles,
+                sum: AtomicU64::new(0.0_f64.to_bits()),
+                count: AtomicU64::new(0),
+                window: Mutex::new(SummaryWindow {
+                    streams,
+                    head: 0,
+                    bucket_width: max_age / age_buckets as u32,
+                    rotated_at: Instant::now(),
+                    restored: None,
+                }),
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = (quantiles, max_age, age_buckets);
+            Self {}
+        }
+    }
+
+    /// Records a value in the summary.
+    pub fn observe(&self, value: f64) {
+        #[cfg(feature = "metrics")]
+        {
+            self.count.fetch_add(1, Ordering::Relaxed);
+
+            self.sum
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                    let current_sum = f64::from_bits(current);
+                    Some((current_sum + value).to_bits())
+                })
+                .ok();
+
+            let mut window = self.window.lock().expect("poisoned");
+            window.rotate(Instant::now());
+            window.restored = None;
+            for stream in &mut window.streams {
+                stream.insert(value);
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = value;
+        }
+    }
+
+    /// Returns the total count of observations.
+    pub fn count(&self) -> u64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.count.load(Ordering::Relaxed)
+        }
+        #[cfg(not(feature = "metrics"))]
+        0
+    }
+
+    /// Returns the sum of all observed values.
+    pub fn sum(&self) -> f64 {
+        #[cfg(feature = "metrics")]
+        {
+            f64::from_bits(self.sum.load(Ordering::Relaxed))
+        }
+        #[cfg(not(feature = "metrics"))]
+        0.0
+    }
+
+    /// Returns the estimates for the target quantiles as `(quantile, value)` pairs.
+    ///
+    /// The value is `NaN` if there were no observations within the window.
+    pub fn quantiles(&self) -> Vec<(f64, f64)> {
+        #[cfg(feature = "metrics")]
+        {
+            let mut window = self.window.lock().expect("poisoned");
+            window.rotate(Instant::now());
+            if let Some(restored) = &window.restored {
+                return restored.clone();
+            }
+            let head = window.head;
+            let stream = &mut window.streams[head];
+            self.quantiles
+                .iter()
+                .map(|&q| (q, stream.query(q)))
+                .collect()
+        }
+        #[cfg(not(feature = "metrics"))]
+        Vec::new()
+    }
+
+    /// Moves the window forward by `by`, as if that much time had passed.
+    #[cfg(all(test, feature = "metrics"))]
+    pub(crate) fn advance(&self, by: Duration) {
+        let mut window = self.window.lock().expect("poisoned");
+        window.rotated_at = window.rotated_at.checked_sub(by).expect("uptime too short");
+    }
+}
+
+impl Metric for Summary {
+    fn r#type(&self) -> MetricType {
+        MetricType::Summary
+    }
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Summary {
+            quantiles: self.quantiles(),
+            sum: self.sum(),
+            count: self.count(),
+        }
+    }
+
+    fn set_value(&self, value: MetricValue) {
+        #[cfg(feature = "metrics")]
+        if let MetricValue::Summary {
+            quantiles,
+            sum,
+            count,
+        } = value
+        {
+            // The streams can't be rebuilt from their estimates, so keep the
+            // restored values around until new observations come in or the
+            // window moves on.
+            self.count.store(count, Ordering::Relaxed);
+            self.sum.store(sum.to_bits(), Ordering::Relaxed);
+            let mut window = self.window.lock().expect("poisoned");
+            window.rotate(Instant::now());
+            window.restored = Some(quantiles);
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = value;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Metric for Gauge {
+    fn r#type(&self) -> MetricType {
+        MetricType::Gauge
+    }
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Gauge(self.get())
+    }
+
+    fn set_value(&self, value: MetricValue) {
+        if let MetricValue::Gauge(v) = value {
+            self.set(v);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Gauge {
+    /// Constructs a new gauge, based on the given `help`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Increases the [`Gauge`] by 1, returning the previous value.
+    pub fn inc(&self) -> i64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.value.fetch_add(1, Ordering::Relaxed)
+        }
+        #[cfg(not(feature = "metrics"))]
+        0
+    }
+
+    /// Increases the [`Gauge`] by `i64`, returning the previous value.
+    pub fn inc_by(&self, v: i64) -> i64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.value.fetch_add(v, Ordering::Relaxed)
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = v;
+            0
+        }
+    }
+
+    /// Decreases the [`Gauge`] by 1, returning the previous value.
+    pub fn dec(&self) -> i64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.value.fetch_sub(1, Ordering::Relaxed)
+        }
+        #[cfg(not(feature = "metrics"))]
+        0
+    }
+
+    /// Decreases the [`Gauge`] by `i64`, returning the previous value.
+    pub fn dec_by(&self, v: i64) -> i64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.value.fetch_sub(v, Ordering::Relaxed)
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = v;
+            0
+        }
+    }
+
+    /// Sets the [`Gauge`] to `v`, returning the previous value.
+    pub fn set(&self, v: i64) -> i64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.value.swap(v, Ordering::Relaxed)
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = v;
+            0
+        }
+    }
+
+    /// Sets the [`Gauge`] to the current Unix time in seconds, returning the
+    /// previous value.
+    pub fn set_to_current_time(&self) -> i64 {
+        #[cfg(feature = "metrics")]
+        {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            self.set(i64::try_from(now).unwrap_or(i64::MAX))
+        }
+        #[cfg(not(feature = "metrics"))]
+        0
+    }
+
+    /// Returns the [`Gauge`] value.
     pub fn get(&self) -> i64 {
         #[cfg(feature = "metrics")]
         {
@@ -500,4 +1590,399 @@ impl Gauge {
         #[cfg(not(feature = "metrics"))]
         0
     }
+
+    /// Increments the [`Gauge`] and returns a guard that decrements it again
+    /// when dropped.
+    pub fn guard(&self) -> GaugeGuard<&Self> {
+        GaugeGuard::new(self)
+    }
+
+    /// Like [`Self::guard`], but the guard keeps the gauge alive, for use with
+    /// the `Arc` returned by [`Family::get_or_create`].
+    ///
+    /// [`Family::get_or_create`]: crate::Family::get_or_create
+    pub fn guard_owned(self: Arc<Self>) -> GaugeGuard<Arc<Self>> {
+        GaugeGuard::new(self)
+    }
+}
+
+/// OpenMetrics [`Counter`] with a floating point value.
+///
+/// For counts of things that aren't discrete, like seconds spent. The value
+/// is stored as the bits of an `f64` in an atomic.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CounterF64 {
+    /// The bits of the counter value.
+    #[cfg(feature = "metrics")]
+    pub(crate) value: AtomicU64,
+}
+
+impl Metric for CounterF64 {
+    fn r#type(&self) -> MetricType {
+        MetricType::Counter
+    }
+
+    fn value(&self) -> MetricValue {
+        MetricValue::CounterF64(self.get())
+    }
+
+    fn set_value(&self, value: MetricValue) {
+        if let MetricValue::CounterF64(v) = value {
+            self.set(v);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl CounterF64 {
+    /// Constructs a new counter.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Increases the [`CounterF64`] by `v`, returning the previous value.
+    ///
+    /// Counters must only increase, so `v` should not be negative.
+    pub fn inc_by(&self, v: f64) -> f64 {
+        #[cfg(feature = "metrics")]
+        {
+            f64_fetch_add(&self.value, v)
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = v;
+            0.0
+        }
+    }
+
+    /// Sets the [`CounterF64`] value, returning the previous value.
+    ///
+    /// Warning: this is not default behavior for a counter that should always be monotonically increasing.
+    pub fn set(&self, v: f64) -> f64 {
+        #[cfg(feature = "metrics")]
+        {
+            f64::from_bits(self.value.swap(v.to_bits(), Ordering::Relaxed))
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = v;
+            0.0
+        }
+    }
+
+    /// Returns the current value of the [`CounterF64`].
+    pub fn get(&self) -> f64 {
+        #[cfg(feature = "metrics")]
+        {
+            f64::from_bits(self.value.load(Ordering::Relaxed))
+        }
+        #[cfg(not(feature = "metrics"))]
+        0.0
+    }
+}
+
+/// OpenMetrics [`Gauge`] with a floating point value.
+///
+/// For values like ratios or rates. The value is stored as the bits of an
+/// `f64` in an atomic.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GaugeF64 {
+    /// The bits of the gauge value.
+    #[cfg(feature = "metrics")]
+    pub(crate) value: AtomicU64,
+}
+
+impl Metric for GaugeF64 {
+    fn r#type(&self) -> MetricType {
+        MetricType::Gauge
+    }
+
+    fn value(&self) -> MetricValue {
+        MetricValue::GaugeF64(self.get())
+    }
+
+    fn set_value(&self, value: MetricValue) {
+        if let MetricValue::GaugeF64(v) = value {
+            self.set(v);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl GaugeF64 {
+    /// Constructs a new gauge.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Increases the [`GaugeF64`] by `v`, returning the previous value.
+    pub fn inc_by(&self, v: f64) -> f64 {
+        #[cfg(feature = "metrics")]
+        {
+            f64_fetch_add(&self.value, v)
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = v;
+            0.0
+        }
+    }
+
+    /// Decreases the [`GaugeF64`] by `v`, returning the previous value.
+    pub fn dec_by(&self, v: f64) -> f64 {
+        self.inc_by(-v)
+    }
+
+    /// Sets the [`GaugeF64`] to `v`, returning the previous value.
+    pub fn set(&self, v: f64) -> f64 {
+        #[cfg(feature = "metrics")]
+        {
+            f64::from_bits(self.value.swap(v.to_bits(), Ordering::Relaxed))
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = v;
+            0.0
+        }
+    }
+
+    /// Returns the [`GaugeF64`] value.
+    pub fn get(&self) -> f64 {
+        #[cfg(feature = "metrics")]
+        {
+            f64::from_bits(self.value.load(Ordering::Relaxed))
+        }
+        #[cfg(not(feature = "metrics"))]
+        0.0
+    }
+}
+
+/// OpenMetrics `Info` metric, for static information like the build version.
+///
+/// Encoded as a single `<name>_info` sample with the info as labels and the
+/// value 1:
+///
+/// ```
+/// # use iroh_metrics::Info;
+/// let info = Info::new([("version", env!("CARGO_PKG_VERSION"))]);
+/// info.set([("version", "1.2.3"), ("node_id", "abcd")]);
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Info {
+    /// The info labels.
+    #[cfg(feature = "metrics")]
+    pub(crate) labels: RwLock<Vec<(String, String)>>,
+}
+
+impl Metric for Info {
+    fn r#type(&self) -> MetricType {
+        MetricType::Info
+    }
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Info(self.get())
+    }
+
+    fn set_value(&self, value: MetricValue) {
+        if let MetricValue::Info(labels) = value {
+            self.set(labels);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Info {
+    /// Constructs a new info metric with the given labels.
+    pub fn new(labels: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>) -> Self {
+        let info = Self::default();
+        info.set(labels);
+        info
+    }
+
+    /// Replaces the labels of the [`Info`].
+    pub fn set(&self, labels: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>) {
+        #[cfg(feature = "metrics")]
+        {
+            let labels = labels
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect();
+            *self.labels.write().expect("poisoned") = labels;
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = labels.into_iter();
+        }
+    }
+
+    /// Returns the labels of the [`Info`].
+    pub fn get(&self) -> Vec<(String, String)> {
+        #[cfg(feature = "metrics")]
+        {
+            self.labels.read().expect("poisoned").clone()
+        }
+        #[cfg(not(feature = "metrics"))]
+        Vec::new()
+    }
+}
+
+/// OpenMetrics `StateSet` metric, for one of a fixed set of states.
+///
+/// The states are the variants of `E`, an enum deriving
+/// [`EncodeLabelValue`](crate::EncodeLabelValue). Encoded as one sample per
+/// state, labeled with the metric name, with the value 1 for the current
+/// state and 0 for all others:
+///
+/// ```
+/// # use iroh_metrics::{EncodeLabelValue, StateSet};
+/// #[derive(EncodeLabelValue)]
+/// enum ConnState {
+///     Direct,
+///     Relay,
+///     Mixed,
+/// }
+///
+/// let state = StateSet::new(ConnState::Relay);
+/// state.set(ConnState::Direct);
+/// ```
+///
+/// [`StateSet::default`] starts in the first state.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "", deserialize = "E: EncodeStateSet"))]
+pub struct StateSet<E> {
+    /// Index of the current state in [`EncodeStateSet::STATES`].
+    #[cfg(feature = "metrics")]
+    #[serde(deserialize_with = "deserialize_state_index::<_, E>")]
+    pub(crate) state: AtomicU64,
+    #[serde(skip)]
+    _states: PhantomData<fn() -> E>,
+}
+
+impl<E: EncodeStateSet> fmt::Debug for StateSet<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateSet")
+            .field("state", &self.get().encode_label_value().as_str())
+            .finish()
+    }
+}
+
+impl<E: EncodeStateSet> Default for StateSet<E> {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "metrics")]
+            state: AtomicU64::new(0),
+            _states: PhantomData,
+        }
+    }
+}
+
+impl<E: EncodeStateSet> Metric for StateSet<E> {
+    fn r#type(&self) -> MetricType {
+        MetricType::StateSet
+    }
+
+    fn value(&self) -> MetricValue {
+        let current = self.get().state_index();
+        let states = E::STATES
+            .iter()
+            .enumerate()
+            .map(|(i, state)| {
+                (
+                    state.encode_label_value().as_str().into_owned(),
+                    i == current,
+                )
+            })
+            .collect();
+        MetricValue::StateSet(states)
+    }
+
+    fn set_value(&self, value: MetricValue) {
+        let MetricValue::StateSet(states) = value else {
+            return;
+        };
+        let Some((name, _)) = states.iter().find(|(_, active)| *active) else {
+            return;
+        };
+        if let Some(state) = E::STATES
+            .iter()
+            .find(|state| state.encode_label_value().as_str() == name.as_str())
+        {
+            self.set_state_index(state.state_index());
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl<E: EncodeStateSet> StateSet<E> {
+    /// Constructs a new state set in the given state.
+    pub fn new(state: E) -> Self {
+        let set = Self::default();
+        set.set(state);
+        set
+    }
+
+    /// Sets the current state.
+    pub fn set(&self, state: E) {
+        self.set_state_index(state.state_index());
+    }
+
+    /// Returns the current state.
+    ///
+    /// Without the `metrics` feature, this is always the first state.
+    pub fn get(&self) -> &'static E {
+        #[cfg(feature = "metrics")]
+        let index = self.state.load(Ordering::Relaxed) as usize;
+        #[cfg(not(feature = "metrics"))]
+        let index = 0;
+        &E::STATES[index]
+    }
+
+    fn set_state_index(&self, index: usize) {
+        #[cfg(feature = "metrics")]
+        self.state.store(index as u64, Ordering::Relaxed);
+        #[cfg(not(feature = "metrics"))]
+        let _ = index;
+    }
+}
+
+/// Deserializes the state of a [`StateSet`], rejecting indices out of range
+/// of [`EncodeStateSet::STATES`].
+#[cfg(feature = "metrics")]
+fn deserialize_state_index<'de, D, E>(deserializer: D) -> Result<AtomicU64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    E: EncodeStateSet,
+{
+    let index = u64::deserialize(deserializer)?;
+    if usize::try_from(index).is_ok_and(|index| index < E::STATES.len()) {
+        Ok(AtomicU64::new(index))
+    } else {
+        Err(serde::de::Error::custom(format_args!(
+            "state index {index} out of range for {} states",
+            E::STATES.len()
+        )))
+    }
+}
+
+/// Atomically adds `v` to the `f64` stored as bits in `atomic`, returning the previous value.
+#[cfg(feature = "metrics")]
+fn f64_fetch_add(atomic: &AtomicU64, v: f64) -> f64 {
+    let prev = atomic
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+            Some((f64::from_bits(current) + v).to_bits())
+        })
+        .expect("always Some");
+    f64::from_bits(prev)
 }
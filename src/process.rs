@@ -0,0 +1,243 @@
+//! Metrics about the current process.
+
+use crate::{CollectedMetric, Collector};
+
+/// Clock ticks per second in `/proc`, fixed at 100 by the kernel ABI.
+#[cfg(target_os = "linux")]
+const USER_HZ: u64 = 100;
+
+/// [`Collector`] for the standard `process_*` metrics.
+///
+/// Reads `/proc/self` whenever the registry is encoded, and yields:
+///
+/// - `process_cpu_seconds_total`: user and system CPU time
+/// - `process_resident_memory_bytes` and `process_virtual_memory_bytes`
+/// - `process_open_fds` and `process_max_fds`
+/// - `process_threads`
+/// - `process_start_time_seconds`: start time since the Unix epoch
+///
+/// Values that can't be read are left out. On platforms other than Linux,
+/// no metrics are collected.
+///
+/// Register it into the root of a registry to get the standard names:
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use iroh_metrics::{ProcessMetrics, Registry};
+/// let mut registry = Registry::default();
+/// registry.register_collector(Arc::new(ProcessMetrics::new()));
+/// ```
+///
+/// With the `static_core` feature, register it in [`Core::init`] the same way.
+///
+/// [`Core::init`]: crate::static_core::Core::init
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProcessMetrics {
+    _private: (),
+}
+
+impl ProcessMetrics {
+    /// Creates a new process metrics collector.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Collector for ProcessMetrics {
+    #[cfg(target_os = "linux")]
+    fn collect(&self) -> Vec<CollectedMetric> {
+        linux::collect()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn collect(&self) -> Vec<CollectedMetric> {
+        Vec::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs;
+
+    use super::USER_HZ;
+    use crate::{CollectedMetric, MetricValue};
+
+    pub(super) fn collect() -> Vec<CollectedMetric> {
+        let mut metrics = Vec::new();
+        let stat = fs::read_to_string("/proc/self/stat").ok();
+        let stat = stat.as_deref().and_then(parse_stat);
+        if let Some(stat) = &stat {
+            metrics.push(CollectedMetric::new(
+                "process_cpu_seconds",
+                "Total user and system CPU time spent in seconds",
+                MetricValue::CounterF64(stat.cpu_ticks as f64 / USER_HZ as f64),
+            ));
+        }
+
+        if let Ok(status) = fs::read_to_string("/proc/self/status") {
+            if let Some(kb) = parse_status_field(&status, "VmRSS") {
+                metrics.push(gauge(
+                    "process_resident_memory_bytes",
+                    "Resident memory size in bytes",
+                    kb * 1024,
+                ));
+            }
+            if let Some(kb) = parse_status_field(&status, "VmSize") {
+                metrics.push(gauge(
+                    "process_virtual_memory_bytes",
+                    "Virtual memory size in bytes",
+                    kb * 1024,
+                ));
+            }
+            if let Some(threads) = parse_status_field(&status, "Threads") {
+                metrics.push(gauge("process_threads", "Number of OS threads", threads));
+            }
+        }
+
+        if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+            metrics.push(gauge(
+                "process_open_fds",
+                "Number of open file descriptors",
+                fds.count() as u64,
+            ));
+        }
+        if let Some(max) = fs::read_to_string("/proc/self/limits")
+            .ok()
+            .as_deref()
+            .and_then(parse_max_fds)
+        {
+            metrics.push(gauge(
+                "process_max_fds",
+                "Maximum number of open file descriptors",
+                max,
+            ));
+        }
+
+        let boot_time = fs::read_to_string("/proc/stat")
+            .ok()
+            .as_deref()
+            .and_then(parse_boot_time);
+        if let (Some(stat), Some(boot_time)) = (&stat, boot_time) {
+            metrics.push(gauge(
+                "process_start_time_seconds",
+                "Start time of the process since unix epoch in seconds",
+                boot_time + stat.start_ticks / USER_HZ,
+            ));
+        }
+        metrics
+    }
+
+    fn gauge(name: &'static str, help: &'static str, value: u64) -> CollectedMetric {
+        CollectedMetric::gauge(name, help, i64::try_from(value).unwrap_or(i64::MAX))
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub(super) struct Stat {
+        /// User plus system time, in clock ticks.
+        pub(super) cpu_ticks: u64,
+        /// Start time after boot, in clock ticks.
+        pub(super) start_ticks: u64,
+    }
+
+    /// Parses `/proc/self/stat`, see `proc_pid_stat(5)`.
+    pub(super) fn parse_stat(stat: &str) -> Option<Stat> {
+        // The command name may contain spaces and parentheses, skip past its end.
+        let rest = &stat[stat.rfind(')')? + 1..];
+        // Fields are numbered from 1, the first after the command is the state (3).
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+        Some(Stat {
+            cpu_ticks: field(14)? + field(15)?,
+            start_ticks: field(22)?,
+        })
+    }
+
+    /// Parses the numeric value of a `Key: value [kB]` line of `/proc/self/status`.
+    pub(super) fn parse_status_field(status: &str, key: &str) -> Option<u64> {
+        status.lines().find_map(|line| {
+            let value = line.strip_prefix(key)?.strip_prefix(':')?;
+            value.split_whitespace().next()?.parse().ok()
+        })
+    }
+
+    /// Parses the soft limit of open files from `/proc/self/limits`.
+    pub(super) fn parse_max_fds(limits: &str) -> Option<u64> {
+        let line = limits.lines().find(|l| l.starts_with("Max open files"))?;
+        line["Max open files".len()..]
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    }
+
+    /// Parses the boot time in seconds since the Unix epoch from `/proc/stat`.
+    pub(super) fn parse_boot_time(stat: &str) -> Option<u64> {
+        stat.lines()
+            .find_map(|line| line.strip_prefix("btime "))?
+            .trim()
+            .parse()
+            .ok()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{linux::*, *};
+    use crate::{MetricValue, MetricsSource, Registry};
+
+    #[test]
+    fn test_parse_proc() {
+        let stat = "1234 (my (weird) cmd) S 1 1234 1234 0 -1 4194560 1000 0 0 0 250 130 0 0 20 0 7 0 98765 123456789 2000 18446744073709551615";
+        assert_eq!(
+            parse_stat(stat),
+            Some(Stat {
+                cpu_ticks: 380,
+                start_ticks: 98765
+            })
+        );
+        assert_eq!(parse_stat("1234 (cmd) S 1"), None);
+
+        let status = "Name:\tcmd\nVmSize:\t  123456 kB\nVmRSS:\t    2048 kB\nThreads:\t7\n";
+        assert_eq!(parse_status_field(status, "VmRSS"), Some(2048));
+        assert_eq!(parse_status_field(status, "VmSize"), Some(123456));
+        assert_eq!(parse_status_field(status, "Threads"), Some(7));
+        assert_eq!(parse_status_field(status, "VmSwap"), None);
+
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \nMax cpu time              unlimited            unlimited            seconds   \nMax open files            1024                 524288               files     \n";
+        assert_eq!(parse_max_fds(limits), Some(1024));
+
+        let proc_stat = "cpu  1 2 3\nintr 1 2\nbtime 1700000000\nprocesses 42\n";
+        assert_eq!(parse_boot_time(proc_stat), Some(1700000000));
+    }
+
+    #[test]
+    fn test_process_metrics() {
+        let metrics = ProcessMetrics::new().collect();
+        let value = |name: &str| {
+            metrics
+                .iter()
+                .find(|m| m.name == name)
+                .map(|m| m.value.clone())
+        };
+        assert!(matches!(
+            value("process_cpu_seconds"),
+            Some(MetricValue::CounterF64(_))
+        ));
+        assert!(
+            matches!(value("process_resident_memory_bytes"), Some(MetricValue::Gauge(v)) if v > 0)
+        );
+        assert!(matches!(value("process_open_fds"), Some(MetricValue::Gauge(v)) if v > 0));
+        assert!(matches!(value("process_threads"), Some(MetricValue::Gauge(v)) if v > 0));
+        assert!(
+            matches!(value("process_start_time_seconds"), Some(MetricValue::Gauge(v)) if v > 1_600_000_000)
+        );
+
+        let mut registry = Registry::default();
+        registry.register_collector(Arc::new(ProcessMetrics::new()));
+        let text = registry.encode_openmetrics_to_string().unwrap();
+        assert!(text.contains("# TYPE process_cpu_seconds counter\nprocess_cpu_seconds_total "));
+        assert!(text.contains("\nprocess_max_fds "));
+    }
+}
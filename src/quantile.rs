@@ -0,0 +1,157 @@
+//! Streaming quantile estimation for [`Summary`](crate::Summary).
+//!
+//! Implements the targeted-quantiles variant of the CKMS algorithm
+//! ("Effective Computation of Biased Quantiles over Data Streams", Cormode,
+//! Korn, Muthukrishnan, Srivastava), the same approach used by the Go
+//! Prometheus client. Memory use is bounded by the error targets instead of
+//! the number of observations.
+
+use serde::{Deserialize, Serialize};
+
+/// Number of observations buffered before they are merged into the stream.
+const BUFFER_CAP: usize = 128;
+
+/// A compressed sample: `width` is the number of observations it stands for
+/// and `delta` the maximum rank error it carries.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Sample {
+    value: f64,
+    width: f64,
+    delta: f64,
+}
+
+/// A CKMS stream estimating a fixed set of target quantiles.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct QuantileStream {
+    /// `(quantile, allowed rank error)` pairs.
+    targets: Vec<(f64, f64)>,
+    samples: Vec<Sample>,
+    buffer: Vec<f64>,
+    /// Number of observations merged into `samples`.
+    n: f64,
+}
+
+impl QuantileStream {
+    /// Creates a stream targeting the given `(quantile, epsilon)` pairs.
+    pub(crate) fn new(targets: Vec<(f64, f64)>) -> Self {
+        Self {
+            targets,
+            samples: Vec::new(),
+            buffer: Vec::with_capacity(BUFFER_CAP),
+            n: 0.0,
+        }
+    }
+
+    /// Records an observation.
+    pub(crate) fn insert(&mut self, value: f64) {
+        self.buffer.push(value);
+        if self.buffer.len() >= BUFFER_CAP {
+            self.flush();
+        }
+    }
+
+    /// Returns the estimate for quantile `q`, or `NaN` if the stream is empty.
+    pub(crate) fn query(&mut self, q: f64) -> f64 {
+        self.flush();
+        let Some((first, rest)) = self.samples.split_first() else {
+            return f64::NAN;
+        };
+        let mut t = (q * self.n).ceil();
+        t += (self.invariant(t) / 2.0).ceil();
+        let mut prev = first;
+        let mut r = 0.0;
+        for cur in rest {
+            r += prev.width;
+            if r + cur.width + cur.delta > t {
+                return prev.value;
+            }
+            prev = cur;
+        }
+        prev.value
+    }
+
+    /// Drops all observations, keeping the targets.
+    pub(crate) fn reset(&mut self) {
+        self.samples.clear();
+        self.buffer.clear();
+        self.n = 0.0;
+    }
+
+    /// Maximum rank error allowed at rank `r`, minimized over all targets.
+    fn invariant(&self, r: f64) -> f64 {
+        let mut min = f64::MAX;
+        for &(q, eps) in &self.targets {
+            let f = if q * self.n <= r {
+                (2.0 * eps * r) / q
+            } else {
+                (2.0 * eps * (self.n - r)) / (1.0 - q)
+            };
+            if f < min {
+                min = f;
+            }
+        }
+        min
+    }
+
+    fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.sort_by(f64::total_cmp);
+        self.merge(&buffer);
+        buffer.clear();
+        self.buffer = buffer;
+        self.compress();
+    }
+
+    /// Merges sorted observations into the sample list.
+    fn merge(&mut self, sorted: &[f64]) {
+        let mut r = 0.0;
+        let mut i = 0;
+        for &value in sorted {
+            while i < self.samples.len() && self.samples[i].value <= value {
+                r += self.samples[i].width;
+                i += 1;
+            }
+            // The smallest and largest samples are always exact.
+            let delta = if i == 0 || i == self.samples.len() {
+                0.0
+            } else {
+                (self.invariant(r).floor() - 1.0).max(0.0)
+            };
+            self.samples.insert(
+                i,
+                Sample {
+                    value,
+                    width: 1.0,
+                    delta,
+                },
+            );
+            i += 1;
+            self.n += 1.0;
+            r += 1.0;
+        }
+    }
+
+    /// Merges adjacent samples whose combined error stays within bounds.
+    fn compress(&mut self) {
+        if self.samples.len() < 2 {
+            return;
+        }
+        let mut xi = self.samples.len() - 1;
+        let mut r = self.n - 1.0 - self.samples[xi].width;
+        for i in (0..xi).rev() {
+            let c = self.samples[i];
+            let x = self.samples[xi];
+            if c.width + x.width + x.delta <= self.invariant(r) {
+                self.samples[xi].width += c.width;
+                self.samples.remove(i);
+                xi -= 1;
+            } else {
+                xi = i;
+            }
+            r -= c.width;
+        }
+    }
+}
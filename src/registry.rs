@@ -9,13 +9,19 @@ use std::{
 
 use portable_atomic::{AtomicU64, Ordering};
 
-use crate::{Error, MetricsGroup, MetricsGroupSet, encoding::encode_eof, iterable::IntoIterable};
+use crate::{
+    Collector, Error, MetricsGroup, MetricsGroupSet,
+    collector::RegisteredCollector,
+    encoding::{GroupedSamples, encode_eof},
+    iterable::IntoIterable,
+};
 
 /// A registry for [`MetricsGroup`].
 #[derive(Debug, Default)]
 pub struct Registry {
     schema_version: Arc<AtomicU64>,
     metrics: Vec<Arc<dyn MetricsGroup>>,
+    collectors: Vec<RegisteredCollector>,
     prefix: Option<Cow<'static, str>>,
     labels: Vec<(Cow<'static, str>, Cow<'static, str>)>,
     sub_registries: Vec<Registry>,
@@ -31,6 +37,7 @@ impl Registry {
         let sub_registry = Registry {
             schema_version: self.schema_version.clone(),
             metrics: Default::default(),
+            collectors: Default::default(),
             prefix: Some(prefix),
             labels: self.labels.clone(),
             sub_registries: Default::default(),
@@ -54,6 +61,7 @@ impl Registry {
             prefix: self.prefix.clone(),
             labels: all_labels,
             metrics: Default::default(),
+            collectors: Default::default(),
             sub_registries: Default::default(),
         };
         self.sub_registries.push(sub_registry);
@@ -80,6 +88,16 @@ impl Registry {
         self.metrics.push(metrics_group);
     }
 
+    /// Registers a [`Collector`] into this registry.
+    ///
+    /// The collector is called once here, and then whenever the registry is
+    /// encoded. Its metrics get the prefix and labels of this registry. It can
+    /// be removed again with [`Self::unregister`].
+    pub fn register_collector(&mut self, collector: Arc<dyn Collector>) {
+        self.schema_version.fetch_add(1, Ordering::Relaxed);
+        self.collectors.push(RegisteredCollector::new(collector));
+    }
+
     /// Registers a [`MetricsGroupSet`] into this registry.
     pub fn register_all(&mut self, metrics_group_set: &impl MetricsGroupSet) {
         for group in metrics_group_set.groups_cloned() {
@@ -93,19 +111,104 @@ impl Registry {
         registry.register_all(metrics_group_set)
     }
 
+    /// Removes a [`MetricsGroup`] or [`Collector`] from this registry and all its subregistries.
+    ///
+    /// Groups and collectors are matched by [`Arc`] identity, so pass a clone
+    /// of the `Arc` that was registered. Returns `true` if it was found.
+    pub fn unregister<G: ?Sized>(&mut self, metrics_group: &Arc<G>) -> bool {
+        let ptr = Arc::as_ptr(metrics_group).cast::<()>();
+        let removed = self.remove_group(ptr);
+        if removed {
+            self.schema_version.fetch_add(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /// Removes all groups of a [`MetricsGroupSet`] from this registry and all its subregistries.
+    ///
+    /// Subregistries created by [`Self::register_all_prefixed`] are kept, but
+    /// can be removed with [`Self::remove_sub_registries`].
+    pub fn unregister_all(&mut self, metrics_group_set: &impl MetricsGroupSet) {
+        for group in metrics_group_set.groups_cloned() {
+            self.unregister(&group);
+        }
+    }
+
+    fn remove_group(&mut self, ptr: *const ()) -> bool {
+        let len = self.metrics.len();
+        self.metrics
+            .retain(|group| Arc::as_ptr(group).cast::<()>() != ptr);
+        let mut removed = self.metrics.len() != len;
+        let len = self.collectors.len();
+        self.collectors
+            .retain(|c| Arc::as_ptr(&c.collector).cast::<()>() != ptr);
+        removed |= self.collectors.len() != len;
+        for sub in self.sub_registries.iter_mut() {
+            removed |= sub.remove_group(ptr);
+        }
+        removed
+    }
+
+    /// Removes the direct subregistries for which `predicate` returns `true`,
+    /// including everything registered in them.
+    ///
+    /// Returns the number of removed subregistries.
+    ///
+    /// ```
+    /// # use iroh_metrics::Registry;
+    /// let mut registry = Registry::default();
+    /// registry.sub_registry_with_label("endpoint", "a");
+    /// registry.sub_registry_with_label("endpoint", "b");
+    /// let removed = registry
+    ///     .remove_sub_registries(|sub| sub.labels().any(|(k, v)| k == "endpoint" && v == "a"));
+    /// assert_eq!(removed, 1);
+    /// ```
+    pub fn remove_sub_registries(&mut self, mut predicate: impl FnMut(&Registry) -> bool) -> usize {
+        let len = self.sub_registries.len();
+        self.sub_registries.retain(|sub| !predicate(sub));
+        let removed = len - self.sub_registries.len();
+        if removed > 0 {
+            self.schema_version.fetch_add(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /// Returns the prefix of all metrics in this registry, if any.
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    /// Returns the labels added to all metrics in this registry.
+    pub fn labels(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.labels.iter().map(|(k, v)| (k.as_ref(), v.as_ref()))
+    }
+
     /// Encodes all metrics in the OpenMetrics text format.
     ///
+    /// Samples sharing a metric name, e.g. from the same group type
+    /// registered in several labeled sub-registries, are written as a single
+    /// metric family under one `# HELP` / `# TYPE` header.
+    ///
     /// This does not write the terminal `# EOF\n` string to `writer`.
     /// You can use [`encode_openmetrics_eof`] to do that.
     ///
     /// [`encode_openmetrics_eof`]: crate::encoding::encode_openmetrics_eof
     pub fn encode_openmetrics_to_writer(&self, writer: &mut impl Write) -> fmt::Result {
+        let mut samples = GroupedSamples::default();
+        self.collect_openmetrics(&mut samples)?;
+        samples.encode_openmetrics(writer)
+    }
+
+    fn collect_openmetrics(&self, samples: &mut GroupedSamples) -> fmt::Result {
         for group in &self.metrics {
-            group.encode_openmetrics(writer, self.prefix.as_deref(), &self.labels)?;
+            group.collect_openmetrics(samples, self.prefix.as_deref(), &self.labels)?;
+        }
+        for collector in &self.collectors {
+            collector.collect_openmetrics(samples, self.prefix.as_deref(), &self.labels)?;
         }
 
         for sub in self.sub_registries.iter() {
-            sub.encode_openmetrics_to_writer(writer)?;
+            sub.collect_openmetrics(samples)?;
         }
         Ok(())
     }
@@ -135,6 +238,15 @@ impl Registry {
                 &self.labels,
             );
         }
+        for collector in &self.collectors {
+            collector.encode_schema(
+                schema.as_deref_mut(),
+                values,
+                self.prefix.as_deref(),
+                &self.labels,
+                &self.schema_version,
+            );
+        }
 
         for sub in self.sub_registries.iter() {
             sub.encode_schema(schema.as_deref_mut(), values);
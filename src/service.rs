@@ -4,21 +4,59 @@
 //! returned handle is dropped.
 
 use std::{
-    net::SocketAddr,
-    sync::Arc,
+    collections::{BTreeMap, HashMap},
+    io::Write as _,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
-use hyper::{Request, Response, service::service_fn};
-use tokio::{io::AsyncWriteExt as _, net::TcpListener, task::JoinSet};
+use flate2::{Compression, write::GzEncoder};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    header::{self, HeaderValue},
+    service::service_fn,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt as _},
+    net::TcpListener,
+    task::JoinSet,
+};
+use tokio_rustls::TlsAcceptor;
 use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
 use tracing::{debug, error, info, warn};
 
-use crate::{Error, MetricsSource, parse_prometheus_metrics};
+use self::{
+    access::{Auth, IpRange},
+    format::{TextFormat, accepts_gzip},
+};
+pub use self::{
+    otlp::{OtlpExporter, OtlpExporterConfig},
+    remote_write::{RemoteWriteConfig, RemoteWriteExporter},
+    statsd::{StatsdConfig, StatsdExporter, StatsdHistogramType},
+};
+use crate::{
+    Error, MetricType, MetricValue, MetricsSource,
+    encoding::{ParsedFamily, parse_openmetrics},
+};
+
+mod access;
+mod format;
+mod otlp;
+mod proto;
+mod remote_write;
+mod statsd;
 
 type BytesBody = http_body_util::Full<hyper::body::Bytes>;
 
-/// HTTP server that exposes metrics in the OpenMetrics text format.
+/// HTTP server that exposes metrics on `/` and `/metrics`.
+///
+/// Responds in the OpenMetrics text format if the `Accept` header asks for
+/// `application/openmetrics-text`, and in the Prometheus text format 0.0.4
+/// otherwise. Responses are gzip-compressed if the client accepts it.
+///
+/// Use [`MetricsServer::builder`] to serve several sources, JSON or health
+/// endpoints, or to add TLS, authentication and an IP allowlist.
 ///
 /// Aborts the accept loop and all in-flight connections on drop. For an
 /// orderly shutdown that lets in-flight connections finish, call
@@ -32,20 +70,24 @@ pub struct MetricsServer {
 
 impl MetricsServer {
     /// Binds to `addr` and spawns the server in a background task.
+    ///
+    /// Serves the metrics on `/` and `/metrics`. Every request encodes a
+    /// clone of `registry`.
     pub async fn spawn(
         addr: SocketAddr,
         registry: impl MetricsSource + Clone,
     ) -> std::io::Result<Self> {
-        let listener = TcpListener::bind(addr).await?;
-        let addr = listener.local_addr()?;
-        info!("Starting metrics server on {addr}");
-        let cancel = CancellationToken::new();
-        let task = tokio::spawn(server_loop(listener, registry, cancel.clone()));
-        Ok(Self {
-            addr,
-            cancel,
-            task: AbortOnDropHandle::new(task),
-        })
+        let registry: Arc<dyn DynMetricsSource> = Arc::new(CloneSource(Mutex::new(registry)));
+        Self::builder()
+            .route("/", Route::Metrics(registry.clone()))
+            .route("/metrics", Route::Metrics(registry))
+            .spawn(addr)
+            .await
+    }
+
+    /// Returns a builder to configure the routes of the server.
+    pub fn builder() -> MetricsServerBuilder {
+        MetricsServerBuilder::default()
     }
 
     /// Returns the local address the server is bound to.
@@ -64,11 +106,202 @@ impl MetricsServer {
     }
 }
 
-async fn server_loop(
-    listener: TcpListener,
-    registry: impl MetricsSource + Clone,
-    cancel: CancellationToken,
-) {
+/// Builder for a [`MetricsServer`] with custom routes.
+///
+/// Requests to paths without a route are answered with `404 Not Found`.
+///
+/// ```no_run
+/// # use std::sync::{Arc, RwLock};
+/// # use iroh_metrics::{Registry, encoding::Decoder, service::MetricsServer};
+/// # async fn run(registry: Arc<Registry>, decoder: Arc<RwLock<Decoder>>) -> std::io::Result<()> {
+/// let server = MetricsServer::builder()
+///     .metrics("/metrics", registry.clone())
+///     .metrics("/metrics/decoded", decoder)
+///     .json("/metrics.json", registry)
+///     .liveness("/healthz")
+///     .spawn("127.0.0.1:9090".parse().unwrap())
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct MetricsServerBuilder {
+    routes: HashMap<String, Route>,
+    auth: Option<Auth>,
+    allowlist: Vec<IpRange>,
+    tls_config: Option<rustls::ServerConfig>,
+}
+
+impl MetricsServerBuilder {
+    /// Serves `source` in the text format on `path`.
+    ///
+    /// The format and compression are negotiated as described on [`MetricsServer`].
+    pub fn metrics(self, path: impl Into<String>, source: impl MetricsSource + Sync) -> Self {
+        self.route(path, Route::Metrics(Arc::new(source)))
+    }
+
+    /// Serves the metrics of `source` as a JSON array on `path`.
+    ///
+    /// Each element describes one metric item, with its `name`, `help`,
+    /// `type`, `labels` and `value`.
+    pub fn json(self, path: impl Into<String>, source: impl MetricsSource + Sync) -> Self {
+        self.route(path, Route::Json(Arc::new(source)))
+    }
+
+    /// Answers `200 OK` on `path` for as long as the server is running.
+    pub fn liveness(self, path: impl Into<String>) -> Self {
+        self.route(path, Route::Liveness)
+    }
+
+    /// Answers `200 OK` on `path` if `ready` returns `true`, and
+    /// `503 Service Unavailable` otherwise.
+    pub fn readiness(
+        self,
+        path: impl Into<String>,
+        ready: impl Fn() -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.route(path, Route::Readiness(Arc::new(ready)))
+    }
+
+    /// Requires `Authorization: Bearer <token>` on the metrics and JSON routes.
+    ///
+    /// Health endpoints stay unauthenticated, so that probes work without
+    /// credentials. Replaces a previously configured basic auth.
+    pub fn bearer_auth(mut self, token: impl AsRef<str>) -> Self {
+        self.auth = Some(Auth::bearer(token.as_ref()));
+        self
+    }
+
+    /// Requires HTTP basic auth on the metrics and JSON routes.
+    ///
+    /// Like [`bearer_auth`](Self::bearer_auth), health endpoints are exempt.
+    /// Without [TLS](Self::tls), the credentials are sent in plain text.
+    pub fn basic_auth(mut self, username: impl AsRef<str>, password: impl AsRef<str>) -> Self {
+        self.auth = Some(Auth::basic(username.as_ref(), password.as_ref()));
+        self
+    }
+
+    /// Only accepts connections from `ip`.
+    ///
+    /// Can be called several times, and combined with
+    /// [`allow_subnet`](Self::allow_subnet). If no address is allowed
+    /// explicitly, connections from all addresses are accepted.
+    pub fn allow_ip(self, ip: IpAddr) -> Self {
+        let prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+        self.allow_subnet(ip, prefix_len)
+    }
+
+    /// Only accepts connections from addresses in `network/prefix_len`.
+    ///
+    /// See [`allow_ip`](Self::allow_ip).
+    pub fn allow_subnet(mut self, network: IpAddr, prefix_len: u8) -> Self {
+        self.allowlist.push(IpRange::new(network, prefix_len));
+        self
+    }
+
+    /// Serves HTTPS with the given rustls [`ServerConfig`].
+    ///
+    /// Only HTTP/1.1 is served, so the ALPN protocols of the config should be
+    /// empty or include `http/1.1`.
+    ///
+    /// [`ServerConfig`]: rustls::ServerConfig
+    pub fn tls(mut self, config: rustls::ServerConfig) -> Self {
+        self.tls_config = Some(config);
+        self
+    }
+
+    /// Mounts `route` on `path`, replacing any previous route.
+    fn route(mut self, path: impl Into<String>, route: Route) -> Self {
+        self.routes.insert(path.into(), route);
+        self
+    }
+
+    /// Binds to `addr` and spawns the server in a background task.
+    pub async fn spawn(self, addr: SocketAddr) -> std::io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        info!("Starting metrics server on {addr}");
+        let cancel = CancellationToken::new();
+        let state = Arc::new(ServerState {
+            routes: self.routes,
+            auth: self.auth,
+            allowlist: self.allowlist,
+            tls: self
+                .tls_config
+                .map(|config| TlsAcceptor::from(Arc::new(config))),
+        });
+        let task = tokio::spawn(server_loop(listener, state, cancel.clone()));
+        Ok(MetricsServer {
+            addr,
+            cancel,
+            task: AbortOnDropHandle::new(task),
+        })
+    }
+}
+
+/// The text encoding of a [`MetricsSource`], as an object-safe trait.
+trait DynMetricsSource: Send + Sync {
+    fn encode_openmetrics_to_string(&self) -> Result<String, Error>;
+}
+
+impl<S: MetricsSource + Sync> DynMetricsSource for S {
+    fn encode_openmetrics_to_string(&self) -> Result<String, Error> {
+        MetricsSource::encode_openmetrics_to_string(self)
+    }
+}
+
+/// A source that doesn't need to be `Sync`, encoded through a clone.
+struct CloneSource<S>(Mutex<S>);
+
+impl<S: MetricsSource + Clone> DynMetricsSource for CloneSource<S> {
+    fn encode_openmetrics_to_string(&self) -> Result<String, Error> {
+        let source = self.0.lock().expect("poisoned").clone();
+        source.encode_openmetrics_to_string()
+    }
+}
+
+/// What a [`MetricsServer`] serves on a path.
+enum Route {
+    Metrics(Arc<dyn DynMetricsSource>),
+    Json(Arc<dyn DynMetricsSource>),
+    Liveness,
+    Readiness(Arc<dyn Fn() -> bool + Send + Sync>),
+}
+
+impl std::fmt::Debug for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Metrics(_) => f.write_str("Metrics"),
+            Self::Json(_) => f.write_str("Json"),
+            Self::Liveness => f.write_str("Liveness"),
+            Self::Readiness(_) => f.write_str("Readiness"),
+        }
+    }
+}
+
+impl Route {
+    /// Returns whether the route is subject to [`MetricsServerBuilder::bearer_auth`]
+    /// and [`MetricsServerBuilder::basic_auth`].
+    fn requires_auth(&self) -> bool {
+        matches!(self, Self::Metrics(_) | Self::Json(_))
+    }
+}
+
+/// The configuration of a running [`MetricsServer`].
+struct ServerState {
+    routes: HashMap<String, Route>,
+    auth: Option<Auth>,
+    allowlist: Vec<IpRange>,
+    tls: Option<TlsAcceptor>,
+}
+
+impl ServerState {
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allowlist.is_empty() || self.allowlist.iter().any(|range| range.contains(ip))
+    }
+}
+
+async fn server_loop(listener: TcpListener, state: Arc<ServerState>, cancel: CancellationToken) {
     let mut tasks: JoinSet<()> = JoinSet::new();
     loop {
         tokio::select! {
@@ -76,8 +309,12 @@ async fn server_loop(
             () = cancel.cancelled() => break,
             res = listener.accept() => {
                 match res {
-                    Ok((stream, _addr)) => {
-                        tasks.spawn(serve_connection(stream, registry.clone(), cancel.clone()));
+                    Ok((stream, addr)) => {
+                        if !state.is_allowed(addr.ip()) {
+                            debug!("rejected metrics connection from {addr}");
+                            continue;
+                        }
+                        tasks.spawn(accept_connection(stream, state.clone(), cancel.clone()));
                     }
                     Err(err) => {
                         error!("metrics server accept failed: {err:#}");
@@ -103,14 +340,44 @@ async fn server_loop(
     }
 }
 
-async fn serve_connection(
+/// How long a client may take to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the TLS handshake, if configured, and serves the connection.
+async fn accept_connection(
     stream: tokio::net::TcpStream,
-    registry: impl MetricsSource + Clone,
+    state: Arc<ServerState>,
+    cancel: CancellationToken,
+) {
+    let Some(acceptor) = state.tls.clone() else {
+        return serve_connection(stream, state, cancel).await;
+    };
+    let handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+    let stream = tokio::select! {
+        res = handshake => match res {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => {
+                debug!("metrics TLS handshake failed: {err:#}");
+                return;
+            }
+            Err(_) => {
+                debug!("metrics TLS handshake timed out");
+                return;
+            }
+        },
+        () = cancel.cancelled() => return,
+    };
+    serve_connection(stream, state, cancel).await
+}
+
+async fn serve_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    state: Arc<ServerState>,
     cancel: CancellationToken,
 ) {
     let io = hyper_util::rt::TokioIo::new(stream);
     let conn = hyper::server::conn::http1::Builder::new()
-        .serve_connection(io, service_fn(move |req| handler(req, registry.clone())));
+        .serve_connection(io, service_fn(move |req| handler(req, state.clone())));
     let mut conn = std::pin::pin!(conn);
     tokio::select! {
         res = conn.as_mut() => {
@@ -192,11 +459,17 @@ async fn dumper_loop(
                 return;
             }
         };
-        if let Err(err) = dump_metrics(&mut file, &start, &encoded, write_header).await {
-            error!("metrics dumper failed: {err:#}");
-            return;
+        match parse_openmetrics(&encoded) {
+            Ok(families) => {
+                if let Err(err) = dump_metrics(&mut file, &start, &families, write_header).await {
+                    error!("metrics dumper failed: {err:#}");
+                    return;
+                }
+                write_header = false;
+            }
+            // Skip this dump, the next one may succeed.
+            Err(err) => warn!("metrics dumper failed to parse metrics: {err:#}"),
         }
-        write_header = false;
         tokio::select! {
             biased;
             () = cancel.cancelled() => break,
@@ -304,6 +577,42 @@ async fn exporter_loop(
     }
 }
 
+/// Panics if the push interval of an exporter is zero, as its loop would spin.
+fn check_interval(interval: Duration, exporter: &str) {
+    assert!(
+        !interval.is_zero(),
+        "{exporter} push interval must not be zero"
+    );
+}
+
+/// Scrapes `registry` into parsed families for a push exporter.
+///
+/// Logs a warning and returns `None` if encoding or parsing fails.
+fn scrape(registry: &impl MetricsSource) -> Option<Vec<ParsedFamily>> {
+    let families = registry
+        .encode_openmetrics_to_string()
+        .map_err(|err| err.to_string())
+        .and_then(|text| parse_openmetrics(&text).map_err(|err| err.to_string()));
+    match families {
+        Ok(families) => Some(families),
+        Err(err) => {
+            warn!("failed to encode metrics: {err}");
+            None
+        }
+    }
+}
+
+/// Deserializes a push interval, rejecting zero.
+fn deserialize_interval<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    let interval = <Duration as serde::Deserialize>::deserialize(deserializer)?;
+    if interval.is_zero() {
+        return Err(serde::de::Error::custom("interval must not be zero"));
+    }
+    Ok(interval)
+}
+
 /// Builds the default rustls config used when no [`MetricsExporterConfig::tls_config`]
 /// is supplied: the ring crypto provider with the platform certificate verifier.
 fn default_tls_config() -> rustls::ClientConfig {
@@ -319,39 +628,148 @@ fn default_tls_config() -> rustls::ClientConfig {
         .with_no_client_auth()
 }
 
-/// HTTP handler that responds with the OpenMetrics encoding of the metrics.
+/// HTTP handler that dispatches requests to their [`Route`].
 #[allow(clippy::unused_async)]
 async fn handler(
-    _req: Request<hyper::body::Incoming>,
-    registry: impl MetricsSource,
+    req: Request<hyper::body::Incoming>,
+    state: Arc<ServerState>,
 ) -> Result<Response<BytesBody>, Error> {
-    let content = registry.encode_openmetrics_to_string()?;
-    let response = Response::builder()
-        .header(hyper::header::CONTENT_TYPE, "text/plain; charset=utf-8")
-        .body(body_full(content))
-        .expect("Failed to build response");
+    let Some(route) = state.routes.get(req.uri().path()) else {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    };
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        let mut response = status_response(StatusCode::METHOD_NOT_ALLOWED);
+        response
+            .headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+        return Ok(response);
+    }
+
+    let header_value = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    if let Some(auth) = state.auth.as_ref().filter(|_| route.requires_auth()) {
+        if !auth.check(header_value(header::AUTHORIZATION)) {
+            let mut response = status_response(StatusCode::UNAUTHORIZED);
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(auth.challenge()),
+            );
+            return Ok(response);
+        }
+    }
+    let gzip = accepts_gzip(header_value(header::ACCEPT_ENCODING));
+    match route {
+        Route::Metrics(source) => {
+            let format = TextFormat::from_accept(header_value(header::ACCEPT));
+            let content = format.convert(source.encode_openmetrics_to_string()?);
+            content_response(format.content_type(), content.into_bytes(), gzip)
+        }
+        Route::Json(source) => {
+            let families = parse_openmetrics(&source.encode_openmetrics_to_string()?)
+                .map_err(|err| Error::from(std::io::Error::other(err)))?;
+            let items: Vec<JsonItem<'_>> =
+                families.iter().flat_map(JsonItem::from_family).collect();
+            let content = serde_json::to_vec(&items).map_err(std::io::Error::from)?;
+            content_response("application/json", content, gzip)
+        }
+        Route::Liveness => content_response(HEALTH_CONTENT_TYPE, b"ok\n".to_vec(), false),
+        Route::Readiness(ready) => {
+            if ready() {
+                content_response(HEALTH_CONTENT_TYPE, b"ok\n".to_vec(), false)
+            } else {
+                let mut response =
+                    content_response(HEALTH_CONTENT_TYPE, b"not ready\n".to_vec(), false)?;
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                Ok(response)
+            }
+        }
+    }
+}
+
+const HEALTH_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
 
+/// Builds a `200 OK` response, gzip-compressed if `gzip` is set.
+fn content_response(
+    content_type: &'static str,
+    content: Vec<u8>,
+    gzip: bool,
+) -> Result<Response<BytesBody>, Error> {
+    let builder = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::VARY, "Accept, Accept-Encoding");
+    let response = if gzip {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&content)?;
+        builder
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(body_full(encoder.finish()?))
+    } else {
+        builder.body(body_full(content))
+    }
+    .expect("Failed to build response");
     Ok(response)
 }
 
+/// A metric item as served by [`MetricsServerBuilder::json`].
+#[derive(Debug, serde::Serialize)]
+struct JsonItem<'a> {
+    name: &'a str,
+    help: Option<&'a str>,
+    r#type: &'a str,
+    labels: BTreeMap<String, String>,
+    value: MetricValue,
+}
+
+impl<'a> JsonItem<'a> {
+    fn from_family(family: &'a ParsedFamily) -> impl Iterator<Item = Self> + 'a {
+        family.values().into_iter().map(|(labels, value)| Self {
+            name: &family.name,
+            help: family.help.as_deref(),
+            r#type: family.r#type.as_ref().map_or("unknown", MetricType::as_str),
+            labels: labels.into_iter().collect(),
+            value,
+        })
+    }
+}
+
+/// Creates an empty response with the given status.
+fn status_response(status: StatusCode) -> Response<BytesBody> {
+    let mut response = Response::new(body_full(hyper::body::Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
+
 /// Creates a new [`BytesBody`] with given content.
 fn body_full(content: impl Into<hyper::body::Bytes>) -> BytesBody {
     http_body_util::Full::new(content.into())
 }
 
 /// Dump metrics to a file.
+///
+/// Each sample becomes one column, keyed by its name and labels.
 async fn dump_metrics(
     file: &mut tokio::io::BufWriter<tokio::fs::File>,
     start: &Instant,
-    encoded: &str,
+    families: &[ParsedFamily],
     write_header: bool,
 ) -> std::io::Result<()> {
-    let m = parse_prometheus_metrics(encoded);
+    let mut m = std::collections::BTreeMap::new();
+    for sample in families.iter().flat_map(|family| &family.samples) {
+        let mut key = sample.name.clone();
+        if !sample.labels.is_empty() {
+            let labels: Vec<_> = sample
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{k}=\"{v}\""))
+                .collect();
+            key.push('{');
+            key.push_str(&labels.join(","));
+            key.push('}');
+        }
+        m.insert(key, sample.value);
+    }
     let time_since_start = start.elapsed().as_millis() as f64;
 
-    // take the keys from m and sort them
-    let mut keys: Vec<&String> = m.keys().collect();
-    keys.sort();
+    let keys: Vec<&String> = m.keys().collect();
 
     let mut metrics = String::new();
     if write_header {
@@ -439,6 +857,21 @@ mod tests {
         Arc::new(reg)
     }
 
+    /// Sends a request with the given request line and headers, and returns
+    /// the response head and body.
+    async fn request(addr: SocketAddr, head: &str) -> (String, Vec<u8>) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("{head}\r\nHost: localhost\r\nConnection: close\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        (head, response[split + 4..].to_vec())
+    }
+
     #[tokio::test]
     async fn smoke_metrics_server() {
         let server = MetricsServer::spawn("127.0.0.1:0".parse().unwrap(), registry())
@@ -464,6 +897,220 @@ mod tests {
         server.shutdown().await;
     }
 
+    #[tokio::test]
+    async fn test_metrics_server_negotiation() {
+        use std::io::Read;
+
+        let server = MetricsServer::spawn("127.0.0.1:0".parse().unwrap(), registry())
+            .await
+            .unwrap();
+        let request = async |head: &str| request(server.local_addr(), head).await;
+
+        let (head, body) = request("GET /metrics HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "head: {head}");
+        assert!(
+            head.contains("content-type: text/plain; version=0.0.4"),
+            "head: {head}"
+        );
+        let body = String::from_utf8(body).unwrap();
+        assert!(
+            body.contains("# TYPE test_count_total counter\n"),
+            "body: {body}"
+        );
+        assert!(!body.contains("# EOF"), "body: {body}");
+
+        let (head, body) = request(
+            "GET /metrics HTTP/1.1\r\nAccept: application/openmetrics-text; version=1.0.0\r\nAccept-Encoding: gzip",
+        )
+        .await;
+        assert!(
+            head.contains("content-type: application/openmetrics-text; version=1.0.0"),
+            "head: {head}"
+        );
+        assert!(head.contains("content-encoding: gzip"), "head: {head}");
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut text)
+            .unwrap();
+        assert!(text.contains("# TYPE test_count counter\n"), "body: {text}");
+        assert!(text.ends_with("# EOF\n"), "body: {text}");
+
+        let (head, _) = request("GET /other HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 404"), "head: {head}");
+        let (head, _) = request("POST /metrics HTTP/1.1\r\nContent-Length: 0").await;
+        assert!(head.starts_with("HTTP/1.1 405"), "head: {head}");
+        assert!(head.contains("allow: GET, HEAD"), "head: {head}");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_metrics_server_builder() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let mut decoder = crate::encoding::Decoder::default();
+        decoder
+            .import_openmetrics("# TYPE remote_count counter\nremote_count_total 3\n# EOF\n")
+            .unwrap();
+        let ready = Arc::new(AtomicBool::new(false));
+        let server = MetricsServer::builder()
+            .metrics("/metrics", registry())
+            .metrics("/metrics/decoded", decoder)
+            .json("/metrics.json", registry())
+            .liveness("/healthz")
+            .readiness("/readyz", {
+                let ready = ready.clone();
+                move || ready.load(Ordering::Relaxed)
+            })
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let request = async |head: &str| {
+            let (head, body) = request(server.local_addr(), head).await;
+            (head, String::from_utf8(body).unwrap())
+        };
+
+        let (_, body) = request("GET /metrics HTTP/1.1").await;
+        assert!(body.contains("test_count_total 7"), "body: {body}");
+        let (_, body) = request("GET /metrics/decoded HTTP/1.1").await;
+        assert!(body.contains("remote_count_total 3"), "body: {body}");
+        let (head, _) = request("GET / HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 404"), "head: {head}");
+
+        let (head, body) = request("GET /metrics.json HTTP/1.1").await;
+        assert!(
+            head.contains("content-type: application/json"),
+            "head: {head}"
+        );
+        assert_eq!(
+            body,
+            r#"[{"name":"test_count","help":"Smoke test counter.","type":"counter","labels":{},"value":{"Counter":7}}]"#
+        );
+
+        let (head, body) = request("GET /healthz HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "head: {head}");
+        assert_eq!(body, "ok\n");
+        let (head, _) = request("GET /readyz HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 503"), "head: {head}");
+        ready.store(true, Ordering::Relaxed);
+        let (head, _) = request("GET /readyz HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "head: {head}");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_metrics_server_access() {
+        let server = MetricsServer::builder()
+            .metrics("/metrics", registry())
+            .liveness("/healthz")
+            .bearer_auth("secret")
+            .allow_ip("127.0.0.1".parse().unwrap())
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+
+        let (head, _) = request(addr, "GET /metrics HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 401"), "head: {head}");
+        assert!(head.contains("www-authenticate: Bearer"), "head: {head}");
+        let (head, _) = request(addr, "GET /metrics HTTP/1.1\r\nAuthorization: Bearer nope").await;
+        assert!(head.starts_with("HTTP/1.1 401"), "head: {head}");
+        let (head, body) = request(
+            addr,
+            "GET /metrics HTTP/1.1\r\nAuthorization: Bearer secret",
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 200"), "head: {head}");
+        assert!(
+            String::from_utf8(body)
+                .unwrap()
+                .contains("test_count_total 7")
+        );
+        let (head, _) = request(addr, "GET /healthz HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "head: {head}");
+        server.shutdown().await;
+
+        let server = MetricsServer::builder()
+            .metrics("/metrics", registry())
+            .allow_subnet("10.0.0.0".parse().unwrap(), 8)
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut stream = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        let _ = stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await;
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_metrics_server_tls() {
+        let cert = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let cert_der = cert.cert.der().clone();
+        let key_der =
+            rustls::pki_types::PrivateKeyDer::Pkcs8(cert.signing_key.serialize_der().into());
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_config = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)
+            .unwrap();
+        let server = MetricsServer::builder()
+            .metrics("/metrics", registry())
+            .basic_auth("user", "pass")
+            .tls(server_config)
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        let client_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let client = reqwest::Client::builder()
+            .use_preconfigured_tls(client_config)
+            .resolve("localhost", server.local_addr())
+            .build()
+            .unwrap();
+        let url = format!("https://localhost:{}/metrics", server.local_addr().port());
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let res = client
+            .get(&url)
+            .basic_auth("user", Some("pass"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert!(res.text().await.unwrap().contains("test_count_total 7"));
+
+        // Clients that never start the handshake are disconnected.
+        let mut idle = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        tokio::time::pause();
+        let mut buf = [0u8; 1];
+        let read = tokio::io::AsyncReadExt::read(&mut idle, &mut buf);
+        let n = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT * 2, read)
+            .await
+            .expect("connection not closed")
+            .unwrap();
+        assert_eq!(n, 0);
+
+        server.shutdown().await;
+    }
+
     #[tokio::test]
     async fn smoke_metrics_dumper() {
         let path = std::env::temp_dir().join(format!(
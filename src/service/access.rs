@@ -0,0 +1,136 @@
+//! Access control for [`MetricsServer`](super::MetricsServer).
+
+use std::net::IpAddr;
+
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+
+/// Credentials required in the `Authorization` header.
+#[derive(Debug, Clone)]
+pub(super) struct Auth {
+    scheme: &'static str,
+    credentials: String,
+    challenge: &'static str,
+}
+
+impl Auth {
+    pub(super) fn bearer(token: &str) -> Self {
+        Self {
+            scheme: "Bearer",
+            credentials: token.to_string(),
+            challenge: "Bearer",
+        }
+    }
+
+    pub(super) fn basic(username: &str, password: &str) -> Self {
+        Self {
+            scheme: "Basic",
+            credentials: BASE64_STANDARD.encode(format!("{username}:{password}")),
+            challenge: "Basic realm=\"metrics\"",
+        }
+    }
+
+    /// Returns the value for the `WWW-Authenticate` header of rejected requests.
+    pub(super) fn challenge(&self) -> &'static str {
+        self.challenge
+    }
+
+    /// Returns whether an `Authorization` header carries the credentials.
+    pub(super) fn check(&self, authorization: Option<&str>) -> bool {
+        let Some((scheme, credentials)) = authorization.and_then(|v| v.split_once(' ')) else {
+            return false;
+        };
+        scheme.eq_ignore_ascii_case(self.scheme)
+            && constant_time_eq(credentials.trim().as_bytes(), self.credentials.as_bytes())
+    }
+}
+
+/// Compares without exiting early, to not leak the position of the first mismatch.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// A range of IP addresses in CIDR notation.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// Creates a range, clamping `prefix_len` to the length of the address.
+    ///
+    /// IPv4-mapped IPv6 networks are converted to IPv4, with the 96 bits of
+    /// the mapping removed from `prefix_len`.
+    pub(super) fn new(network: IpAddr, prefix_len: u8) -> Self {
+        let canonical = network.to_canonical();
+        let (prefix_len, max) = match (network, canonical) {
+            (IpAddr::V6(_), IpAddr::V4(_)) => (prefix_len.saturating_sub(96), 32),
+            (_, IpAddr::V4(_)) => (prefix_len, 32),
+            (_, IpAddr::V6(_)) => (prefix_len, 128),
+        };
+        Self {
+            network: canonical,
+            prefix_len: prefix_len.min(max),
+        }
+    }
+
+    /// Returns whether `ip` is in this range.
+    ///
+    /// IPv4-mapped IPv6 addresses are treated as IPv4 addresses.
+    pub(super) fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u128::from(network.to_bits()), u128::from(ip.to_bits()), 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (network.to_bits(), ip.to_bits(), 128),
+            _ => return false,
+        };
+        let prefix_len = u32::from(self.prefix_len);
+        prefix_len == 0 || (network ^ ip) >> (bits - prefix_len) == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth() {
+        let auth = Auth::basic("user", "pass");
+        assert!(auth.check(Some("Basic dXNlcjpwYXNz")));
+        assert!(auth.check(Some("basic dXNlcjpwYXNz")));
+        assert!(!auth.check(Some("Basic dXNlcjpwYXNa")));
+        assert!(!auth.check(Some("Bearer dXNlcjpwYXNz")));
+        assert!(!auth.check(None));
+
+        let auth = Auth::bearer("secret");
+        assert!(auth.check(Some("Bearer secret")));
+        assert!(!auth.check(Some("Bearer secret2")));
+        assert!(!auth.check(Some("Bearersecret")));
+    }
+
+    #[test]
+    fn test_ip_range() {
+        let range = IpRange::new("10.1.0.0".parse().unwrap(), 16);
+        assert!(range.contains("10.1.2.3".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        assert!(!range.contains("::1".parse().unwrap()));
+
+        let range = IpRange::new("::1".parse().unwrap(), 200);
+        assert!(range.contains("::1".parse().unwrap()));
+        assert!(!range.contains("::2".parse().unwrap()));
+
+        let range = IpRange::new("0.0.0.0".parse().unwrap(), 0);
+        assert!(range.contains("192.168.1.1".parse().unwrap()));
+
+        // Mapped addresses are ranges of IPv4 addresses.
+        let range = IpRange::new("::ffff:127.0.0.1".parse().unwrap(), 128);
+        assert!(range.contains("127.0.0.1".parse().unwrap()));
+        assert!(range.contains("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!range.contains("127.0.0.2".parse().unwrap()));
+        let range = IpRange::new("::ffff:10.1.0.0".parse().unwrap(), 112);
+        assert!(range.contains("10.1.2.3".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,257 @@
+//! Content negotiation for [`MetricsServer`](super::MetricsServer).
+
+use std::collections::HashMap;
+
+/// The content type of the OpenMetrics text format.
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// The content type of the Prometheus text format.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// A text exposition format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum TextFormat {
+    /// OpenMetrics 1.0.0, as written by [`MetricsSource`](crate::MetricsSource).
+    OpenMetrics,
+    /// The Prometheus text format 0.0.4.
+    Prometheus,
+}
+
+impl TextFormat {
+    /// Picks the format preferred by an `Accept` header.
+    ///
+    /// OpenMetrics is only served if asked for explicitly, and wins ties
+    /// with `text/plain`.
+    pub(super) fn from_accept(accept: Option<&str>) -> Self {
+        let mut openmetrics = 0.0;
+        let mut prometheus = 0.0;
+        for (media_type, q) in accept.into_iter().flat_map(parse_weighted) {
+            if media_type.eq_ignore_ascii_case("application/openmetrics-text") {
+                openmetrics = f32::max(openmetrics, q);
+            } else if ["text/plain", "text/*", "*/*"]
+                .iter()
+                .any(|ty| media_type.eq_ignore_ascii_case(ty))
+            {
+                prometheus = f32::max(prometheus, q);
+            }
+        }
+        if openmetrics > 0.0 && openmetrics >= prometheus {
+            Self::OpenMetrics
+        } else {
+            Self::Prometheus
+        }
+    }
+
+    /// Returns the value of the `Content-Type` header for this format.
+    pub(super) fn content_type(self) -> &'static str {
+        match self {
+            Self::OpenMetrics => OPENMETRICS_CONTENT_TYPE,
+            Self::Prometheus => PROMETHEUS_CONTENT_TYPE,
+        }
+    }
+
+    /// Converts OpenMetrics text into this format.
+    pub(super) fn convert(self, openmetrics: String) -> String {
+        match self {
+            Self::OpenMetrics => openmetrics,
+            Self::Prometheus => openmetrics_to_prometheus(&openmetrics),
+        }
+    }
+}
+
+/// Returns whether an `Accept-Encoding` header allows a gzip-compressed body.
+pub(super) fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    accept_encoding
+        .into_iter()
+        .flat_map(parse_weighted)
+        .any(|(coding, q)| q > 0.0 && (coding.eq_ignore_ascii_case("gzip") || coding == "*"))
+}
+
+/// Parses a comma-separated header of values with optional `q` weights.
+///
+/// Other parameters are ignored. Values without a valid weight get `1.0`.
+fn parse_weighted(header: &str) -> impl Iterator<Item = (&str, f32)> {
+    header.split(',').filter_map(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        let value = parts.next().filter(|v| !v.is_empty())?;
+        let q = parts
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, q)| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        Some((value, q))
+    })
+}
+
+/// Converts OpenMetrics text into the Prometheus text format 0.0.4.
+///
+/// Counter and info families are named after their samples, `_created`
+/// samples, exemplars, `# UNIT` and `# EOF` lines are dropped, and types the
+/// Prometheus format lacks are mapped to `gauge` or `untyped`.
+fn openmetrics_to_prometheus(text: &str) -> String {
+    // `# HELP` lines precede the `# TYPE` line of their family.
+    let types: HashMap<&str, &str> = text
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE ")?.split_once(' '))
+        .collect();
+
+    let mut out = String::with_capacity(text.len());
+    for line in text.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let (kind, rest) = comment.split_once(' ').unwrap_or((comment, ""));
+            if kind != "HELP" && kind != "TYPE" {
+                continue;
+            }
+            let (name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            out.push_str("# ");
+            out.push_str(kind);
+            out.push(' ');
+            out.push_str(name);
+            // Prometheus names these families after their samples.
+            let suffix = match types.get(name) {
+                Some(&"counter") => "_total",
+                Some(&"info") => "_info",
+                _ => "",
+            };
+            if !name.ends_with(suffix) {
+                out.push_str(suffix);
+            }
+            out.push(' ');
+            if kind == "TYPE" {
+                out.push_str(prometheus_type(rest));
+            } else {
+                // Quotes are not escaped in Prometheus help texts.
+                out.push_str(&rest.replace("\\\"", "\""));
+            }
+            out.push('\n');
+            continue;
+        }
+
+        let sample = strip_exemplar(line);
+        let name_end = sample.find(['{', ' ']).unwrap_or(sample.len());
+        let is_created = sample[..name_end]
+            .strip_suffix("_created")
+            .and_then(|family| types.get(family))
+            .is_some_and(|ty| matches!(*ty, "counter" | "histogram" | "summary"));
+        if !is_created {
+            out.push_str(sample);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn prometheus_type(openmetrics_type: &str) -> &str {
+    match openmetrics_type {
+        "counter" | "gauge" | "histogram" | "summary" => openmetrics_type,
+        "info" | "stateset" => "gauge",
+        _ => "untyped",
+    }
+}
+
+/// Removes the exemplar, if any, from a sample line.
+fn strip_exemplar(line: &str) -> &str {
+    // Skip the label set, whose values may contain ` # `.
+    let mut values_start = 0;
+    if let Some(open) = line.find('{').filter(|open| !line[..*open].contains(' ')) {
+        let mut in_quotes = false;
+        let mut escaped = false;
+        for (i, c) in line[open..].char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_quotes = !in_quotes,
+                '}' if !in_quotes => {
+                    values_start = open + i;
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    match line[values_start..].find(" # ") {
+        Some(pos) => &line[..values_start + pos],
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_accept() {
+        assert_eq!(TextFormat::from_accept(None), TextFormat::Prometheus);
+        assert_eq!(TextFormat::from_accept(Some("*/*")), TextFormat::Prometheus);
+        assert_eq!(
+            TextFormat::from_accept(Some("application/openmetrics-text; version=1.0.0")),
+            TextFormat::OpenMetrics
+        );
+        // What Prometheus sends by default.
+        assert_eq!(
+            TextFormat::from_accept(Some(
+                "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+            )),
+            TextFormat::OpenMetrics
+        );
+        assert_eq!(
+            TextFormat::from_accept(Some(
+                "application/openmetrics-text;q=0.2,text/plain;version=0.0.4"
+            )),
+            TextFormat::Prometheus
+        );
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        assert!(!accepts_gzip(None));
+        assert!(accepts_gzip(Some("gzip")));
+        assert!(accepts_gzip(Some("br;q=1.0, gzip;q=0.8")));
+        assert!(accepts_gzip(Some("*")));
+        assert!(!accepts_gzip(Some("gzip;q=0, identity")));
+    }
+
+    #[test]
+    fn test_openmetrics_to_prometheus() {
+        let openmetrics = r#"# HELP requests Requests "served".
+# TYPE requests counter
+# UNIT requests requests
+requests_total{path="a # b"} 3 # {trace_id="abc"} 1.0 1600000000.0
+requests_created{path="a # b"} 1600000000
+# HELP sent_total Bytes sent.
+# TYPE sent_total counter
+sent_total 10
+# HELP latency Latency.
+# TYPE latency histogram
+latency_bucket{le="1.0"} 1 # {trace_id="def"} 0.5
+latency_bucket{le="+Inf"} 1
+latency_sum 0.5
+latency_count 1
+# TYPE build info
+build_info{version="1.0"} 1
+# TYPE state unknown
+state 2
+# EOF
+"#;
+        let expected = r#"# HELP requests_total Requests "served".
+# TYPE requests_total counter
+requests_total{path="a # b"} 3
+# HELP sent_total Bytes sent.
+# TYPE sent_total counter
+sent_total 10
+# HELP latency Latency.
+# TYPE latency histogram
+latency_bucket{le="1.0"} 1
+latency_bucket{le="+Inf"} 1
+latency_sum 0.5
+latency_count 1
+# TYPE build_info gauge
+build_info{version="1.0"} 1
+# TYPE state untyped
+state 2
+"#;
+        assert_eq!(
+            TextFormat::Prometheus.convert(openmetrics.replace("\"served\"", "\\\"served\\\"")),
+            expected
+        );
+    }
+}
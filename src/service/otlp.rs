@@ -0,0 +1,611 @@
+//! Push exporter for the OpenTelemetry protocol (OTLP) over HTTP/protobuf.
+//!
+//! The message and field numbers follow `opentelemetry/proto/metrics/v1/metrics.proto`
+//! and `opentelemetry/proto/collector/metrics/v1/metrics_service.proto`.
+
+use std::time::{Duration, SystemTime};
+
+use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
+use tracing::{debug, warn};
+
+use super::{check_interval, default_tls_config, deserialize_interval, proto::ProtoWriter, scrape};
+use crate::{MetricType, MetricValue, MetricsSource, encoding::ParsedFamily};
+
+/// `AggregationTemporality::AGGREGATION_TEMPORALITY_CUMULATIVE`.
+const CUMULATIVE: u64 = 2;
+
+/// Periodic exporter that pushes metrics to an OTLP collector.
+///
+/// Counters are exported as monotonic cumulative sums, gauges and untyped
+/// metrics as gauges, histograms as explicit-bucket histograms and summaries
+/// as summaries. Labels become data point attributes. Infos are exported as
+/// gauges with the value 1, and state sets as one gauge point per state.
+/// Gauge histograms are exported as cumulative histograms, whose counts may
+/// decrease between pushes.
+///
+/// Aborts the background task on drop. For an orderly shutdown that lets
+/// the in-flight push finish, call [`shutdown`](Self::shutdown).
+#[derive(Debug)]
+pub struct OtlpExporter {
+    cancel: CancellationToken,
+    task: AbortOnDropHandle<()>,
+}
+
+impl OtlpExporter {
+    /// Spawns the OTLP exporter in a background task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the [`interval`](OtlpExporterConfig::interval) is zero.
+    #[allow(clippy::unused_async)]
+    pub async fn spawn(cfg: OtlpExporterConfig, registry: impl MetricsSource) -> Self {
+        check_interval(cfg.interval, "OTLP");
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn(otlp_loop(cfg, registry, cancel.clone()));
+        Self {
+            cancel,
+            task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    /// Gracefully shuts down the exporter.
+    ///
+    /// Stops between push cycles, letting the current push finish before
+    /// returning. Wrap in [`tokio::time::timeout`] to bound the wait.
+    pub async fn shutdown(self) {
+        self.cancel.cancel();
+        let _ = self.task.await;
+    }
+}
+
+/// Configuration for pushing metrics to an OTLP collector.
+#[derive(Debug, serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct OtlpExporterConfig {
+    /// The push interval.
+    ///
+    /// Must not be zero. Defaults to 60 seconds, the default of the
+    /// OpenTelemetry SDKs.
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub interval: Duration,
+    /// The OTLP/HTTP metrics endpoint, e.g. `http://localhost:4318/v1/metrics`.
+    pub endpoint: String,
+    /// Exported as the `service.name` resource attribute.
+    pub service_name: String,
+    /// Exported as the `service.instance.id` resource attribute.
+    ///
+    /// This should be device-unique, see
+    /// [`MetricsExporterConfig::instance_name`](super::MetricsExporterConfig::instance_name).
+    pub instance_name: String,
+    /// Additional HTTP headers sent with every request, e.g. for authentication.
+    pub headers: Vec<(String, String)>,
+    /// Custom rustls [`ClientConfig`] for the push client.
+    ///
+    /// If `None`, a default config is used: the ring crypto provider with
+    /// the platform certificate verifier.
+    ///
+    /// [`ClientConfig`]: rustls::ClientConfig
+    #[serde(skip)]
+    pub tls_config: Option<rustls::ClientConfig>,
+}
+
+impl Default for OtlpExporterConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            endpoint: String::new(),
+            service_name: String::new(),
+            instance_name: String::new(),
+            headers: Vec::new(),
+            tls_config: None,
+        }
+    }
+}
+
+async fn otlp_loop(
+    cfg: OtlpExporterConfig,
+    registry: impl MetricsSource,
+    cancel: CancellationToken,
+) {
+    let OtlpExporterConfig {
+        interval,
+        endpoint,
+        service_name,
+        instance_name,
+        headers,
+        tls_config,
+    } = cfg;
+
+    let tls = tls_config.unwrap_or_else(default_tls_config);
+    let push_client = reqwest::Client::builder()
+        .use_preconfigured_tls(tls)
+        .build()
+        .expect("reqwest client builder failed");
+
+    let resource = [
+        ("service.name", service_name.as_str()),
+        ("service.instance.id", instance_name.as_str()),
+    ];
+    let start_time = unix_nanos();
+    loop {
+        tokio::select! {
+            biased;
+            () = cancel.cancelled() => break,
+            () = tokio::time::sleep(interval) => {}
+        }
+
+        let Some(families) = scrape(&registry) else {
+            continue;
+        };
+        let body = encode_request(&families, &resource, start_time, unix_nanos());
+
+        let mut req = push_client
+            .post(&endpoint)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf");
+        for (name, value) in &headers {
+            req = req.header(name, value);
+        }
+        let res = match req.body(body).send().await {
+            Ok(res) => res,
+            Err(err) => {
+                warn!("failed to push metrics: {err}");
+                continue;
+            }
+        };
+        let status = res.status();
+        if status.is_success() {
+            debug!("pushed metrics to OTLP collector");
+        } else {
+            match res.text().await {
+                Ok(body) => warn!("failed to push metrics to OTLP collector: {status} {body}"),
+                Err(err) => warn!(
+                    "failed to push metrics to OTLP collector: {status}; reading body failed: {err:#}"
+                ),
+            }
+        }
+    }
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Encodes an `ExportMetricsServiceRequest` with a single resource and scope.
+fn encode_request(
+    families: &[ParsedFamily],
+    resource: &[(&str, &str)],
+    start_time: u64,
+    time: u64,
+) -> Vec<u8> {
+    let mut w = ProtoWriter::default();
+    // ExportMetricsServiceRequest.resource_metrics
+    w.message(1, |w| {
+        // ResourceMetrics.resource
+        w.message(1, |w| {
+            for (key, value) in resource {
+                // Resource.attributes
+                encode_attribute(w, 1, key, value);
+            }
+        });
+        // ResourceMetrics.scope_metrics
+        w.message(2, |w| {
+            // ScopeMetrics.scope
+            w.message(1, |w| {
+                w.string(1, env!("CARGO_PKG_NAME"));
+                w.string(2, env!("CARGO_PKG_VERSION"));
+            });
+            for family in families {
+                // ScopeMetrics.metrics
+                w.message(2, |w| encode_metric(w, family, start_time, time));
+            }
+        });
+    });
+    w.into_bytes()
+}
+
+fn encode_metric(w: &mut ProtoWriter, family: &ParsedFamily, start_time: u64, time: u64) {
+    w.string(1, &family.name);
+    if let Some(help) = &family.help {
+        w.string(2, help);
+    }
+    if let Some(unit) = &family.unit {
+        w.string(3, unit);
+    }
+    match family.r#type {
+        Some(MetricType::Counter) => {
+            // Metric.sum
+            w.message(7, |w| {
+                let total = format!("{}_total", family.name);
+                for sample in &family.samples {
+                    if sample.name == total || sample.name == family.name {
+                        encode_number_point(w, &sample.labels, sample.value, start_time, time);
+                    }
+                }
+                w.uint64(2, CUMULATIVE);
+                w.bool(3, true);
+            });
+        }
+        Some(MetricType::Histogram | MetricType::GaugeHistogram) => {
+            // Metric.histogram
+            w.message(9, |w| {
+                for (labels, value) in family.values() {
+                    if let MetricValue::Histogram {
+                        buckets,
+                        sum,
+                        count,
+                    }
+                    | MetricValue::GaugeHistogram {
+                        buckets,
+                        sum,
+                        count,
+                    } = value
+                    {
+                        encode_histogram_point(w, &labels, &buckets, sum, count, start_time, time);
+                    }
+                }
+                w.uint64(2, CUMULATIVE);
+            });
+        }
+        Some(MetricType::Summary) => {
+            // Metric.summary
+            w.message(11, |w| {
+                for (labels, value) in family.values() {
+                    if let MetricValue::Summary {
+                        quantiles,
+                        sum,
+                        count,
+                    } = value
+                    {
+                        // Summary.data_points
+                        w.message(1, |w| {
+                            encode_attributes(w, 7, &labels);
+                            w.fixed64(2, start_time);
+                            w.fixed64(3, time);
+                            w.fixed64(4, count);
+                            w.double(5, sum);
+                            for (q, v) in quantiles {
+                                w.message(6, |w| {
+                                    w.double(1, q);
+                                    w.double(2, v);
+                                });
+                            }
+                        });
+                    }
+                }
+            });
+        }
+        Some(MetricType::Info) => {
+            // Metric.gauge, the info is in the attributes.
+            w.message(5, |w| {
+                for (labels, _) in family.values() {
+                    encode_number_point(w, &labels, 1.0, start_time, time);
+                }
+            });
+        }
+        _ => {
+            // Metric.gauge
+            w.message(5, |w| {
+                for sample in &family.samples {
+                    if sample.name == family.name {
+                        encode_number_point(w, &sample.labels, sample.value, start_time, time);
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Writes a `NumberDataPoint` as field 1 of a `Sum` or `Gauge`.
+fn encode_number_point(
+    w: &mut ProtoWriter,
+    labels: &[(String, String)],
+    value: f64,
+    start_time: u64,
+    time: u64,
+) {
+    w.message(1, |w| {
+        encode_attributes(w, 7, labels);
+        w.fixed64(2, start_time);
+        w.fixed64(3, time);
+        w.double(4, value);
+    });
+}
+
+/// Writes a `HistogramDataPoint` as field 1 of a `Histogram`.
+///
+/// OTLP bucket counts are per bucket while the OpenMetrics ones are
+/// cumulative, and the implicit `+Inf` bound is not listed.
+fn encode_histogram_point(
+    w: &mut ProtoWriter,
+    labels: &[(String, String)],
+    buckets: &[(f64, u64)],
+    sum: f64,
+    count: u64,
+    start_time: u64,
+    time: u64,
+) {
+    w.message(1, |w| {
+        encode_attributes(w, 9, labels);
+        w.fixed64(2, start_time);
+        w.fixed64(3, time);
+        w.fixed64(4, count);
+        w.double(5, sum);
+        let mut prev = 0;
+        let mut counts: Vec<u64> = buckets
+            .iter()
+            .map(|(_, cumulative)| {
+                let count = cumulative.saturating_sub(prev);
+                prev = *cumulative;
+                count
+            })
+            .collect();
+        let bounds: Vec<f64> = buckets
+            .iter()
+            .map(|(le, _)| *le)
+            .filter(|le| le.is_finite())
+            .collect();
+        if counts.len() == bounds.len() {
+            // No `+Inf` bucket was exported, derive it from the count.
+            counts.push(count.saturating_sub(prev));
+        }
+        w.packed_fixed64(6, counts);
+        w.packed_double(7, bounds);
+    });
+}
+
+fn encode_attributes(w: &mut ProtoWriter, field: u32, labels: &[(String, String)]) {
+    for (key, value) in labels {
+        encode_attribute(w, field, key, value);
+    }
+}
+
+/// Writes a `KeyValue` with a string `AnyValue`.
+fn encode_attribute(w: &mut ProtoWriter, field: u32, key: &str, value: &str) {
+    w.message(field, |w| {
+        w.string(1, key);
+        w.message(2, |w| w.string(1, value));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use http_body_util::{BodyExt, Full};
+    use hyper::{Request, Response, body::Incoming, service::service_fn};
+    use hyper_util::rt::TokioIo;
+
+    use super::*;
+    use crate::{
+        Counter, Family, Gauge, Histogram, LabelPair, LabelValue, Registry,
+        encoding::parse_openmetrics,
+        labels::EncodeLabelSet,
+        service::proto::{Field, decode},
+    };
+
+    #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+    struct Method(&'static str);
+
+    impl EncodeLabelSet for Method {
+        fn encode_label_pairs(&self) -> Vec<LabelPair<'_>> {
+            vec![("method", LabelValue::from(self.0))]
+        }
+    }
+
+    #[derive(Debug, crate::MetricsGroup)]
+    #[metrics(default, name = "test")]
+    struct TestMetrics {
+        /// Requests served
+        requests: Family<Method, Counter>,
+        /// Open connections
+        connections: Gauge,
+        /// Request latency
+        #[default(Histogram::new(vec![0.1, 1.0]))]
+        latency: Histogram,
+    }
+
+    /// Returns the fields with number `field`.
+    fn get(fields: &[(u32, Field)], field: u32) -> Vec<&Field> {
+        fields
+            .iter()
+            .filter(|(f, _)| *f == field)
+            .map(|(_, v)| v)
+            .collect()
+    }
+
+    fn attributes(fields: &[(u32, Field)], field: u32) -> Vec<(String, String)> {
+        get(fields, field)
+            .into_iter()
+            .map(|kv| {
+                let kv = kv.message();
+                let value = get(&get(&kv, 2)[0].message(), 1)[0].string();
+                (get(&kv, 1)[0].string(), value)
+            })
+            .collect()
+    }
+
+    /// Encodes `text` and returns the `Metric` messages.
+    fn encode_text(text: &str) -> Vec<Vec<(u32, Field)>> {
+        let body = encode_request(&parse_openmetrics(text).unwrap(), &[], 1, 2);
+        let request = decode(&body);
+        let resource_metrics = get(&request, 1)[0].message();
+        let scope_metrics = get(&resource_metrics, 2)[0].message();
+        get(&scope_metrics, 2)
+            .into_iter()
+            .map(|m| m.message())
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_info() {
+        let metrics = encode_text(
+            "# TYPE build info
+build_info{instance=\"a\",version=\"1.0\"} 1
+build_info{instance=\"b\",version=\"1.1\"} 1
+",
+        );
+        assert_eq!(get(&metrics[0], 1)[0].string(), "build");
+        let gauge = get(&metrics[0], 5)[0].message();
+        let points: Vec<_> = get(&gauge, 1).into_iter().map(|p| p.message()).collect();
+        assert_eq!(points.len(), 2);
+        assert_eq!(
+            attributes(&points[1], 7),
+            [
+                ("instance".to_string(), "b".to_string()),
+                ("version".to_string(), "1.1".to_string())
+            ]
+        );
+        assert_eq!(get(&points[1], 4)[0].double(), 1.0);
+    }
+
+    #[test]
+    fn test_encode_gauge_histogram() {
+        let metrics = encode_text(
+            "# TYPE queue_age gaugehistogram
+queue_age_bucket{le=\"1.0\"} 2
+queue_age_bucket{le=\"+Inf\"} 3
+queue_age_gsum 4.5
+queue_age_gcount 3
+",
+        );
+        let histogram = get(&metrics[0], 9)[0].message();
+        assert_eq!(get(&histogram, 2), [&Field::Varint(CUMULATIVE)]);
+        let point = get(&histogram, 1)[0].message();
+        assert_eq!(get(&point, 4), [&Field::Fixed64(3)]);
+        assert_eq!(get(&point, 5)[0].double(), 4.5);
+        let Field::Bytes(counts) = get(&point, 6)[0] else {
+            panic!("bucket counts not packed");
+        };
+        let counts: Vec<_> = counts
+            .chunks(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(counts, [2, 1]);
+    }
+
+    #[test]
+    fn test_config_interval() {
+        assert_eq!(
+            OtlpExporterConfig::default().interval,
+            Duration::from_secs(60)
+        );
+        let cfg: OtlpExporterConfig =
+            serde_json::from_str(r#"{"endpoint": "http://localhost:4318/v1/metrics"}"#).unwrap();
+        assert_eq!(cfg.interval, Duration::from_secs(60));
+        let zero = r#"{"interval": {"secs": 0, "nanos": 0}}"#;
+        assert!(serde_json::from_str::<OtlpExporterConfig>(zero).is_err());
+    }
+
+    #[tokio::test]
+    async fn smoke_otlp_exporter() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<(String, String, Vec<u8>)>();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let io = TokioIo::new(stream);
+            let service = service_fn(move |req: Request<Incoming>| {
+                let tx = tx.clone();
+                async move {
+                    let path = req.uri().path().to_string();
+                    let content_type = req.headers()[hyper::header::CONTENT_TYPE]
+                        .to_str()
+                        .unwrap()
+                        .to_string();
+                    let body = req.into_body().collect().await.unwrap().to_bytes();
+                    if let Some(tx) = tx.lock().expect("poisoned").take() {
+                        let _ = tx.send((path, content_type, body.to_vec()));
+                    }
+                    Ok::<_, std::convert::Infallible>(Response::new(Full::new(
+                        hyper::body::Bytes::new(),
+                    )))
+                }
+            });
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(io, service)
+                .await;
+        });
+
+        let metrics = Arc::new(TestMetrics::default());
+        metrics.requests.get_or_create(&Method("GET")).inc_by(7);
+        metrics.connections.set(-3);
+        for v in [0.05, 0.5, 0.7, 5.0] {
+            metrics.latency.observe(v);
+        }
+        let mut registry = Registry::default();
+        registry.register(metrics);
+
+        let cfg = OtlpExporterConfig {
+            interval: Duration::from_millis(50),
+            endpoint: format!("http://{addr}/v1/metrics"),
+            service_name: "svc".to_string(),
+            instance_name: "inst".to_string(),
+            ..Default::default()
+        };
+        let exporter = OtlpExporter::spawn(cfg, Arc::new(registry)).await;
+
+        let (path, content_type, body) = tokio::time::timeout(Duration::from_secs(5), rx)
+            .await
+            .expect("timeout waiting for push")
+            .expect("oneshot dropped");
+        exporter.shutdown().await;
+
+        assert_eq!(path, "/v1/metrics");
+        assert_eq!(content_type, "application/x-protobuf");
+
+        let request = decode(&body);
+        let resource_metrics = get(&request, 1)[0].message();
+        let resource = get(&resource_metrics, 1)[0].message();
+        assert_eq!(
+            attributes(&resource, 1),
+            [
+                ("service.name".to_string(), "svc".to_string()),
+                ("service.instance.id".to_string(), "inst".to_string())
+            ]
+        );
+        let scope_metrics = get(&resource_metrics, 2)[0].message();
+        let metrics: Vec<_> = get(&scope_metrics, 2)
+            .into_iter()
+            .map(|m| m.message())
+            .collect();
+        let names: Vec<_> = metrics.iter().map(|m| get(m, 1)[0].string()).collect();
+        assert_eq!(names, ["test_connections", "test_latency", "test_requests"]);
+
+        // Gauge
+        let gauge = get(&metrics[0], 5)[0].message();
+        let point = get(&gauge, 1)[0].message();
+        assert_eq!(get(&point, 4)[0].double(), -3.0);
+
+        // Histogram with per-bucket counts
+        let histogram = get(&metrics[1], 9)[0].message();
+        assert_eq!(get(&histogram, 2), [&Field::Varint(CUMULATIVE)]);
+        let point = get(&histogram, 1)[0].message();
+        assert_eq!(get(&point, 4), [&Field::Fixed64(4)]);
+        assert_eq!(get(&point, 5)[0].double(), 6.25);
+        let Field::Bytes(counts) = get(&point, 6)[0] else {
+            panic!("bucket counts not packed");
+        };
+        let counts: Vec<_> = counts
+            .chunks(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(counts, [1, 2, 1]);
+
+        // Counter as monotonic cumulative sum with labels as attributes
+        assert_eq!(get(&metrics[2], 2)[0].string(), "Requests served.");
+        let sum = get(&metrics[2], 7)[0].message();
+        assert_eq!(get(&sum, 2), [&Field::Varint(CUMULATIVE)]);
+        assert_eq!(get(&sum, 3), [&Field::Varint(1)]);
+        let point = get(&sum, 1)[0].message();
+        assert_eq!(
+            attributes(&point, 7),
+            [("method".to_string(), "GET".to_string())]
+        );
+        assert_eq!(get(&point, 4)[0].double(), 7.0);
+    }
+}
@@ -0,0 +1,160 @@
+//! Minimal protobuf wire-format encoder for the push exporters.
+//!
+//! Only what the OTLP and remote-write messages need is implemented. Field
+//! numbers are written by the callers, next to the message definitions they
+//! come from.
+
+/// Protobuf wire types.
+const VARINT: u32 = 0;
+const FIXED64: u32 = 1;
+const LEN: u32 = 2;
+
+/// Appends protobuf fields to a byte buffer.
+#[derive(Debug, Default)]
+pub(super) struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    pub(super) fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+
+    fn tag(&mut self, field: u32, wire_type: u32) {
+        self.varint(u64::from((field << 3) | wire_type));
+    }
+
+    pub(super) fn uint64(&mut self, field: u32, v: u64) {
+        self.tag(field, VARINT);
+        self.varint(v);
+    }
+
+    pub(super) fn int64(&mut self, field: u32, v: i64) {
+        self.uint64(field, v as u64);
+    }
+
+    pub(super) fn bool(&mut self, field: u32, v: bool) {
+        self.uint64(field, u64::from(v));
+    }
+
+    pub(super) fn fixed64(&mut self, field: u32, v: u64) {
+        self.tag(field, FIXED64);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(super) fn double(&mut self, field: u32, v: f64) {
+        self.fixed64(field, v.to_bits());
+    }
+
+    pub(super) fn bytes(&mut self, field: u32, v: &[u8]) {
+        self.tag(field, LEN);
+        self.varint(v.len() as u64);
+        self.buf.extend_from_slice(v);
+    }
+
+    pub(super) fn string(&mut self, field: u32, v: &str) {
+        self.bytes(field, v.as_bytes());
+    }
+
+    /// Writes a nested message built by `f`.
+    pub(super) fn message(&mut self, field: u32, f: impl FnOnce(&mut ProtoWriter)) {
+        let mut inner = ProtoWriter::default();
+        f(&mut inner);
+        self.bytes(field, &inner.buf);
+    }
+
+    pub(super) fn packed_fixed64(&mut self, field: u32, values: impl IntoIterator<Item = u64>) {
+        let mut inner = ProtoWriter::default();
+        for v in values {
+            inner.buf.extend_from_slice(&v.to_le_bytes());
+        }
+        self.bytes(field, &inner.buf);
+    }
+
+    pub(super) fn packed_double(&mut self, field: u32, values: impl IntoIterator<Item = f64>) {
+        self.packed_fixed64(field, values.into_iter().map(f64::to_bits));
+    }
+}
+
+/// A decoded protobuf field value, for inspecting encoded messages in tests.
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Field {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(Vec<u8>),
+}
+
+#[cfg(test)]
+impl Field {
+    /// Decodes the fields of a nested message.
+    pub(super) fn message(&self) -> Vec<(u32, Field)> {
+        match self {
+            Field::Bytes(b) => decode(b),
+            _ => panic!("not a message: {self:?}"),
+        }
+    }
+
+    pub(super) fn string(&self) -> String {
+        match self {
+            Field::Bytes(b) => String::from_utf8(b.clone()).unwrap(),
+            _ => panic!("not a string: {self:?}"),
+        }
+    }
+
+    pub(super) fn double(&self) -> f64 {
+        match self {
+            Field::Fixed64(v) => f64::from_bits(*v),
+            _ => panic!("not a double: {self:?}"),
+        }
+    }
+}
+
+/// Decodes a message into its `(field number, value)` pairs.
+#[cfg(test)]
+pub(super) fn decode(mut buf: &[u8]) -> Vec<(u32, Field)> {
+    fn varint(buf: &mut &[u8]) -> u64 {
+        let mut v = 0;
+        let mut shift = 0;
+        loop {
+            let b = buf[0];
+            *buf = &buf[1..];
+            v |= u64::from(b & 0x7f) << shift;
+            if b < 0x80 {
+                return v;
+            }
+            shift += 7;
+        }
+    }
+
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let tag = varint(&mut buf);
+        let field = (tag >> 3) as u32;
+        let value = match (tag & 7) as u32 {
+            VARINT => Field::Varint(varint(&mut buf)),
+            FIXED64 => {
+                let (v, rest) = buf.split_at(8);
+                buf = rest;
+                Field::Fixed64(u64::from_le_bytes(v.try_into().unwrap()))
+            }
+            LEN => {
+                let len = varint(&mut buf) as usize;
+                let (v, rest) = buf.split_at(len);
+                buf = rest;
+                Field::Bytes(v.to_vec())
+            }
+            wire_type => panic!("unsupported wire type {wire_type}"),
+        };
+        fields.push((field, value));
+    }
+    fields
+}
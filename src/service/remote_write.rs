@@ -0,0 +1,497 @@
+//! Push exporter for the Prometheus remote-write protocol (version 1.0).
+//!
+//! The message and field numbers follow `prometheus/prompb/remote.proto` and
+//! `prometheus/prompb/types.proto`.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
+use tracing::{debug, warn};
+
+use super::{check_interval, default_tls_config, deserialize_interval, proto::ProtoWriter, scrape};
+use crate::{MetricsSource, encoding::ParsedFamily};
+
+/// Delay before the first retry of a failed request, doubled for every further retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Periodic exporter that pushes metrics to a Prometheus remote-write endpoint.
+///
+/// Every interval the metrics are scraped into a snappy-compressed
+/// `WriteRequest`, with one time series per sample. Series are labeled with
+/// `job` and `instance` from the config, unless they already have labels of
+/// these names. Requests that fail with a network
+/// error, a `5xx` or a `429` status are retried and, if that is not enough,
+/// kept in a bounded queue and sent in order once the endpoint is reachable
+/// again. When the queue is full, the oldest request is dropped.
+///
+/// Aborts the background task on drop. For an orderly shutdown that lets
+/// the in-flight push finish, call [`shutdown`](Self::shutdown).
+#[derive(Debug)]
+pub struct RemoteWriteExporter {
+    cancel: CancellationToken,
+    task: AbortOnDropHandle<()>,
+}
+
+impl RemoteWriteExporter {
+    /// Spawns the remote-write exporter in a background task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the [`interval`](RemoteWriteConfig::interval) is zero.
+    #[allow(clippy::unused_async)]
+    pub async fn spawn(cfg: RemoteWriteConfig, registry: impl MetricsSource) -> Self {
+        check_interval(cfg.interval, "Remote-write");
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn(remote_write_loop(cfg, registry, cancel.clone()));
+        Self {
+            cancel,
+            task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    /// Gracefully shuts down the exporter.
+    ///
+    /// Stops between push cycles, letting the current push finish before
+    /// returning. Requests still queued are dropped. Wrap in
+    /// [`tokio::time::timeout`] to bound the wait.
+    pub async fn shutdown(self) {
+        self.cancel.cancel();
+        let _ = self.task.await;
+    }
+}
+
+/// Configuration for pushing metrics to a Prometheus remote-write endpoint.
+#[derive(Debug, serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct RemoteWriteConfig {
+    /// The push interval.
+    ///
+    /// Must not be zero.
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub interval: Duration,
+    /// The remote-write endpoint, e.g. `http://localhost:9090/api/v1/write`.
+    pub endpoint: String,
+    /// Added to all series without a `job` label as the `job` label.
+    pub service_name: String,
+    /// Added to all series without an `instance` label as the `instance` label.
+    ///
+    /// This should be device-unique, see
+    /// [`MetricsExporterConfig::instance_name`](super::MetricsExporterConfig::instance_name).
+    pub instance_name: String,
+    /// The username for basic auth.
+    pub username: Option<String>,
+    /// The password for basic auth.
+    pub password: String,
+    /// How often a failed request is retried before it is queued.
+    pub max_retries: u32,
+    /// The number of requests kept while the endpoint is unreachable.
+    pub max_queue_len: usize,
+    /// Custom rustls [`ClientConfig`] for the push client.
+    ///
+    /// If `None`, a default config is used: the ring crypto provider with
+    /// the platform certificate verifier.
+    ///
+    /// [`ClientConfig`]: rustls::ClientConfig
+    #[serde(skip)]
+    pub tls_config: Option<rustls::ClientConfig>,
+}
+
+impl Default for RemoteWriteConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            endpoint: String::new(),
+            service_name: String::new(),
+            instance_name: String::new(),
+            username: None,
+            password: String::new(),
+            max_retries: 3,
+            max_queue_len: 64,
+            tls_config: None,
+        }
+    }
+}
+
+/// Outcome of a failed send.
+enum SendError {
+    /// The endpoint may accept the request later.
+    Retryable,
+    /// The endpoint rejected the request, sending it again won't help.
+    Rejected,
+}
+
+async fn remote_write_loop(
+    cfg: RemoteWriteConfig,
+    registry: impl MetricsSource,
+    cancel: CancellationToken,
+) {
+    let tls = cfg.tls_config.clone().unwrap_or_else(default_tls_config);
+    let push_client = reqwest::Client::builder()
+        .use_preconfigured_tls(tls)
+        .build()
+        .expect("reqwest client builder failed");
+
+    let mut queue: VecDeque<Vec<u8>> = VecDeque::new();
+    loop {
+        tokio::select! {
+            biased;
+            () = cancel.cancelled() => break,
+            () = tokio::time::sleep(cfg.interval) => {}
+        }
+
+        let Some(families) = scrape(&registry) else {
+            continue;
+        };
+        let request = encode_write_request(
+            &families,
+            &[
+                ("instance", cfg.instance_name.as_str()),
+                ("job", cfg.service_name.as_str()),
+            ],
+            unix_millis(),
+        );
+        let body = match snap::raw::Encoder::new().compress_vec(&request) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("failed to compress metrics: {err}");
+                continue;
+            }
+        };
+        if cfg.max_queue_len == 0 {
+            queue.clear();
+        } else if queue.len() >= cfg.max_queue_len {
+            queue.pop_front();
+            warn!("remote-write queue full, dropping oldest request");
+        }
+        queue.push_back(body);
+
+        while let Some(body) = queue.front() {
+            let res = tokio::select! {
+                biased;
+                () = cancel.cancelled() => return,
+                res = send_with_retries(&push_client, &cfg, body) => res,
+            };
+            match res {
+                Ok(()) => {
+                    queue.pop_front();
+                }
+                Err(SendError::Rejected) => {
+                    queue.pop_front();
+                }
+                Err(SendError::Retryable) => {
+                    debug!(
+                        "remote-write endpoint unavailable, {} requests queued",
+                        queue.len()
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn send_with_retries(
+    client: &reqwest::Client,
+    cfg: &RemoteWriteConfig,
+    body: &[u8],
+) -> Result<(), SendError> {
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match send(client, cfg, body).await {
+            Err(SendError::Retryable) if attempt < cfg.max_retries => {
+                attempt += 1;
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            res => return res,
+        }
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    cfg: &RemoteWriteConfig,
+    body: &[u8],
+) -> Result<(), SendError> {
+    let mut req = client
+        .post(&cfg.endpoint)
+        .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+        .header(reqwest::header::CONTENT_ENCODING, "snappy")
+        .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+    if let Some(username) = cfg.username.clone() {
+        req = req.basic_auth(username, Some(cfg.password.clone()));
+    }
+    let res = match req.body(body.to_vec()).send().await {
+        Ok(res) => res,
+        Err(err) => {
+            warn!("failed to push metrics: {err}");
+            return Err(SendError::Retryable);
+        }
+    };
+    let status = res.status();
+    if status.is_success() {
+        debug!("pushed metrics to remote-write endpoint");
+        return Ok(());
+    }
+    match res.text().await {
+        Ok(body) => warn!("failed to push metrics to remote-write endpoint: {status} {body}"),
+        Err(err) => warn!(
+            "failed to push metrics to remote-write endpoint: {status}; reading body failed: {err:#}"
+        ),
+    }
+    if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        Err(SendError::Retryable)
+    } else {
+        Err(SendError::Rejected)
+    }
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Encodes an uncompressed `WriteRequest` with one time series per sample.
+///
+/// `extra_labels` are added to every series that doesn't have a label of
+/// the same name. Samples without a timestamp
+/// get `now`, in milliseconds since the Unix epoch.
+fn encode_write_request(
+    families: &[ParsedFamily],
+    extra_labels: &[(&str, &str)],
+    now: i64,
+) -> Vec<u8> {
+    let mut w = ProtoWriter::default();
+    for family in families {
+        let created = format!("{}_created", family.name);
+        for sample in family.samples.iter().filter(|s| s.name != created) {
+            let mut labels: Vec<(&str, &str)> = sample
+                .labels
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .chain(
+                    extra_labels
+                        .iter()
+                        .filter(|(name, _)| !sample.labels.iter().any(|(k, _)| k == name))
+                        .copied(),
+                )
+                .chain([("__name__", sample.name.as_str())])
+                .collect();
+            // Remote write requires labels sorted by name.
+            labels.sort_by_key(|(k, _)| *k);
+            // Parsed timestamps are in seconds, remote write wants milliseconds.
+            let timestamp = sample.timestamp.map(|t| (t * 1000.0) as i64).unwrap_or(now);
+            // WriteRequest.timeseries
+            w.message(1, |w| {
+                for (name, value) in labels {
+                    // TimeSeries.labels
+                    w.message(1, |w| {
+                        w.string(1, name);
+                        w.string(2, value);
+                    });
+                }
+                // TimeSeries.samples
+                w.message(2, |w| {
+                    w.double(1, sample.value);
+                    w.int64(2, timestamp);
+                });
+            });
+        }
+    }
+    w.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use http_body_util::{BodyExt, Full};
+    use hyper::{Request, Response, StatusCode, body::Incoming, service::service_fn};
+    use hyper_util::rt::TokioIo;
+
+    use super::*;
+    use crate::{
+        Counter, Histogram, Registry,
+        encoding::parse_openmetrics,
+        service::proto::{Field, decode},
+    };
+
+    type Series = (Vec<(String, String)>, f64);
+
+    /// Decodes the labels and values of the series of a `WriteRequest`.
+    fn decode_series(request: &[u8]) -> Vec<Series> {
+        decode(request)
+            .into_iter()
+            .map(|(field, series)| {
+                assert_eq!(field, 1);
+                let series = series.message();
+                let labels = series
+                    .iter()
+                    .filter(|(f, _)| *f == 1)
+                    .map(|(_, label)| {
+                        let label = label.message();
+                        (label[0].1.string(), label[1].1.string())
+                    })
+                    .collect();
+                let (_, sample) = series.iter().find(|(f, _)| *f == 2).unwrap();
+                let sample = sample.message();
+                assert!(matches!(sample[1], (2, Field::Varint(ts)) if ts > 0));
+                (labels, sample[0].1.double())
+            })
+            .collect()
+    }
+
+    fn label(k: &str, v: &str) -> (String, String) {
+        (k.to_string(), v.to_string())
+    }
+
+    #[test]
+    fn test_config_interval() {
+        let zero = r#"{"interval": {"secs": 0, "nanos": 0}}"#;
+        assert!(serde_json::from_str::<RemoteWriteConfig>(zero).is_err());
+        let cfg: RemoteWriteConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(cfg.interval, Duration::from_secs(15));
+    }
+
+    #[test]
+    fn test_encode_existing_labels() {
+        let text = "# TYPE up gauge\nup{job=\"node\"} 1\n";
+        let families = parse_openmetrics(text).unwrap();
+        let request = encode_write_request(&families, &[("instance", "inst"), ("job", "svc")], 1);
+        assert_eq!(
+            decode_series(&request),
+            [(
+                vec![
+                    label("__name__", "up"),
+                    label("instance", "inst"),
+                    label("job", "node"),
+                ],
+                1.0
+            )]
+        );
+    }
+
+    #[derive(Debug, crate::MetricsGroup)]
+    #[metrics(default, name = "test")]
+    struct TestMetrics {
+        /// Smoke test counter
+        count: Counter,
+        /// Request latency
+        #[default(Histogram::new(vec![1.0]))]
+        latency: Histogram,
+    }
+
+    #[tokio::test]
+    async fn smoke_remote_write_exporter() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<(Vec<(String, String)>, Vec<u8>)>();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let attempts = Arc::new(Mutex::new(0));
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                let attempts = attempts.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let tx = tx.clone();
+                    let attempts = attempts.clone();
+                    async move {
+                        // Fail the first attempt to exercise the retry.
+                        let attempt = {
+                            let mut attempts = attempts.lock().expect("poisoned");
+                            *attempts += 1;
+                            *attempts
+                        };
+                        let mut response = Response::new(Full::new(hyper::body::Bytes::new()));
+                        if attempt == 1 {
+                            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                            return Ok::<_, std::convert::Infallible>(response);
+                        }
+                        let headers = req
+                            .headers()
+                            .iter()
+                            .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
+                            .collect();
+                        let body = req.into_body().collect().await.unwrap().to_bytes();
+                        if let Some(tx) = tx.lock().expect("poisoned").take() {
+                            let _ = tx.send((headers, body.to_vec()));
+                        }
+                        Ok(response)
+                    }
+                });
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        let metrics = Arc::new(TestMetrics::default());
+        metrics.count.inc_by(7);
+        metrics.latency.observe(0.5);
+        let mut registry = Registry::default();
+        registry.register(metrics);
+
+        let cfg = RemoteWriteConfig {
+            interval: Duration::from_millis(50),
+            endpoint: format!("http://{addr}/api/v1/write"),
+            service_name: "svc".to_string(),
+            instance_name: "inst".to_string(),
+            username: Some("user".to_string()),
+            password: "pass".to_string(),
+            ..Default::default()
+        };
+        let exporter = RemoteWriteExporter::spawn(cfg, Arc::new(registry)).await;
+
+        let (headers, body) = tokio::time::timeout(Duration::from_secs(5), rx)
+            .await
+            .expect("timeout waiting for push")
+            .expect("oneshot dropped");
+        exporter.shutdown().await;
+
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(header("content-encoding"), Some("snappy"));
+        assert_eq!(header("content-type"), Some("application/x-protobuf"));
+        assert_eq!(header("authorization"), Some("Basic dXNlcjpwYXNz"));
+
+        let request = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        let series = decode_series(&request);
+        assert_eq!(series.len(), 5);
+        assert_eq!(
+            series[0],
+            (
+                vec![
+                    label("__name__", "test_count_total"),
+                    label("instance", "inst"),
+                    label("job", "svc"),
+                ],
+                7.0
+            )
+        );
+        assert_eq!(
+            series[2],
+            (
+                vec![
+                    label("__name__", "test_latency_bucket"),
+                    label("instance", "inst"),
+                    label("job", "svc"),
+                    label("le", "+Inf"),
+                ],
+                1.0
+            )
+        );
+    }
+}
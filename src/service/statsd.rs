@@ -0,0 +1,550 @@
+//! Push exporter for StatsD agents, using the DogStatsD line format over UDP.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
+use tracing::{debug, warn};
+
+use super::{check_interval, deserialize_interval, scrape};
+use crate::{MetricType, MetricValue, MetricsSource, encoding::ParsedFamily};
+
+/// Periodic exporter that pushes metrics to a StatsD agent over UDP.
+///
+/// StatsD counters are deltas, so for every [`Counter`] the increase since
+/// the previous push is sent. Gauges and untyped metrics are sent as-is.
+/// [`Histogram`] observations are recovered from the change in bucket
+/// counts and sent as histograms, timers or distributions, see
+/// [`StatsdHistogramType`].
+/// Of a [`GaugeHistogram`], only the current count and sum are sent, as
+/// gauges. Family labels and registry labels are sent as DogStatsD tags.
+///
+/// Lines are batched into packets of at most
+/// [`max_packet_size`](StatsdConfig::max_packet_size) bytes.
+///
+/// Aborts the background task on drop. For an orderly shutdown that lets
+/// the in-flight push finish, call [`shutdown`](Self::shutdown).
+///
+/// [`Counter`]: crate::Counter
+/// [`Histogram`]: crate::Histogram
+/// [`GaugeHistogram`]: crate::GaugeHistogram
+#[derive(Debug)]
+pub struct StatsdExporter {
+    cancel: CancellationToken,
+    task: AbortOnDropHandle<()>,
+}
+
+impl StatsdExporter {
+    /// Spawns the StatsD exporter in a background task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the [`interval`](StatsdConfig::interval) is zero.
+    #[allow(clippy::unused_async)]
+    pub async fn spawn(cfg: StatsdConfig, registry: impl MetricsSource) -> Self {
+        check_interval(cfg.interval, "StatsD");
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn(statsd_loop(cfg, registry, cancel.clone()));
+        Self {
+            cancel,
+            task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    /// Gracefully shuts down the exporter.
+    ///
+    /// Stops between push cycles, letting the current push finish before
+    /// returning. Wrap in [`tokio::time::timeout`] to bound the wait.
+    pub async fn shutdown(self) {
+        self.cancel.cancel();
+        let _ = self.task.await;
+    }
+}
+
+/// The StatsD metric type used for [`Histogram`](crate::Histogram) observations.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdHistogramType {
+    /// Sent as histograms (`|h`), with the observed values unchanged.
+    #[default]
+    Histogram,
+    /// Sent as timers (`|ms`), supported by all StatsD agents.
+    ///
+    /// The observed values are taken to be seconds, as is the convention for
+    /// Prometheus histograms, and sent as milliseconds.
+    Timer,
+    /// Sent as DogStatsD distributions (`|d`), aggregated globally by the agent.
+    Distribution,
+}
+
+impl StatsdHistogramType {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Histogram => "h",
+            Self::Timer => "ms",
+            Self::Distribution => "d",
+        }
+    }
+}
+
+/// Configuration for pushing metrics to a StatsD agent.
+#[derive(Debug, serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct StatsdConfig {
+    /// The push interval.
+    ///
+    /// Must not be zero.
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub interval: Duration,
+    /// The address of the agent, e.g. `127.0.0.1:8125`.
+    ///
+    /// Host names are resolved when connecting, and again after a send failed.
+    pub addr: String,
+    /// Tags added to all metrics.
+    pub tags: Vec<(String, String)>,
+    /// The metric type histogram observations are sent as.
+    pub histogram_type: StatsdHistogramType,
+    /// The maximum size of a UDP payload.
+    ///
+    /// The default of 1432 bytes fits into a single Ethernet frame. Lines
+    /// longer than this are sent in a packet of their own.
+    pub max_packet_size: usize,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            addr: "127.0.0.1:8125".to_string(),
+            tags: Vec::new(),
+            histogram_type: StatsdHistogramType::default(),
+            max_packet_size: 1432,
+        }
+    }
+}
+
+async fn statsd_loop(cfg: StatsdConfig, registry: impl MetricsSource, cancel: CancellationToken) {
+    let mut state = StatsdState::default();
+    let mut socket = None;
+    loop {
+        tokio::select! {
+            biased;
+            () = cancel.cancelled() => break,
+            () = tokio::time::sleep(cfg.interval) => {}
+        }
+
+        let Some(families) = scrape(&registry) else {
+            continue;
+        };
+        let lines = state.encode(&families, &cfg);
+
+        let sock = match &socket {
+            Some(sock) => sock,
+            None => match connect(&cfg.addr).await {
+                Ok(sock) => socket.insert(sock),
+                Err(err) => {
+                    warn!("failed to connect to statsd agent at {}: {err}", cfg.addr);
+                    continue;
+                }
+            },
+        };
+        for packet in batch(&lines, cfg.max_packet_size) {
+            if let Err(err) = sock.send(packet.as_bytes()).await {
+                warn!("failed to push metrics to statsd agent: {err}");
+                socket = None;
+                break;
+            }
+        }
+        debug!("pushed {} lines to statsd agent", lines.len());
+    }
+}
+
+async fn connect(addr: &str) -> io::Result<UdpSocket> {
+    let target = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address did not resolve"))?;
+    let local: SocketAddr = match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+/// A metric name together with its labels.
+type SeriesKey = (String, Vec<(String, String)>);
+
+/// The values seen in the previous push, to compute deltas from.
+#[derive(Debug, Default)]
+struct StatsdState {
+    counters: HashMap<SeriesKey, f64>,
+    /// Cumulative bucket counts as `(upper bound, count)`.
+    histograms: HashMap<SeriesKey, Vec<(f64, u64)>>,
+}
+
+impl StatsdState {
+    /// Encodes the families into StatsD lines and remembers the sent values.
+    ///
+    /// Series that are missing from `families` are forgotten, so a series that
+    /// reappears starts from zero.
+    fn encode(&mut self, families: &[ParsedFamily], cfg: &StatsdConfig) -> Vec<String> {
+        let mut counters = HashMap::new();
+        let mut histograms = HashMap::new();
+        let mut lines = Vec::new();
+        for family in families {
+            match family.r#type {
+                Some(MetricType::Counter) => {
+                    for (labels, value) in family.values() {
+                        let value = match value {
+                            MetricValue::Counter(value) => value as f64,
+                            MetricValue::CounterF64(value) => value,
+                            _ => continue,
+                        };
+                        let tags = encode_tags(&cfg.tags, &labels);
+                        let key = (family.name.clone(), labels);
+                        let prev = self.counters.get(&key).copied().unwrap_or(0.0);
+                        // A smaller value means the counter was reset.
+                        let delta = if value < prev { value } else { value - prev };
+                        if delta > 0.0 {
+                            lines.push(format!("{}:{delta}|c{tags}", family.name));
+                        }
+                        counters.insert(key, value);
+                    }
+                }
+                Some(MetricType::Histogram) => {
+                    for (labels, value) in family.values() {
+                        let MetricValue::Histogram { buckets, .. } = value else {
+                            continue;
+                        };
+                        let tags = encode_tags(&cfg.tags, &labels);
+                        let key = (family.name.clone(), labels);
+                        // Buckets of exponential histograms come and go, so the
+                        // previous counts are matched by bound. A bound that is
+                        // new had no observations since the next lower one.
+                        let prev_buckets = self.histograms.get(&key);
+                        let prev_at = |le: f64| {
+                            prev_buckets
+                                .and_then(|prev| prev.iter().take_while(|(b, _)| *b <= le).last())
+                                .map_or(0, |(_, count)| *count)
+                        };
+                        let mut prev: Vec<u64> =
+                            buckets.iter().map(|(le, _)| prev_at(*le)).collect();
+                        // A smaller count means the histogram was reset.
+                        if buckets
+                            .iter()
+                            .zip(&prev)
+                            .any(|((_, count), prev)| prev > count)
+                        {
+                            prev.fill(0);
+                        }
+                        // Observations in the `+Inf` bucket are sent as the largest finite bound.
+                        let mut lower = 0.0;
+                        let mut below = 0;
+                        for ((le, count), prev) in buckets.iter().zip(prev) {
+                            let delta = count - prev;
+                            let observed = delta.saturating_sub(below);
+                            below = delta;
+                            let value = if le.is_finite() { *le } else { lower };
+                            lower = *le;
+                            if observed > 0 {
+                                lines.push(encode_observations(
+                                    &family.name,
+                                    value,
+                                    observed,
+                                    cfg.histogram_type,
+                                    &tags,
+                                ));
+                            }
+                        }
+                        histograms.insert(key, buckets);
+                    }
+                }
+                Some(MetricType::GaugeHistogram) => {
+                    // StatsD has no gauge histograms, send the current count
+                    // and sum as gauges.
+                    for sample in &family.samples {
+                        let suffix = sample.name.strip_prefix(family.name.as_str());
+                        if matches!(suffix, Some("_gcount" | "_gsum")) {
+                            let tags = encode_tags(&cfg.tags, &sample.labels);
+                            lines.push(format!("{}:{}|g{tags}", sample.name, sample.value));
+                        }
+                    }
+                }
+                _ => {
+                    // Also covers infos and state sets, which are gauges
+                    // with the info or state in the tags.
+                    let created = format!("{}_created", family.name);
+                    for sample in family.samples.iter().filter(|s| s.name != created) {
+                        let tags = encode_tags(&cfg.tags, &sample.labels);
+                        lines.push(format!("{}:{}|g{tags}", sample.name, sample.value));
+                    }
+                }
+            }
+        }
+        self.counters = counters;
+        self.histograms = histograms;
+        lines
+    }
+}
+
+/// Encodes `count` observations of `value` as a single sampled line.
+fn encode_observations(
+    name: &str,
+    value: f64,
+    count: u64,
+    histogram_type: StatsdHistogramType,
+    tags: &str,
+) -> String {
+    let ty = histogram_type.as_str();
+    let value = match histogram_type {
+        StatsdHistogramType::Timer => value * 1000.0,
+        _ => value,
+    };
+    if count == 1 {
+        format!("{name}:{value}|{ty}{tags}")
+    } else {
+        // A sample rate of 1/n makes the agent count the line n times.
+        let rate = 1.0 / count as f64;
+        format!("{name}:{value}|{ty}|@{rate}{tags}")
+    }
+}
+
+/// Encodes the tag section of a line, including the leading `|#`.
+///
+/// Characters that are part of the line syntax are replaced by `_`.
+fn encode_tags(global: &[(String, String)], labels: &[(String, String)]) -> String {
+    let mut out = String::new();
+    for (i, (key, value)) in global.iter().chain(labels).enumerate() {
+        out.push_str(if i == 0 { "|#" } else { "," });
+        for c in key.chars().chain([':']).chain(value.chars()) {
+            out.push(match c {
+                '|' | ',' | '#' | '\n' => '_',
+                c => c,
+            });
+        }
+    }
+    out
+}
+
+/// Joins lines into newline-separated packets of at most `max_size` bytes.
+fn batch(lines: &[String], max_size: usize) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > max_size {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{Counter, CounterF64, Gauge, Histogram, Registry, encoding::parse_openmetrics};
+
+    #[derive(Debug, crate::MetricsGroup)]
+    #[metrics(default, name = "test")]
+    struct TestMetrics {
+        /// Smoke test counter
+        count: Counter,
+        /// CPU time
+        cpu: CounterF64,
+        /// Open connections
+        conns: Gauge,
+        /// Request latency
+        #[default(Histogram::new(vec![1.0, 5.0]))]
+        latency: Histogram,
+    }
+
+    #[test]
+    fn test_encode_deltas() {
+        let metrics = Arc::new(TestMetrics::default());
+        let mut registry = Registry::default();
+        registry
+            .sub_registry_with_labels([("region", "eu")])
+            .register(metrics.clone());
+        let cfg = StatsdConfig {
+            tags: vec![("service".to_string(), "svc".to_string())],
+            histogram_type: StatsdHistogramType::Distribution,
+            ..Default::default()
+        };
+        let mut state = StatsdState::default();
+        let mut encode = || {
+            let text = registry.encode_openmetrics_to_string().unwrap();
+            state.encode(&parse_openmetrics(&text).unwrap(), &cfg)
+        };
+
+        metrics.count.inc_by(7);
+        metrics.cpu.inc_by(0.75);
+        metrics.conns.set(3);
+        metrics.latency.observe(0.5);
+        metrics.latency.observe(2.0);
+        metrics.latency.observe(2.0);
+        metrics.latency.observe(10.0);
+        assert_eq!(
+            encode(),
+            [
+                "test_count:7|c|#service:svc,region:eu",
+                "test_cpu:0.75|c|#service:svc,region:eu",
+                "test_conns:3|g|#service:svc,region:eu",
+                "test_latency:1|d|#service:svc,region:eu",
+                "test_latency:5|d|@0.5|#service:svc,region:eu",
+                "test_latency:5|d|#service:svc,region:eu",
+            ]
+        );
+
+        metrics.count.inc_by(2);
+        metrics.cpu.inc_by(0.5);
+        metrics.latency.observe(3.0);
+        assert_eq!(
+            encode(),
+            [
+                "test_count:2|c|#service:svc,region:eu",
+                "test_cpu:0.5|c|#service:svc,region:eu",
+                "test_conns:3|g|#service:svc,region:eu",
+                "test_latency:5|d|#service:svc,region:eu",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_histogram_types() {
+        let text = "# TYPE latency histogram
+latency_bucket{le=\"0.5\"} 2
+latency_bucket{le=\"+Inf\"} 2
+latency_sum 0.4
+latency_count 2
+";
+        let families = parse_openmetrics(text).unwrap();
+        let encode = |histogram_type| {
+            let cfg = StatsdConfig {
+                histogram_type,
+                ..Default::default()
+            };
+            StatsdState::default().encode(&families, &cfg)
+        };
+        assert_eq!(
+            encode(StatsdHistogramType::default()),
+            ["latency:0.5|h|@0.5"]
+        );
+        assert_eq!(encode(StatsdHistogramType::Timer), ["latency:500|ms|@0.5"]);
+    }
+
+    #[test]
+    fn test_encode_new_buckets() {
+        let mut state = StatsdState::default();
+        let mut encode = |text: &str| {
+            let cfg = StatsdConfig::default();
+            state.encode(&parse_openmetrics(text).unwrap(), &cfg)
+        };
+        let text = "# TYPE latency histogram
+latency_bucket{le=\"1.0\"} 1
+latency_bucket{le=\"+Inf\"} 1
+latency_count 1
+";
+        assert_eq!(encode(text), ["latency:1|h"]);
+        // Only the observation in the new bucket is sent.
+        let text = "# TYPE latency histogram
+latency_bucket{le=\"1.0\"} 1
+latency_bucket{le=\"2.0\"} 2
+latency_bucket{le=\"+Inf\"} 2
+latency_count 2
+";
+        assert_eq!(encode(text), ["latency:2|h"]);
+    }
+
+    #[test]
+    fn test_config_interval() {
+        let zero = r#"{"interval": {"secs": 0, "nanos": 0}}"#;
+        assert!(serde_json::from_str::<StatsdConfig>(zero).is_err());
+        let cfg: StatsdConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(cfg.interval, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_encode_info() {
+        let text = "# TYPE build info\nbuild_info{version=\"1.0\"} 1\n";
+        let lines =
+            StatsdState::default().encode(&parse_openmetrics(text).unwrap(), &Default::default());
+        assert_eq!(lines, ["build_info:1|g|#version:1.0"]);
+    }
+
+    #[test]
+    fn test_encode_gauge_histogram() {
+        let text = "# TYPE queue_age gaugehistogram
+queue_age_bucket{le=\"1.0\"} 2
+queue_age_bucket{le=\"+Inf\"} 3
+queue_age_gsum 4.5
+queue_age_gcount 3
+";
+        let lines =
+            StatsdState::default().encode(&parse_openmetrics(text).unwrap(), &Default::default());
+        assert_eq!(lines, ["queue_age_gsum:4.5|g", "queue_age_gcount:3|g"]);
+    }
+
+    #[test]
+    fn test_batch() {
+        let lines: Vec<String> = ["a:1|c", "b:2|c", "c:3|c", "a_very_long_line:1|c"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(
+            batch(&lines, 11),
+            ["a:1|c\nb:2|c", "c:3|c", "a_very_long_line:1|c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn smoke_statsd_exporter() {
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let metrics = Arc::new(TestMetrics::default());
+        metrics.count.inc_by(7);
+        let mut registry = Registry::default();
+        registry.register(metrics.clone());
+
+        let cfg = StatsdConfig {
+            interval: Duration::from_millis(50),
+            addr: agent.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let exporter = StatsdExporter::spawn(cfg, Arc::new(registry)).await;
+
+        let mut buf = [0u8; 1500];
+        let mut recv = async || {
+            let len = tokio::time::timeout(Duration::from_secs(5), agent.recv(&mut buf))
+                .await
+                .expect("timeout waiting for push")
+                .unwrap();
+            String::from_utf8(buf[..len].to_vec()).unwrap()
+        };
+        let packet = recv().await;
+        assert!(packet.lines().any(|l| l == "test_count:7|c"), "{packet}");
+        assert!(packet.lines().any(|l| l == "test_conns:0|g"), "{packet}");
+
+        metrics.count.inc_by(3);
+        loop {
+            let packet = recv().await;
+            if let Some(line) = packet.lines().find(|l| l.starts_with("test_count:")) {
+                assert_eq!(line, "test_count:3|c");
+                break;
+            }
+        }
+        exporter.shutdown().await;
+    }
+}
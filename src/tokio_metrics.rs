@@ -0,0 +1,199 @@
+//! Metrics about a tokio runtime.
+
+use std::any::Any;
+
+use tokio::runtime::{Handle, RuntimeMetrics};
+
+use crate::{Metric, MetricItem, MetricType, MetricValue, MetricsGroup, iterable::Iterable};
+
+/// [`MetricsGroup`] that samples the metrics of a tokio runtime.
+///
+/// Values are read from [`Handle::metrics`] whenever the registry is
+/// encoded, so there is nothing to update. Per-worker counts are summed
+/// over all workers. Register it like any other group:
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use iroh_metrics::{Registry, TokioMetrics};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let mut registry = Registry::default();
+/// registry.register(Arc::new(TokioMetrics::current()));
+/// # }
+/// ```
+///
+/// With `--cfg tokio_unstable`, the blocking thread counts, spawned tasks
+/// and poll counts are included as well.
+#[derive(Debug)]
+pub struct TokioMetrics {
+    metrics: Vec<RuntimeMetric>,
+}
+
+impl TokioMetrics {
+    /// Samples the runtime of `handle`.
+    pub fn new(handle: &Handle) -> Self {
+        let runtime = handle.metrics();
+        let mut metrics = Vec::new();
+        let mut add = |name, help, r#type, read| {
+            metrics.push(RuntimeMetric {
+                runtime: runtime.clone(),
+                name,
+                help,
+                r#type,
+                read,
+            })
+        };
+        add(
+            "workers",
+            "Number of worker threads",
+            MetricType::Gauge,
+            |m| m.num_workers() as u64,
+        );
+        add(
+            "alive_tasks",
+            "Number of alive tasks",
+            MetricType::Gauge,
+            |m| m.num_alive_tasks() as u64,
+        );
+        add(
+            "global_queue_depth",
+            "Number of tasks in the global queue",
+            MetricType::Gauge,
+            |m| m.global_queue_depth() as u64,
+        );
+        #[cfg(target_has_atomic = "64")]
+        {
+            add(
+                "park_count",
+                "Number of times worker threads parked",
+                MetricType::Counter,
+                |m| sum_workers(m, RuntimeMetrics::worker_park_count),
+            );
+            add(
+                "park_unpark_count",
+                "Number of times worker threads parked or unparked",
+                MetricType::Counter,
+                |m| sum_workers(m, RuntimeMetrics::worker_park_unpark_count),
+            );
+        }
+        #[cfg(tokio_unstable)]
+        {
+            add(
+                "blocking_threads",
+                "Number of blocking threads",
+                MetricType::Gauge,
+                |m| m.num_blocking_threads() as u64,
+            );
+            add(
+                "idle_blocking_threads",
+                "Number of idle blocking threads",
+                MetricType::Gauge,
+                |m| m.num_idle_blocking_threads() as u64,
+            );
+            #[cfg(target_has_atomic = "64")]
+            {
+                add(
+                    "spawned_tasks",
+                    "Number of spawned tasks",
+                    MetricType::Counter,
+                    RuntimeMetrics::spawned_tasks_count,
+                );
+                add(
+                    "poll_count",
+                    "Number of tasks polled by worker threads",
+                    MetricType::Counter,
+                    |m| sum_workers(m, RuntimeMetrics::worker_poll_count),
+                );
+            }
+        }
+        Self { metrics }
+    }
+
+    /// Samples the runtime of the current context.
+    ///
+    /// Panics if called outside of a tokio runtime, like [`Handle::current`].
+    pub fn current() -> Self {
+        Self::new(&Handle::current())
+    }
+}
+
+impl Iterable for TokioMetrics {
+    fn metric_field_count(&self) -> usize {
+        self.metrics.len()
+    }
+
+    fn metric_field_ref(&self, n: usize) -> Option<MetricItem<'_>> {
+        let metric = self.metrics.get(n)?;
+        Some(MetricItem::new(metric.name, metric.help, metric))
+    }
+}
+
+impl MetricsGroup for TokioMetrics {
+    fn name(&self) -> &'static str {
+        "tokio"
+    }
+}
+
+#[cfg(target_has_atomic = "64")]
+fn sum_workers(metrics: &RuntimeMetrics, read: fn(&RuntimeMetrics, usize) -> u64) -> u64 {
+    (0..metrics.num_workers()).map(|i| read(metrics, i)).sum()
+}
+
+/// A runtime metric that is read on every access.
+#[derive(Debug)]
+struct RuntimeMetric {
+    runtime: RuntimeMetrics,
+    name: &'static str,
+    help: &'static str,
+    r#type: MetricType,
+    read: fn(&RuntimeMetrics) -> u64,
+}
+
+impl Metric for RuntimeMetric {
+    fn r#type(&self) -> MetricType {
+        self.r#type
+    }
+
+    fn value(&self) -> MetricValue {
+        let value = (self.read)(&self.runtime);
+        match self.r#type {
+            MetricType::Counter => MetricValue::Counter(value),
+            _ => MetricValue::Gauge(i64::try_from(value).unwrap_or(i64::MAX)),
+        }
+    }
+
+    /// Runtime metrics are read-only, so this does nothing.
+    fn set_value(&self, _value: MetricValue) {}
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{MetricsSource, Registry};
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tokio_metrics() {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let task = tokio::spawn(rx);
+
+        let mut registry = Registry::default();
+        registry.register(Arc::new(TokioMetrics::current()));
+        let text = registry.encode_openmetrics_to_string().unwrap();
+        assert!(text.contains("\ntokio_workers 1\n"), "{text}");
+        assert!(text.contains("\ntokio_alive_tasks 1\n"), "{text}");
+        assert!(text.contains("\ntokio_global_queue_depth "), "{text}");
+        #[cfg(target_has_atomic = "64")]
+        assert!(text.contains("\ntokio_park_count_total "), "{text}");
+
+        tx.send(()).unwrap();
+        task.await.unwrap().unwrap();
+        let text = registry.encode_openmetrics_to_string().unwrap();
+        assert!(text.contains("\ntokio_alive_tasks 0\n"), "{text}");
+    }
+}